@@ -0,0 +1,219 @@
+//! Benchmarks for the upstream cache, with enough resources for a linear scan
+//! to show up. The cache is compiled in directly, since the crate only has a
+//! binary target. Run with "cargo bench".
+
+// not every part of the cache is exercised here
+#![ allow (dead_code) ]
+
+extern crate im;
+extern crate serde_json;
+
+#[ macro_use ]
+#[ path = "../src/macros.rs" ]
+mod macros;
+
+#[ path = "../src/upstream/upstream_data.rs" ]
+mod upstream_data;
+
+use std::time::Instant;
+
+use upstream_data::*;
+
+const RESOURCE_COUNT: u64 = 50000;
+const CLASS_COUNT: u64 = 50;
+const NAMESPACE_COUNT: u64 = 10;
+const FIELD_NAMES: & [& str] = & [ "data", "status", "secret" ];
+
+fn main () {
+
+	let build_start =
+		Instant::now ();
+
+	let data =
+		data_build ();
+
+	println! (
+		"{:<40} {:>12} ms ({} nodes)",
+		"build",
+		duration_nanos (build_start) / 1000000,
+		data.len ());
+
+	let middle_name =
+		resource_name (RESOURCE_COUNT / 2);
+
+	let middle_key =
+		format! ("/resource/{}/data", middle_name);
+
+	bench ("get", 100000, || {
+		data.get (& middle_key).into_iter ().count ()
+	});
+
+	bench ("resource", 100000, || {
+		data.resource (& middle_name).into_iter ().count ()
+	});
+
+	bench ("resources", 20, || {
+		data.resources ().count ()
+	});
+
+	bench ("resources_with_prefix", 10000, || {
+		data.resources_with_prefix ("host-0001").count ()
+	});
+
+	bench ("resources_for_class", 1000, || {
+		data.resources_for_class ("class-7").len ()
+	});
+
+	bench ("resources_for_namespace", 1000, || {
+		data.resources_for_namespace ("namespace-3").len ()
+	});
+
+	bench ("descendants (one resource)", 100000, || {
+		data.descendants (& format! ("/resource/{}", middle_name)).count ()
+	});
+
+	bench ("children (/resource)", 20, || {
+		data.children ("/resource").count ()
+	});
+
+	// for comparison, a scan of every key as the collection routes did
+	// before the cache was indexed
+
+	bench ("scan for resources of a class", 20, || {
+		data.descendants ("").filter (|node|
+			node.key ().starts_with ("/resource/")
+			&& node.key ().ends_with ("/data")
+			&& node.value ().contains ("\"class-7\"")
+		).count ()
+	});
+
+	let mut data =
+		data;
+
+	bench ("remove_recursive and insert", 10000, || {
+
+		data.remove_recursive (
+			& format! ("/resource/{}", middle_name));
+
+		resource_insert (
+			& mut data,
+			RESOURCE_COUNT / 2);
+
+		1
+
+	});
+
+}
+
+fn data_build (
+) -> UpstreamData {
+
+	let mut data =
+		UpstreamData::new ();
+
+	data.insert (
+		NodeData::new (
+			"/resource".to_owned (),
+			String::new (),
+			true,
+			1,
+			1));
+
+	for index in 0 .. RESOURCE_COUNT {
+
+		resource_insert (
+			& mut data,
+			index);
+
+	}
+
+	data
+
+}
+
+fn resource_insert (
+	data: & mut UpstreamData,
+	index: u64,
+) {
+
+	let name =
+		resource_name (index);
+
+	data.insert (
+		NodeData::new (
+			format! ("/resource/{}", name),
+			String::new (),
+			true,
+			index,
+			index));
+
+	for field_name in FIELD_NAMES {
+
+		let value =
+			if * field_name == "data" {
+				format! (
+					"{{\"identity\":{{\"name\":\"{}\",\"class\":\"class-{}\",\
+					\"namespace\":\"namespace-{}\"}}}}",
+					name,
+					index % CLASS_COUNT,
+					index % NAMESPACE_COUNT)
+			} else {
+				"{}".to_owned ()
+			};
+
+		data.insert (
+			NodeData::new (
+				format! ("/resource/{}/{}", name, field_name),
+				value,
+				false,
+				index,
+				index));
+
+	}
+
+}
+
+fn resource_name (
+	index: u64,
+) -> String {
+
+	format! ("host-{:06}", index)
+
+}
+
+fn bench <Function: FnMut () -> usize> (
+	name: & str,
+	iterations: u64,
+	mut function: Function,
+) {
+
+	let start =
+		Instant::now ();
+
+	let mut results =
+		0;
+
+	for _ in 0 .. iterations {
+		results += function ();
+	}
+
+	println! (
+		"{:<40} {:>12} ns/iter ({} results)",
+		name,
+		duration_nanos (start) / iterations,
+		results as u64 / iterations);
+
+}
+
+fn duration_nanos (
+	start: Instant,
+) -> u64 {
+
+	let elapsed =
+		start.elapsed ();
+
+	elapsed.as_secs () * 1000000000 + elapsed.subsec_nanos () as u64
+
+}
+
+// ex: noet ts=4 filetype=rust
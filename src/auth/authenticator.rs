@@ -0,0 +1,439 @@
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use crate::auth::*;
+use crate::server::*;
+use crate::settings::*;
+
+pub struct Authenticator {
+	settings: Arc <Settings>,
+	tokens: RwLock <Arc <Vec <AuthenticatorToken>>>,
+	token_file_modified: Mutex <Option <SystemTime>>,
+	client_identities: Vec <AuthenticatorClientIdentity>,
+	peer_identities: Vec <AuthenticatorPeerIdentity>,
+}
+
+struct AuthenticatorToken {
+	name: String,
+	hash: TokenHash,
+	permissions: Arc <Permissions>,
+}
+
+struct AuthenticatorClientIdentity {
+	subject: String,
+	permissions: Arc <Permissions>,
+}
+
+struct AuthenticatorPeerIdentity {
+	name: String,
+	uid: Option <u32>,
+	gid: Option <u32>,
+	permissions: Arc <Permissions>,
+}
+
+impl Authenticator {
+
+	pub fn new (
+		settings: Arc <Settings>,
+	) -> Result <Authenticator, String> {
+
+		let token_file_modified =
+			Self::token_file_modified (
+				& settings);
+
+		let tokens =
+			Self::tokens_load (
+				& settings,
+			) ?;
+
+		let mut client_identities: Vec <AuthenticatorClientIdentity> =
+			Vec::new ();
+
+		for client_identity_settings in settings.server.tls.iter ().flat_map (
+			|tls_settings| tls_settings.client_identities.iter ()) {
+
+			if client_identity_settings.subject == "*" {
+
+				return Err (
+					"Client identity subject must name a certificate, not \"*\""
+						.to_string ());
+
+			}
+
+			client_identities.push (
+				AuthenticatorClientIdentity {
+					subject: client_identity_settings.subject.clone (),
+					permissions: Arc::new (
+						Permissions::from_settings (
+							& client_identity_settings.permissions,
+						).map_err (|error|
+							format! (
+								"Error in client identity {}: {}",
+								client_identity_settings.subject,
+								error)
+						) ?
+					),
+				});
+
+		}
+
+		let mut peer_identities: Vec <AuthenticatorPeerIdentity> =
+			Vec::new ();
+
+		for peer_identity_settings in settings.server.unix_socket.iter ().flat_map (
+			|unix_socket_settings| unix_socket_settings.peer_identities.iter ()) {
+
+			// an identity with neither would match every local process
+
+			if peer_identity_settings.uid.is_none ()
+				&& peer_identity_settings.gid.is_none () {
+
+				return Err (
+					format! (
+						"Peer identity {} must specify a uid or gid",
+						peer_identity_settings.name));
+
+			}
+
+			peer_identities.push (
+				AuthenticatorPeerIdentity {
+					name: peer_identity_settings.name.clone (),
+					uid: peer_identity_settings.uid,
+					gid: peer_identity_settings.gid,
+					permissions: Arc::new (
+						Permissions::from_settings (
+							& peer_identity_settings.permissions,
+						).map_err (|error|
+							format! (
+								"Error in peer identity {}: {}",
+								peer_identity_settings.name,
+								error)
+						) ?
+					),
+				});
+
+		}
+
+		Ok (Authenticator {
+			settings,
+			tokens: RwLock::new (Arc::new (tokens)),
+			token_file_modified: Mutex::new (token_file_modified),
+			client_identities,
+			peer_identities,
+		})
+
+	}
+
+	/// Rereads the token file. On error the previous tokens are kept, and
+	/// the file won't be retried until it changes again.
+	pub fn reload (
+		& self,
+	) -> Result <(), String> {
+
+		* self.token_file_modified.lock ().unwrap () =
+			Self::token_file_modified (
+				& self.settings);
+
+		let tokens =
+			Self::tokens_load (
+				& self.settings,
+			) ?;
+
+		* self.tokens.write ().unwrap () =
+			Arc::new (tokens);
+
+		Ok (())
+
+	}
+
+	/// Reloads the tokens if the token file's modification time has changed
+	/// since it was last read, returning true if it did so.
+	pub fn reload_if_changed (
+		& self,
+	) -> Result <bool, String> {
+
+		let token_file_modified =
+			Self::token_file_modified (
+				& self.settings);
+
+		if token_file_modified
+			== * self.token_file_modified.lock ().unwrap () {
+
+			return Ok (false);
+
+		}
+
+		self.reload () ?;
+
+		Ok (true)
+
+	}
+
+	fn token_file_modified (
+		settings: & Settings,
+	) -> Option <SystemTime> {
+
+		settings.server.token_file.as_ref ().and_then (|token_file|
+			fs::metadata (
+				token_file,
+			).and_then (|metadata|
+				metadata.modified ()
+			).ok ()
+		)
+
+	}
+
+	fn tokens_load (
+		settings: & Settings,
+	) -> Result <Vec <AuthenticatorToken>, String> {
+
+		let mut tokens: Vec <AuthenticatorToken> =
+			Vec::new ();
+
+		if let Some (ref authorization_token) =
+			settings.server.authorization_token {
+
+			tokens.push (
+				AuthenticatorToken {
+					name: "default".to_string (),
+					hash: TokenHash::create (
+						authorization_token,
+					) ?,
+					permissions: Arc::new (Permissions::full ()),
+				});
+
+		}
+
+		for token_settings in settings.server.tokens.iter () {
+
+			tokens.push (
+				Self::token_load (
+					token_settings,
+				) ?
+			);
+
+		}
+
+		if let Some (ref token_file) =
+			settings.server.token_file {
+
+			for token_settings in Self::token_file_read (
+				token_file,
+			) ?.iter () {
+
+				// the token file holds salted hashes only
+
+				if token_settings.token.is_some () {
+
+					return Err (
+						format! (
+							"Error in {}: Token {} must be given as a hash",
+							token_file.to_string_lossy (),
+							token_settings.name));
+
+				}
+
+				tokens.push (
+					Self::token_load (
+						token_settings,
+					).map_err (|error|
+						format! (
+							"Error in {}: {}",
+							token_file.to_string_lossy (),
+							error)
+					) ?
+				);
+
+			}
+
+		}
+
+		Ok (tokens)
+
+	}
+
+	fn token_file_read (
+		token_file: & Path,
+	) -> Result <Vec <TokenSettings>, String> {
+
+		let file =
+			File::open (
+				token_file,
+			).map_err (|error|
+				format! (
+					"Error opening {}: {}",
+					token_file.to_string_lossy (),
+					error)
+			) ?;
+
+		serde_yaml::from_reader (
+			file,
+		).map_err (|error|
+			format! (
+				"Error reading {}: {}",
+				token_file.to_string_lossy (),
+				error)
+		)
+
+	}
+
+	fn token_load (
+		token_settings: & TokenSettings,
+	) -> Result <AuthenticatorToken, String> {
+
+		let hash =
+			match (& token_settings.token, & token_settings.hash) {
+
+			(Some (token), & None) =>
+				TokenHash::create (token),
+
+			(& None, Some (hash)) =>
+				TokenHash::parse (hash),
+
+			_ =>
+				Err (
+					"Must specify exactly one of token or hash"
+						.to_string ()),
+
+		}.map_err (|error|
+			format! (
+				"Error in token {}: {}",
+				token_settings.name,
+				error)
+		) ?;
+
+		let permissions =
+			Permissions::from_settings (
+				& token_settings.permissions,
+			).map_err (|error|
+				format! (
+					"Error in token {}: {}",
+					token_settings.name,
+					error)
+			) ?;
+
+		Ok (AuthenticatorToken {
+			name: token_settings.name.clone (),
+			hash,
+			permissions: Arc::new (permissions),
+		})
+
+	}
+
+	/// Identifies the client making a request, by the credentials of a unix
+	/// socket peer or a verified client certificate, whichever the connection
+	/// has, and otherwise by the bearer token.
+	pub fn authenticate (
+		& self,
+		request: & ServerRequest,
+	) -> Option <Identity> {
+
+		self.authenticate_peer_credentials (
+			request,
+		).or_else (||
+			self.authenticate_client_certificate (
+				request,
+			)
+		).or_else (||
+			self.authenticate_bearer_token (
+				request,
+			)
+		)
+
+	}
+
+	fn authenticate_peer_credentials (
+		& self,
+		request: & ServerRequest,
+	) -> Option <Identity> {
+
+		let peer_credentials =
+			request.peer_credentials () ?;
+
+		self.peer_identities.iter ().find (|peer_identity|
+			peer_identity.uid.map (|uid|
+				uid == peer_credentials.uid
+			).unwrap_or (true)
+			&& peer_identity.gid.map (|gid|
+				gid == peer_credentials.gid
+			).unwrap_or (true)
+		).map (|peer_identity|
+			Identity::new (
+				peer_identity.name.clone (),
+				IdentitySource::PeerCredentials,
+				peer_identity.permissions.clone (),
+			)
+		)
+
+	}
+
+	fn authenticate_client_certificate (
+		& self,
+		request: & ServerRequest,
+	) -> Option <Identity> {
+
+		let certificate_names =
+			request.peer_certificate_names ();
+
+		for client_identity in self.client_identities.iter () {
+
+			for certificate_name in certificate_names.iter () {
+
+				if client_identity.subject == * certificate_name {
+
+					return Some (Identity::new (
+						certificate_name.clone (),
+						IdentitySource::ClientCertificate,
+						client_identity.permissions.clone (),
+					));
+
+				}
+
+			}
+
+		}
+
+		None
+
+	}
+
+	fn authenticate_bearer_token (
+		& self,
+		request: & ServerRequest,
+	) -> Option <Identity> {
+
+		let authorization =
+			request.header ("Authorization") ?;
+
+		let bearer_token =
+			match authorization.get ( .. 7) {
+				Some (scheme) if scheme.eq_ignore_ascii_case ("Bearer ") =>
+					authorization [7 .. ].trim (),
+				_ =>
+					return None,
+			};
+
+		let tokens =
+			self.tokens.read ().unwrap ().clone ();
+
+		tokens.iter ().find (|token|
+			token.hash.verify (
+				bearer_token)
+		).map (|token|
+			Identity::new (
+				token.name.clone (),
+				IdentitySource::BearerToken,
+				token.permissions.clone (),
+			)
+		)
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,43 @@
+use std::sync::Arc;
+
+use crate::auth::*;
+
+#[ derive (Clone, Copy, Debug, PartialEq, Eq, Hash) ]
+pub enum IdentitySource {
+	BearerToken,
+	ClientCertificate,
+	PeerCredentials,
+}
+
+#[ derive (Clone, Debug) ]
+pub struct Identity {
+	name: String,
+	source: IdentitySource,
+	permissions: Arc <Permissions>,
+}
+
+impl Identity {
+
+	pub fn new (
+		name: String,
+		source: IdentitySource,
+		permissions: Arc <Permissions>,
+	) -> Identity {
+
+		Identity {
+			name,
+			source,
+			permissions,
+		}
+
+	}
+
+	property_accessors! {
+		ref name: & str;
+		copy source: IdentitySource;
+		ref permissions: & Arc <Permissions>;
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,11 @@
+mod authenticator;
+mod identity;
+mod permissions;
+mod token_hash;
+
+pub use self::authenticator::*;
+pub use self::identity::*;
+pub use self::permissions::*;
+pub use self::token_hash::*;
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,157 @@
+use crate::inventory::*;
+use crate::settings::*;
+
+#[ derive (Clone, Copy, Debug, PartialEq, Eq) ]
+pub enum Scope {
+	ReadRawKeys,
+	ReadResources,
+	ReadMetrics,
+	Write,
+	Admin,
+}
+
+impl Scope {
+
+	pub fn parse (
+		name: & str,
+	) -> Result <Scope, String> {
+
+		match name {
+
+			"read-raw-keys" => Ok (Scope::ReadRawKeys),
+			"read-resources" => Ok (Scope::ReadResources),
+			"read-metrics" => Ok (Scope::ReadMetrics),
+			"write" => Ok (Scope::Write),
+			"admin" => Ok (Scope::Admin),
+
+			_ => Err (
+				format! (
+					"Invalid scope: {}",
+					name)),
+
+		}
+
+	}
+
+	pub fn name (
+		& self,
+	) -> & 'static str {
+
+		match * self {
+			Scope::ReadRawKeys => "read-raw-keys",
+			Scope::ReadResources => "read-resources",
+			Scope::ReadMetrics => "read-metrics",
+			Scope::Write => "write",
+			Scope::Admin => "admin",
+		}
+
+	}
+
+}
+
+#[ derive (Debug) ]
+pub struct Permissions {
+	scopes: Vec <Scope>,
+	namespaces: Option <Vec <String>>,
+	classes: Option <Vec <String>>,
+}
+
+impl Permissions {
+
+	property_accessors! {
+		ref scopes: & [Scope];
+		ref namespaces: & Option <Vec <String>>;
+		ref classes: & Option <Vec <String>>;
+	}
+
+	/// Unrestricted access, used for the legacy shared authorization token.
+	pub fn full (
+	) -> Permissions {
+
+		Permissions {
+			scopes: vec! [ Scope::Admin ],
+			namespaces: None,
+			classes: None,
+		}
+
+	}
+
+	pub fn from_settings (
+		permission_settings: & PermissionSettings,
+	) -> Result <Permissions, String> {
+
+		Ok (Permissions {
+
+			scopes: permission_settings.scopes.iter ().map (|scope_name|
+				Scope::parse (scope_name)
+			).collect::<Result <Vec <Scope>, String>> () ?,
+
+			namespaces: permission_settings.namespaces.clone (),
+			classes: permission_settings.classes.clone (),
+
+		})
+
+	}
+
+	/// The admin scope implies every other scope.
+	pub fn has_scope (
+		& self,
+		scope: Scope,
+	) -> bool {
+
+		self.scopes.iter ().any (|& granted_scope|
+			granted_scope == scope
+				|| granted_scope == Scope::Admin)
+
+	}
+
+	/// True if access is limited to some namespaces or classes, in which case
+	/// only data belonging to a permitted resource may be returned.
+	pub fn is_restricted (
+		& self,
+	) -> bool {
+
+		self.namespaces.is_some ()
+			|| self.classes.is_some ()
+
+	}
+
+	pub fn allows_resource (
+		& self,
+		resource: & InventoryResource,
+	) -> bool {
+
+		Self::allows_name (
+			& self.namespaces,
+			resource.identity_namespace (),
+		) && Self::allows_name (
+			& self.classes,
+			resource.identity_class (),
+		)
+
+	}
+
+	fn allows_name (
+		allowed_names: & Option <Vec <String>>,
+		name: & Option <String>,
+	) -> bool {
+
+		match (allowed_names, name) {
+
+			(& None, _) =>
+				true,
+
+			(Some (allowed_names), Some (name)) =>
+				allowed_names.iter ().any (|allowed_name|
+					allowed_name == name),
+
+			(& Some (_), & None) =>
+				false,
+
+		}
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,185 @@
+use std::fmt;
+
+use openssl::hash;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::rand;
+
+use crate::hex::*;
+
+const SALT_LENGTH: usize = 16;
+
+/// A salted SHA-256 hash of an API token, written as
+/// "sha256$<salt-hex>$<digest-hex>".
+pub struct TokenHash {
+	salt: Vec <u8>,
+	digest: Vec <u8>,
+}
+
+impl TokenHash {
+
+	pub fn create (
+		token: & str,
+	) -> Result <TokenHash, String> {
+
+		let mut salt: Vec <u8> =
+			vec! [0; SALT_LENGTH];
+
+		rand::rand_bytes (
+			& mut salt,
+		).map_err (|error|
+			format! (
+				"Error generating salt: {}",
+				error)
+		) ?;
+
+		let digest =
+			Self::digest (
+				& salt,
+				token,
+			) ?;
+
+		Ok (TokenHash {
+			salt,
+			digest,
+		})
+
+	}
+
+	pub fn parse (
+		hash_string: & str,
+	) -> Result <TokenHash, String> {
+
+		let parts: Vec <& str> =
+			hash_string.split ('$').collect ();
+
+		if parts.len () != 3 || parts [0] != "sha256" {
+
+			return Err (
+				"Token hash must be of the form sha256$<salt>$<digest>"
+					.to_string ());
+
+		}
+
+		Ok (TokenHash {
+			salt: hex_decode (parts [1]) ?,
+			digest: hex_decode (parts [2]) ?,
+		})
+
+	}
+
+	/// Compares in constant time, so the response time doesn't reveal how
+	/// much of a guessed token was correct.
+	pub fn verify (
+		& self,
+		token: & str,
+	) -> bool {
+
+		match Self::digest (
+			& self.salt,
+			token,
+		) {
+
+			Ok (ref digest) if digest.len () == self.digest.len () =>
+				memcmp::eq (
+					digest,
+					& self.digest),
+
+			_ =>
+				false,
+
+		}
+
+	}
+
+	fn digest (
+		salt: & [u8],
+		token: & str,
+	) -> Result <Vec <u8>, String> {
+
+		let mut input: Vec <u8> =
+			Vec::with_capacity (
+				salt.len () + token.len ());
+
+		input.extend_from_slice (salt);
+		input.extend_from_slice (token.as_bytes ());
+
+		hash::hash (
+			MessageDigest::sha256 (),
+			& input,
+		).map (|digest|
+			digest.to_vec ()
+		).map_err (|error|
+			format! (
+				"Error hashing token: {}",
+				error)
+		)
+
+	}
+
+}
+
+impl fmt::Display for TokenHash {
+
+	fn fmt (
+		& self,
+		formatter: & mut fmt::Formatter,
+	) -> fmt::Result {
+
+		write! (
+			formatter,
+			"sha256${}${}",
+			hex_encode (& self.salt),
+			hex_encode (& self.digest))
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	#[ test ]
+	fn created_hashes_verify_after_a_round_trip () {
+
+		let token_hash =
+			TokenHash::create ("secret").unwrap ();
+
+		let parsed =
+			TokenHash::parse (
+				& token_hash.to_string (),
+			).unwrap ();
+
+		assert! (parsed.verify ("secret"));
+		assert! (! parsed.verify ("secreT"));
+		assert! (! parsed.verify (""));
+
+		// the salt differs each time
+
+		assert_ne! (
+			TokenHash::create ("secret").unwrap ().to_string (),
+			token_hash.to_string ());
+
+	}
+
+	#[ test ]
+	fn malformed_hashes_are_rejected () {
+
+		assert! (TokenHash::parse ("secret").is_err ());
+		assert! (TokenHash::parse ("md5$00$00").is_err ());
+		assert! (TokenHash::parse ("sha256$zz$00").is_err ());
+
+		// a digest of the wrong length never matches
+
+		let token_hash =
+			TokenHash::parse ("sha256$00$00").unwrap ();
+
+		assert! (! token_hash.verify ("secret"));
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,28 +1,277 @@
-use std::error::Error;
+use std::fs;
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Error as IoError;
+use std::io::Read;
 use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process;
 use std::sync::Arc;
-
-use libc;
-
-use inventory::*;
-use settings::*;
-use server::*;
-use upstream::*;
-
+use std::time::Duration;
+
+use tokio::runtime::Builder as RuntimeBuilder;
+use tokio::runtime::Runtime;
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
+use tokio::time;
+
+use crate::inventory::*;
+use crate::privileges::*;
+use crate::settings::*;
+use crate::server::*;
+use crate::signals::*;
+use crate::systemd::*;
+use crate::upstream::*;
+
+const TOKEN_FILE_CHECK_SECS: u64 = 1;
+
+/// The running daemon. The server and the upstream watch run as tasks on the
+/// runtime, while the main thread waits for signals and reloads.
 pub struct Daemon {
+	runtime: Runtime,
+	settings: Arc <Settings>,
+	inventory: Arc <SharedInventory>,
 	upstream: Arc <Upstream>,
 	server: Arc <Server>,
+	notifier: Option <Arc <SystemdNotifier>>,
+	pid_file: Option <PidFile>,
 }
 
+/// The PID file, which stays locked for as long as the daemon runs, so that
+/// a second instance refuses to start.
+struct PidFile {
+	path: PathBuf,
+	// only held for its lock
+	#[ allow (dead_code) ]
+	file: File,
+}
+
+impl Daemon {
+
+	/// Runs until SIGTERM or SIGINT is received, and then shuts down.
+	fn run (
+		self,
+	) {
+
+		self.runtime.block_on (
+			self.signals_wait (),
+		).unwrap_or_else (|error|
+			println! (
+				"{}",
+				error)
+		);
+
+		self.shutdown ();
+
+	}
+
+	/// Waits for signals, reloading on SIGHUP, until asked to shut down.
+	async fn signals_wait (
+		& self,
+	) -> Result <(), String> {
+
+		let signal_stream = |kind: SignalKind, name: & str|
+			signal (
+				kind,
+			).map_err (|error|
+				format! (
+					"Error watching for {}: {}",
+					name,
+					error)
+			);
+
+		let mut sighup_stream =
+			signal_stream (SignalKind::hangup (), "SIGHUP") ?;
+
+		let mut sigterm_stream =
+			signal_stream (SignalKind::terminate (), "SIGTERM") ?;
+
+		let mut sigint_stream =
+			signal_stream (SignalKind::interrupt (), "SIGINT") ?;
+
+		// signals received while starting up were only recorded
+
+		if shutdown_requested () {
+			return Ok (());
+		}
+
+		if sighup_take () {
+			self.reload ();
+		}
+
+		let mut token_file_interval =
+			time::interval (
+				Duration::from_secs (TOKEN_FILE_CHECK_SECS));
+
+		loop {
+
+			tokio::select! {
+
+				_ = sigterm_stream.recv () =>
+					return Ok (()),
+
+				_ = sigint_stream.recv () =>
+					return Ok (()),
+
+				_ = sighup_stream.recv () => {
+
+					println! (
+						"Received SIGHUP, reloading");
+
+					self.reload ();
+
+				},
+
+				_ = token_file_interval.tick () =>
+					self.server.reload_tokens_if_changed (
+					).unwrap_or_else (|error|
+						println! (
+							"Error reloading API tokens: {}",
+							error)
+					),
+
+			}
+
+		}
+
+	}
+
+	fn reload (
+		& self,
+	) {
+
+		match self.inventory.reload () {
+
+			Ok (inventory) =>
+				inventory_log (
+					& inventory),
+
+			Err (error) =>
+				println! (
+					"Error reloading project data, keeping previous: {}",
+					error),
+
+		}
+
+		self.server.reload_tokens (
+		).unwrap_or_else (|error|
+			println! (
+				"Error reloading API tokens: {}",
+				error)
+		);
+
+		self.server.reopen_access_log (
+		).unwrap_or_else (|error|
+			println! (
+				"Error reopening access log: {}",
+				error)
+		);
+
+		self.server.reload_tls (
+		).unwrap_or_else (|error|
+			println! (
+				"Error reloading TLS certificate: {}",
+				error)
+		);
+
+		// only a detached daemon has redirected its output
+
+		if self.pid_file.is_some () {
+
+			stdio_redirect (
+				& self.settings,
+			).unwrap_or_else (|error|
+				println! (
+					"Error reopening log file: {}",
+					error)
+			);
+
+		}
+
+	}
+
+	/// Lets requests in progress finish, and stops the watch. The cache is
+	/// only held in memory, so there is nothing to write out.
+	fn shutdown (
+		self,
+	) {
+
+		println! (
+			"Shutting down");
+
+		if let Some (ref notifier) = self.notifier {
+
+			notifier.notify_or_log (
+				"STOPPING=1");
+
+		}
+
+		self.runtime.block_on (
+			self.server.shutdown ());
+
+		self.upstream.stop ();
+
+		drop (
+			self.pid_file);
+
+		// blocking tasks still running after the timeout are abandoned
+
+		self.runtime.shutdown_timeout (
+			Duration::from_secs (1));
+
+		println! (
+			"Shutdown complete");
+
+	}
+
+}
+
+/// Runs the daemon in the current process, without forking or writing a PID
+/// file, for use under a supervisor such as systemd or in a container.
+pub fn daemon_foreground (
+	settings: Arc <Settings>,
+) -> i32 {
+
+	let daemon = match daemon (
+		settings,
+		true,
+	) {
+
+		Ok (daemon) =>
+			daemon,
+
+		Err (error) => {
+
+			println! (
+				"{}",
+				error);
+
+			return 1;
+
+		},
+
+	};
+
+	daemon.run ();
+
+	0
+
+}
+
+/// Detaches from the terminal and runs the daemon in the background. The
+/// original process waits until startup is complete, and exits with an error
+/// if it fails.
 pub fn daemon_start (
 	settings: Arc <Settings>,
 ) -> i32 {
 
 	unsafe {
 
-		let mut pipe_fds: [libc::pid_t; 2] = [0; 2];
+		let mut pipe_fds: [libc::c_int; 2] = [0; 2];
 
 		let pipe_result =
 			libc::pipe (
@@ -48,7 +297,6 @@ pub fn daemon_start (
 
 			child_pid =>
 				daemon_parent (
-					settings,
 					pipe_fds,
 					child_pid),
 
@@ -59,8 +307,7 @@ pub fn daemon_start (
 }
 
 unsafe fn daemon_parent (
-	settings: Arc <Settings>,
-	pipe_fds: [libc::pid_t; 2],
+	pipe_fds: [libc::c_int; 2],
 	child_pid: libc::pid_t,
 ) -> i32 {
 
@@ -75,36 +322,58 @@ unsafe fn daemon_parent (
 
 	}
 
-	let mut read_buffer: [u8; 1] = [0; 1];
+	// the first child exits as soon as it has forked again
+
+	let mut child_status: libc::c_int = 0;
 
-	let read_result =
-		libc::read (
-			pipe_fds [0],
-			read_buffer.as_mut_ptr ()
-				as * mut libc::c_void,
-			1);
+	libc::waitpid (
+		child_pid,
+		& mut child_status,
+		0);
 
-	if read_result < 1 {
+	let mut pipe =
+		File::from_raw_fd (
+			pipe_fds [0]);
+
+	let mut read_buffer: [u8; 1] = [0; 1];
+
+	if pipe.read_exact (& mut read_buffer).is_err () {
 
 		panic! (
 			"Error reading from pipe in parent");
 
 	}
 
-	if read_result == 0 {
+	if read_buffer [0] == 0 {
 
 		println! (
 			"Startup complete, exiting main process");
 
+		return 0;
+
 	}
 
+	// the daemon's own output is already going to the log file, so it sends
+	// the error here as well
+
+	let mut error_message =
+		String::new ();
+
+	pipe.read_to_string (
+		& mut error_message,
+	).unwrap_or (0);
+
+	println! (
+		"{}",
+		error_message);
+
 	read_buffer [0] as i32
 
 }
 
 unsafe fn daemon_child (
 	settings: Arc <Settings>,
-	pipe_fds: [libc::pid_t; 2],
+	pipe_fds: [libc::c_int; 2],
 ) -> i32 {
 
 	let close_result =
@@ -118,77 +387,177 @@ unsafe fn daemon_child (
 
 	}
 
-	let daemon_result = daemon (
-		settings.clone (),
-	);
+	// a new session detaches us from the terminal, and forking again means
+	// we are no longer its leader, so we can never acquire another
 
-	if let Err (error) = daemon_result {
+	if libc::setsid () == -1 {
 
-		println! (
-			"{}",
-			error);
+		panic! (
+			"Setsid failed");
 
-		libc::write (
-			pipe_fds [1],
-			& [1u8]
-				as * const u8
-				as * const libc::c_void,
-			1);
+	}
 
-		process::exit (0);
+	match libc::fork () {
+
+		-1 =>
+			panic! (
+				"Fork failed"),
+
+		0 =>
+			daemon_grandchild (
+				settings,
+				File::from_raw_fd (
+					pipe_fds [1])),
+
+		_ =>
+			libc::_exit (0),
 
 	}
 
-	let write_result =
-		libc::write (
-			pipe_fds [1],
-			& [0u8]
-				as * const u8
-				as * const libc::c_void,
-			1);
+}
+
+unsafe fn daemon_grandchild (
+	settings: Arc <Settings>,
+	mut pipe: File,
+) -> i32 {
+
+	// don't keep whatever directory we were started in busy
 
-	if write_result != 1 {
+	if libc::chdir (b"/\0".as_ptr () as * const libc::c_char) != 0 {
+
+		panic! (
+			"Chdir failed");
+
+	}
+
+	let daemon_result =
+		stdio_redirect (
+			& settings,
+		).and_then (|()|
+			daemon (
+				settings.clone (),
+				false)
+		);
+
+	let daemon = match daemon_result {
+
+		Ok (daemon) =>
+			daemon,
+
+		Err (error) => {
+
+			println! (
+				"{}",
+				error);
+
+			pipe.write_all (
+				& [1u8],
+			).and_then (|()|
+				pipe.write_all (
+					error.as_bytes ())
+			).unwrap_or (());
+
+			process::exit (1);
+
+		},
+
+	};
+
+	if pipe.write_all (& [0u8]).is_err () {
 
 		panic! (
 			"Failed writing to pipe in child");
 
 	}
 
+	drop (pipe);
+
+	daemon.run ();
+
 	0
 
 }
 
 fn daemon (
 	settings: Arc <Settings>,
+	foreground: bool,
 ) -> Result <Daemon, String> {
 
-	write_pid_file (
-		settings.clone (),
-	) ?;
+	let pid_file =
+		if ! foreground {
+			Some (PidFile::create (& settings) ?)
+		} else {
+			None
+		};
+
+	// readiness is only reported in the foreground, since when forking it is
+	// reported to the parent process instead
+
+	let notifier =
+		if foreground {
+			SystemdNotifier::from_environment () ?.map (Arc::new)
+		} else {
+			None
+		};
+
+	signals_install () ?;
+
+	// the runtime's threads must be started after forking, since they would
+	// not survive it
+
+	let runtime =
+		RuntimeBuilder::new_multi_thread (
+		).enable_all (
+		).build (
+		).map_err (|error|
+			format! (
+				"Error starting runtime: {}",
+				error)
+		) ?;
 
 	let inventory =
 		Arc::new (
-			Inventory::load (
+			SharedInventory::load (
 				settings.clone (),
 			) ?
 		);
 
-	println! (
-		"Loaded project: {}",
-		inventory.project ().project_name ());
+	inventory_log (
+		& inventory.get ());
 
-	println! (
-		"Namespaces: {}",
-		inventory.namespaces_list ().len ());
+	// everything which may need root is done before dropping privileges, and
+	// nothing which acts on requests or upstream data is started until after
 
-	println! (
-		"Classes: {}",
-		inventory.classes_list ().len ());
+	let upstream_client =
+		UpstreamClient::new (
+			settings.clone (),
+		) ?;
+
+	let server_listeners =
+		Server::bind (
+			& settings,
+			runtime.handle (),
+		) ?;
+
+	if let Some (privileges) =
+		Privileges::from_settings (& settings.general) ? {
+
+		privileges.prepare (
+			& settings,
+			! foreground,
+		) ?;
+
+		privileges.drop () ?;
+
+	}
 
 	let upstream =
 		Arc::new (
 			Upstream::start (
 				settings.clone (),
+				upstream_client,
+				runtime.handle (),
+				notifier.clone (),
 			) ?
 		);
 
@@ -196,44 +565,224 @@ fn daemon (
 		Arc::new (
 			Server::start (
 				settings.clone (),
+				server_listeners,
 				upstream.clone (),
+				inventory.clone (),
+				runtime.handle (),
 			) ?
 		);
 
+	if let Some (ref notifier) = notifier {
+
+		notifier.notify (
+			& format! (
+				"READY=1\nSTATUS=Watching, etcd index is {}",
+				upstream.snapshot ().etcd_index ()),
+		) ?;
+
+	}
+
 	Ok (Daemon {
-		upstream: upstream,
-		server: server,
+		runtime,
+		settings,
+		inventory,
+		upstream,
+		server,
+		notifier,
+		pid_file,
 	})
 
 }
 
-fn write_pid_file (
-	settings: Arc <Settings>,
+fn inventory_log (
+	inventory: & Inventory,
+) {
+
+	println! (
+		"Loaded project: {}",
+		inventory.project ().project_name ());
+
+	println! (
+		"Namespaces: {}",
+		inventory.namespaces_list ().len ());
+
+	println! (
+		"Classes: {}",
+		inventory.classes_list ().len ());
+
+}
+
+/// Points standard input at /dev/null, and standard output and error at the
+/// log file, or /dev/null if there is none. This is repeated on SIGHUP, so
+/// that the log file can be rotated.
+fn stdio_redirect (
+	settings: & Settings,
 ) -> Result <(), String> {
 
-	let mut pid_file =
-		File::create (
-			& settings.general.pid_file,
+	let dev_null =
+		Path::new ("/dev/null");
+
+	let log_path =
+		settings.general.log_file.as_deref (
+		).unwrap_or (dev_null);
+
+	let input =
+		File::open (
+			dev_null,
 		).map_err (|error|
 			format! (
-				"Error creating PID file {}: {}",
-				settings.general.pid_file.to_string_lossy (),
-				error.description ())
+				"Error opening {}: {}",
+				dev_null.to_string_lossy (),
+				error)
 		) ?;
 
-	write! (
-		pid_file,
-		"{}\n",
-		unsafe { libc::getpid () },
-	).map_err (|error|
-		format! (
-			"Error writing PID file {}: {}",
-			settings.general.pid_file.to_string_lossy (),
-			error.description ())
-	) ?;
+	let output =
+		OpenOptions::new (
+		).append (
+			true,
+		).create (
+			true,
+		).open (
+			log_path,
+		).map_err (|error|
+			format! (
+				"Error opening log file {}: {}",
+				log_path.to_string_lossy (),
+				error)
+		) ?;
+
+	io::stdout ().flush ().unwrap_or (());
+
+	let redirect_failed =
+		unsafe {
+			libc::dup2 (input.as_raw_fd (), libc::STDIN_FILENO) == -1
+			|| libc::dup2 (output.as_raw_fd (), libc::STDOUT_FILENO) == -1
+			|| libc::dup2 (output.as_raw_fd (), libc::STDERR_FILENO) == -1
+		};
+
+	if redirect_failed {
+
+		return Err (
+			format! (
+				"Error redirecting output to {}: {}",
+				log_path.to_string_lossy (),
+				IoError::last_os_error ()));
+
+	}
 
 	Ok (())
 
 }
 
+impl PidFile {
+
+	/// Locks and writes the PID file. It isn't truncated until the lock is
+	/// held, since it may belong to an instance which is still running.
+	fn create (
+		settings: & Settings,
+	) -> Result <PidFile, String> {
+
+		let path =
+			settings.general.pid_file.clone ();
+
+		let mut file =
+			OpenOptions::new (
+			).read (
+				true,
+			).write (
+				true,
+			).create (
+				true,
+			).truncate (
+				false,
+			).open (
+				& path,
+			).map_err (|error|
+				format! (
+					"Error opening PID file {}: {}",
+					path.to_string_lossy (),
+					error)
+			) ?;
+
+		let lock_result =
+			unsafe {
+				libc::flock (
+					file.as_raw_fd (),
+					libc::LOCK_EX | libc::LOCK_NB)
+			};
+
+		if lock_result != 0 {
+
+			let error =
+				IoError::last_os_error ();
+
+			if error.raw_os_error () != Some (libc::EWOULDBLOCK) {
+
+				return Err (
+					format! (
+						"Error locking PID file {}: {}",
+						path.to_string_lossy (),
+						error));
+
+			}
+
+			let mut running_pid =
+				String::new ();
+
+			file.read_to_string (
+				& mut running_pid,
+			).unwrap_or (0);
+
+			return Err (
+				format! (
+					"Already running with PID {}, according to {}",
+					running_pid.trim (),
+					path.to_string_lossy ()));
+
+		}
+
+		file.set_len (
+			0,
+		).and_then (|()|
+			writeln! (
+				file,
+				"{}",
+				unsafe { libc::getpid () })
+		).map_err (|error|
+			format! (
+				"Error writing PID file {}: {}",
+				path.to_string_lossy (),
+				error)
+		) ?;
+
+		Ok (PidFile {
+			path,
+			file,
+		})
+
+	}
+
+}
+
+/// Removes the PID file, and then releases the lock on it, which is done on
+/// shutdown and on any error after the file was created.
+impl Drop for PidFile {
+
+	fn drop (
+		& mut self,
+	) {
+
+		fs::remove_file (
+			& self.path,
+		).unwrap_or_else (|error|
+			println! (
+				"Error removing PID file {}: {}",
+				self.path.to_string_lossy (),
+				error)
+		);
+
+	}
+
+}
+
 // ex: noet ts=4 filetype=rust
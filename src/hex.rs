@@ -0,0 +1,39 @@
+pub fn hex_encode (
+	bytes: & [u8],
+) -> String {
+
+	bytes.iter ().map (|byte|
+		format! ("{:02x}", byte)
+	).collect ()
+
+}
+
+pub fn hex_decode (
+	hex_string: & str,
+) -> Result <Vec <u8>, String> {
+
+	if ! hex_string.len ().is_multiple_of (2)
+		|| ! hex_string.chars ().all (|character|
+			character.is_ascii_hexdigit ()) {
+
+		return Err (
+			format! (
+				"Invalid hex string: {}",
+				hex_string));
+
+	}
+
+	(0 .. hex_string.len () / 2).map (|index|
+		u8::from_str_radix (
+			& hex_string [index * 2 .. index * 2 + 2],
+			16,
+		).map_err (|_|
+			format! (
+				"Invalid hex string: {}",
+				hex_string)
+		)
+	).collect ()
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,17 +1,18 @@
 use std::collections::HashMap;
-use std::error::Error;
 use std::fs::File;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
-use serde_yaml;
 use serde_yaml::Value as YamlValue;
 
-use inventory::*;
-use settings::*;
+use crate::inventory::*;
+use crate::settings::*;
 
 pub struct Inventory {
 
+	#[ allow (dead_code) ]
 	settings: Arc <Settings>,
 
 	project: Arc <InventoryProject>,
@@ -22,6 +23,8 @@ pub struct Inventory {
 	namespaces_list: Vec <Arc <InventoryNamespace>>,
 	namespaces_map: HashMap <String, Arc <InventoryNamespace>>,
 
+	load_duration: Duration,
+
 }
 
 impl Inventory {
@@ -36,12 +39,17 @@ impl Inventory {
 		ref namespaces_list: & Vec <Arc <InventoryNamespace>>;
 		ref namespaces_map: & HashMap <String, Arc <InventoryNamespace>>;
 
+		copy load_duration: Duration;
+
 	}
 
 	pub fn load (
 		settings: Arc <Settings>,
 	) -> Result <Inventory, String> {
 
+		let start_time =
+			Instant::now ();
+
 		let project =
 			Self::load_file (
 				& InventoryProject::new,
@@ -83,22 +91,24 @@ impl Inventory {
 
 		Ok (Inventory {
 
-			settings: settings,
+			settings,
+
+			project,
 
-			project: project,
+			classes_list,
+			classes_map,
 
-			classes_list: classes_list,
-			classes_map: classes_map,
+			namespaces_list,
+			namespaces_map,
 
-			namespaces_list: namespaces_list,
-			namespaces_map: namespaces_map,
+			load_duration: start_time.elapsed (),
 
 		})
 
 	}
 
 	fn load_files <Type> (
-		loader: & Fn (YamlValue) -> Result <Type, String>,
+		loader: & dyn Fn (YamlValue) -> Result <Type, String>,
 		name_plural: & str,
 		settings: & Settings,
 		root_path: & Path,
@@ -120,7 +130,7 @@ impl Inventory {
 	}
 
 	fn load_files_impl <Type> (
-		loader: & Fn (YamlValue) -> Result <Type, String>,
+		loader: & dyn Fn (YamlValue) -> Result <Type, String>,
 		name_plural: & str,
 		settings: & Settings,
 		path: & Path,
@@ -134,7 +144,7 @@ impl Inventory {
 				"Error reading {} directory {}: {}",
 				name_plural,
 				path.to_string_lossy (),
-				error.description ())
+				error)
 		) ? {
 
 			let dir_entry =
@@ -143,7 +153,7 @@ impl Inventory {
 						"Error reading {} directory {}: {}",
 						name_plural,
 						path.to_string_lossy (),
-						error.description ())
+						error)
 				) ?;
 
 			let dir_entry_path =
@@ -156,7 +166,7 @@ impl Inventory {
 						"Error reading {} directory {}: {}",
 						name_plural,
 						dir_entry_path.to_string_lossy (),
-						error.description ())
+						error)
 				) ?;
 
 			if dir_entry_metadata.is_file () {
@@ -196,8 +206,8 @@ impl Inventory {
 	}
 
 	fn load_file <Type> (
-		loader: & Fn (YamlValue) -> Result <Type, String>,
-		settings: & Settings,
+		loader: & dyn Fn (YamlValue) -> Result <Type, String>,
+		_settings: & Settings,
 		path: & Path,
 	) -> Result <Arc <Type>, String> {
 
@@ -208,7 +218,7 @@ impl Inventory {
 				format! (
 					"Error reading {}: {}",
 					path.to_string_lossy (),
-					error.description ())
+					error)
 			) ?;
 
 		let raw_data =
@@ -218,7 +228,7 @@ impl Inventory {
 				format! (
 					"Error parsing {}: {}",
 					path.to_string_lossy (),
-					error.description ())
+					error)
 			) ?;
 
 		let item =
@@ -1,9 +1,9 @@
-use std::path::PathBuf;
-
 use serde_yaml::Value as YamlValue;
 
 pub struct InventoryClass {
 
+	// kept for fields which aren't parsed yet
+	#[ allow (dead_code) ]
 	raw_data: YamlValue,
 
 	identity_name: String,
@@ -37,18 +37,15 @@ impl InventoryClass {
 
 		}
 
-		let class_groups: Vec <String> =
-			Vec::new ();
-
 		Ok (InventoryClass {
 
-			raw_data: raw_data,
+			raw_data,
 
-			identity_name: identity_name,
+			identity_name,
 
-			class_namespace: class_namespace,
-			class_parent_namespace: class_parent_namespace,
-			class_groups: class_groups,
+			class_namespace,
+			class_parent_namespace,
+			class_groups,
 
 		})
 
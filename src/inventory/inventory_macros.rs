@@ -1,5 +1,5 @@
-/// This module contains macros used to simplify the declaration of inventory
-/// types, and helper functions which are used by them.
+//! This module contains macros used to simplify the declaration of inventory
+//! types, and helper functions which are used by them.
 
 #[ allow (unused) ]
 pub fn capitalise (
@@ -30,14 +30,21 @@ macro_rules! inventory_parser {
 		$( $rest:tt ) *
 	) => {
 
-		use ::inventory::inventory_macros::capitalise;
-		use ::linked_hash_map::LinkedHashMap;
-		use ::serde_yaml::from_value as from_yaml_value;
+		use $crate::inventory::inventory_macros::capitalise;
 		use ::serde_yaml::Value as YamlValue;
+
+		// only some of these are needed, depending on the fields declared
+
+		#[ allow (unused_imports) ]
+		use ::serde_yaml::Mapping as YamlMappingData;
+		#[ allow (unused_imports) ]
+		use ::serde_yaml::from_value as from_yaml_value;
+		#[ allow (unused_imports) ]
 		use ::serde_yaml::Value::Mapping as YamlMapping;
+		#[ allow (unused_imports) ]
 		use ::serde_yaml::Value::Sequence as YamlSequence;
+		#[ allow (unused_imports) ]
 		use ::serde_yaml::Value::String as YamlString;
-		use ::std::error::Error;
 
 		inventory_parser_declarations! {
 			$( $rest ) *
@@ -290,11 +297,12 @@ macro_rules! inventory_parser_section_logic {
 					value.clone (),
 				).map_err (|error|
 					format! (
-						"{} value '{}.{}' must be a {} (if present)",
+						"{} value '{}.{}' must be a {} (if present): {}",
 						capitalise (stringify! ($name)),
 						stringify! ($section_name),
 						$key,
 						stringify! ($value_type),
+						error,
 					),
 				),
 			).unwrap_or (
@@ -344,7 +352,7 @@ macro_rules! inventory_parser_section_logic {
 						stringify! ($section_name),
 						$key,
 						stringify! ($value_type),
-						error.description (),
+						error,
 					),
 				),
 			).collect::<Result <Vec <$value_type>, String>> () ?;
@@ -374,7 +382,7 @@ macro_rules! inventory_parser_section_logic {
 				),
 			).unwrap_or (
 				& YamlMapping (
-					LinkedHashMap::new (),
+					YamlMappingData::new (),
 				),
 			).as_mapping ().ok_or_else (||
 				format! (
@@ -397,7 +405,7 @@ macro_rules! inventory_parser_section_logic {
 							stringify! ($section_name),
 							$key,
 							stringify! ($value_type),
-							error.description (),
+							error,
 						),
 					) ?,
 				)),
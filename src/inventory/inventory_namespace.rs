@@ -1,9 +1,9 @@
-use std::path::PathBuf;
-
 use serde_yaml::Value as YamlValue;
 
 pub struct InventoryNamespace {
 
+	// kept for fields which aren't parsed yet
+	#[ allow (dead_code) ]
 	raw_data: YamlValue,
 
 	identity_name: String,
@@ -29,9 +29,9 @@ impl InventoryNamespace {
 
 		Ok (InventoryNamespace {
 
-			raw_data: raw_data,
+			raw_data,
 
-			identity_name: identity_name,
+			identity_name,
 
 		})
 
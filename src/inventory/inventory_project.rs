@@ -136,29 +136,29 @@ impl InventoryProject {
 
 		Ok (InventoryProject {
 
-			raw_data: raw_data,
+			raw_data,
 
-			project_name: project_name,
-			project_title: project_title,
-			project_subject: project_subject,
+			project_name,
+			project_title,
+			project_subject,
 
-			project_short_name: project_short_name,
-			project_short_title: project_short_title,
+			project_short_name,
+			project_short_title,
 
-			project_script: project_script,
-			project_repository: project_repository,
-			project_website: project_website,
-			project_domain: project_domain,
+			project_script,
+			project_repository,
+			project_website,
+			project_domain,
 
-			project_developers: project_developers,
+			project_developers,
 
-			gridlinker_environment: gridlinker_environment,
-			gridlinker_default_connections: gridlinker_default_connections,
+			gridlinker_environment,
+			gridlinker_default_connections,
 
-			certificate_defaults: certificate_defaults,
+			certificate_defaults,
 
-			inventory_local_data: inventory_local_data,
-			inventory_resource_data: inventory_resource_data,
+			inventory_local_data,
+			inventory_resource_data,
 
 		})
 
@@ -1,3 +1,116 @@
+use serde_json::Value as JsonValue;
 
+use crate::inventory::*;
+
+/// The identifying parts of a resource stored upstream, as needed to decide
+/// which namespace, class and groups it belongs to.
+pub struct InventoryResource {
+
+	identity_name: String,
+	identity_class: Option <String>,
+	identity_namespace: Option <String>,
+	identity_groups: Vec <String>,
+
+}
+
+impl InventoryResource {
+
+	/// Resources are stored upstream as JSON. The namespace is taken from the
+	/// resource itself if present, otherwise from its class. Data which can't
+	/// be parsed simply yields a resource with no class or namespace.
+	pub fn from_json (
+		inventory: & Inventory,
+		resource_name: & str,
+		resource_json: & str,
+	) -> InventoryResource {
+
+		let resource_value: Option <JsonValue> =
+			serde_json::from_str (
+				resource_json,
+			).ok ();
+
+		Self::from_value (
+			inventory,
+			resource_name,
+			resource_value.as_ref ())
+
+	}
+
+	/// As from_json, for data which the caller has already parsed. Groups
+	/// are taken from the resource's class.
+	pub fn from_value (
+		inventory: & Inventory,
+		resource_name: & str,
+		resource_value: Option <& JsonValue>,
+	) -> InventoryResource {
+
+		let identity_string = |name: & str| -> Option <String> {
+			resource_value.and_then (|resource_value|
+				resource_value.pointer (
+					& format! ("/identity/{}", name),
+				).and_then (|value|
+					value.as_str ()
+				).map (|value|
+					value.to_owned ()
+				)
+			)
+		};
+
+		let identity_class =
+			identity_string ("class");
+
+		let class =
+			identity_class.as_ref ().and_then (|class_name|
+				inventory.classes_map ().get (class_name)
+			);
+
+		let identity_namespace =
+			identity_string ("namespace").or_else (||
+				class.map (|class|
+					class.class_namespace ().to_owned ()
+				)
+			);
+
+		let identity_groups =
+			class.map (|class|
+				class.class_groups ().to_vec ()
+			).unwrap_or_default ();
+
+		InventoryResource {
+			identity_name: resource_name.to_owned (),
+			identity_class,
+			identity_namespace,
+			identity_groups,
+		}
+
+	}
+
+	/// Returns the name of the resource which a raw key belongs to, if any.
+	/// Resource keys take the form "/resource/<name>/<field>".
+	pub fn name_for_key (
+		key: & str,
+	) -> Option <& str> {
+
+		if ! key.starts_with ("/resource/") {
+			return None;
+		}
+
+		let rest =
+			& key ["/resource/".len () .. ];
+
+		rest.rfind ('/').map (|position|
+			& rest [ .. position]
+		)
+
+	}
+
+	property_accessors! {
+		ref identity_name: & str;
+		ref identity_class: & Option <String>;
+		ref identity_namespace: & Option <String>;
+		ref identity_groups: & [String];
+	}
+
+}
 
 // ex: noet ts=4 filetype=rust
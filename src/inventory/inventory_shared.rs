@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use crate::inventory::*;
+use crate::settings::*;
+
+/// Holds the currently loaded inventory, which can be replaced by reloading
+/// the project data while requests are being served. If a reload fails the
+/// previous inventory remains in use, and the error is kept so that it can
+/// be reported.
+pub struct SharedInventory {
+	settings: Arc <Settings>,
+	current: RwLock <Arc <Inventory>>,
+	last_error: RwLock <Option <String>>,
+}
+
+impl SharedInventory {
+
+	pub fn load (
+		settings: Arc <Settings>,
+	) -> Result <SharedInventory, String> {
+
+		let inventory =
+			Inventory::load (
+				settings.clone (),
+			) ?;
+
+		Ok (SharedInventory {
+			settings,
+			current: RwLock::new (Arc::new (inventory)),
+			last_error: RwLock::new (None),
+		})
+
+	}
+
+	pub fn reload (
+		& self,
+	) -> Result <Arc <Inventory>, String> {
+
+		match Inventory::load (
+			self.settings.clone (),
+		) {
+
+			Ok (inventory) => {
+
+				let inventory =
+					Arc::new (inventory);
+
+				* self.current.write ().unwrap () =
+					inventory.clone ();
+
+				* self.last_error.write ().unwrap () =
+					None;
+
+				Ok (inventory)
+
+			},
+
+			Err (error) => {
+
+				* self.last_error.write ().unwrap () =
+					Some (error.clone ());
+
+				Err (error)
+
+			},
+
+		}
+
+	}
+
+	pub fn get (
+		& self,
+	) -> Arc <Inventory> {
+
+		self.current.read ().unwrap ().clone ()
+
+	}
+
+	pub fn last_error (
+		& self,
+	) -> Option <String> {
+
+		self.last_error.read ().unwrap ().clone ()
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
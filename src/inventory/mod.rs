@@ -1,18 +1,22 @@
 #[ macro_use ]
 mod inventory_macros;
 
+#[ allow (clippy::module_inception) ]
 mod inventory;
 mod inventory_resource;
 mod inventory_class;
 mod inventory_group;
 mod inventory_namespace;
 mod inventory_project;
+mod inventory_shared;
 
 pub use self::inventory_resource::*;
 pub use self::inventory_class::*;
+#[ allow (unused_imports) ]
 pub use self::inventory_group::*;
 pub use self::inventory_namespace::*;
 pub use self::inventory_project::*;
+pub use self::inventory_shared::*;
 pub use self::inventory::*;
 
 // ex: noet ts=4 filetype=rust
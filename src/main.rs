@@ -4,32 +4,54 @@ extern crate lazy_static;
 #[ macro_use ]
 extern crate serde_derive;
 
+extern crate arc_swap;
+extern crate bytes;
+extern crate flate2;
+extern crate http_body_util;
 extern crate hyper;
+extern crate hyper_util;
+extern crate im;
 extern crate libc;
-extern crate linked_hash_map;
 extern crate openssl;
+extern crate percent_encoding;
 extern crate regex;
+extern crate serde;
+
+#[ macro_use ]
 extern crate serde_json;
+
 extern crate serde_yaml;
+extern crate time;
+extern crate tokio;
+extern crate tokio_openssl;
+extern crate url;
 
 #[ macro_use ]
 mod macros;
 
+mod auth;
 mod daemon;
 mod etcd;
+mod hex;
 mod inventory;
+mod privileges;
 mod routes;
 mod settings;
 mod server;
+mod signals;
 mod ssl;
+mod systemd;
+mod unix_socket;
 mod upstream;
 
 use std::env;
 use std::ffi::OsString;
+use std::io;
 use std::path::PathBuf;
 use std::process;
 use std::sync::Arc;
 
+use auth::*;
 use daemon::*;
 use settings::*;
 
@@ -60,19 +82,29 @@ fn main_impl (
 	let arguments: Vec <OsString> =
 		env::args_os ().collect ();
 
-	if arguments.len () != 2 {
+	let foreground =
+		arguments.len () == 3
+			&& arguments [1] == "--foreground";
+
+	if arguments.len () != 2 && ! foreground {
 
 		return Err (
 			format! (
-				"Syntax: {} <SETTINGS-PATH>",
+				"Syntax: {} [--foreground] <SETTINGS-PATH>\n\
+				Syntax: {} hash-token",
+				arguments [0].to_string_lossy (),
 				arguments [0].to_string_lossy ())
 		);
 
 	}
 
+	if ! foreground && arguments [1] == "hash-token" {
+		return hash_token ();
+	}
+
 	let settings_path =
 		PathBuf::from (
-			arguments [1].to_owned (),
+			arguments [arguments.len () - 1].to_owned (),
 		);
 
 	println! (
@@ -86,9 +118,53 @@ fn main_impl (
 			) ?
 		);
 
-	Ok (daemon_start (
-		settings.clone (),
-	))
+	if foreground {
+
+		Ok (daemon_foreground (
+			settings.clone (),
+		))
+
+	} else {
+
+		Ok (daemon_start (
+			settings.clone (),
+		))
+
+	}
+
+}
+
+/// Reads a token from standard input and prints its salted hash, suitable
+/// for use in the token file.
+fn hash_token (
+) -> Result <i32, String> {
+
+	let mut token =
+		String::new ();
+
+	io::stdin ().read_line (
+		& mut token,
+	).map_err (|error|
+		format! (
+			"Error reading token: {}",
+			error)
+	) ?;
+
+	let token =
+		token.trim ();
+
+	if token.is_empty () {
+
+		return Err (
+			"Token must not be empty".to_string ());
+
+	}
+
+	println! (
+		"{}",
+		TokenHash::create (token) ?);
+
+	Ok (0)
 
 }
 
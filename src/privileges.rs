@@ -0,0 +1,423 @@
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::Error as IoError;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::settings::*;
+
+/// The user and group the daemon switches to, from the general settings.
+pub struct Privileges {
+	user: Option <(String, libc::uid_t)>,
+	group_id: libc::gid_t,
+}
+
+impl Privileges {
+
+	pub fn from_settings (
+		general_settings: & GeneralSettings,
+	) -> Result <Option <Privileges>, String> {
+
+		let user =
+			match general_settings.user {
+
+			Some (ref user_name) =>
+				Some ((
+					user_name.clone (),
+					user_ids_for_name (user_name) ?,
+				)),
+
+			None =>
+				None,
+
+		};
+
+		let group_id =
+			match (general_settings.group.as_ref (), user.as_ref ()) {
+
+			(Some (group_name), _) =>
+				group_id_for_name (group_name) ?,
+
+			(None, Some (& (_, (_, user_group_id)))) =>
+				user_group_id,
+
+			(None, None) =>
+				return Ok (None),
+
+		};
+
+		Ok (Some (Privileges {
+			user: user.map (|(user_name, (user_id, _))|
+				(user_name, user_id)),
+			group_id,
+		}))
+
+	}
+
+	/// Hands the files the daemon writes to over to the user it will run as,
+	/// and warns about those it won't be able to remove or recreate.
+	pub fn prepare (
+		& self,
+		settings: & Settings,
+		detached: bool,
+	) -> Result <(), String> {
+
+		let user_id =
+			self.user.as_ref ().map (|& (_, user_id)|
+				user_id
+			).unwrap_or (libc::uid_t::MAX);
+
+		// only a detached daemon writes its output to the log file, or a PID
+		// file
+
+		let log_files =
+			settings.general.log_file.iter ().filter (|_|
+				detached
+			).chain (
+				settings.server.access_log.path.iter ());
+
+		for log_file in log_files {
+
+			OpenOptions::new (
+			).append (
+				true,
+			).create (
+				true,
+			).open (
+				log_file,
+			).map_err (|error|
+				format! (
+					"Error opening log file {}: {}",
+					log_file.to_string_lossy (),
+					error)
+			) ?;
+
+			path_chown (
+				log_file,
+				user_id,
+				self.group_id,
+			) ?;
+
+			self.directory_check (
+				log_file,
+				"recreated after it is rotated");
+
+		}
+
+		if detached {
+
+			path_chown (
+				& settings.general.pid_file,
+				user_id,
+				self.group_id,
+			) ?;
+
+			self.directory_check (
+				& settings.general.pid_file,
+				"removed on shutdown");
+
+		}
+
+		// the certificate is loaded again on SIGHUP, after dropping privileges,
+		// and the key is often only readable by root
+
+		if let Some (ref tls_settings) = settings.server.tls {
+
+			self.readable_check (
+				& tls_settings.certificate);
+
+			self.readable_check (
+				& tls_settings.private_key);
+
+		}
+
+		if let Some (ref unix_socket_settings) =
+			settings.server.unix_socket {
+
+			self.directory_check (
+				& unix_socket_settings.path,
+				"removed on shutdown");
+
+		}
+
+		Ok (())
+
+	}
+
+	fn directory_check (
+		& self,
+		path: & Path,
+		consequence: & str,
+	) {
+
+		let directory =
+			match path.parent () {
+				Some (directory) => directory,
+				None => return,
+			};
+
+		let metadata =
+			match directory.metadata () {
+				Ok (metadata) => metadata,
+				Err (_) => return,
+			};
+
+		let user_id =
+			self.user.as_ref ().map (|& (_, user_id)|
+				user_id
+			).unwrap_or (0);
+
+		// supplementary groups are not considered, so this may warn when it
+		// needn't
+
+		let writable =
+			user_id == 0
+			|| (metadata.uid () == user_id && metadata.mode () & 0o200 != 0)
+			|| (metadata.gid () == self.group_id && metadata.mode () & 0o020 != 0)
+			|| metadata.mode () & 0o002 != 0;
+
+		if ! writable {
+
+			println! (
+				"Warning: {} can't be {}, since {} is not writable after \
+				dropping privileges",
+				path.to_string_lossy (),
+				consequence,
+				directory.to_string_lossy ());
+
+		}
+
+	}
+
+	fn readable_check (
+		& self,
+		path: & Path,
+	) {
+
+		let metadata =
+			match path.metadata () {
+				Ok (metadata) => metadata,
+				Err (_) => return,
+			};
+
+		let user_id =
+			self.user.as_ref ().map (|& (_, user_id)|
+				user_id
+			).unwrap_or (0);
+
+		let readable =
+			user_id == 0
+			|| (metadata.uid () == user_id && metadata.mode () & 0o400 != 0)
+			|| (metadata.gid () == self.group_id && metadata.mode () & 0o040 != 0)
+			|| metadata.mode () & 0o004 != 0;
+
+		if ! readable {
+
+			println! (
+				"Warning: {} can't be reloaded, since it is not readable after \
+				dropping privileges",
+				path.to_string_lossy ());
+
+		}
+
+	}
+
+	/// Switches to the user and group, including the user's supplementary
+	/// groups. Any failure is an error, rather than carrying on as root.
+	pub fn drop (
+		& self,
+	) -> Result <(), String> {
+
+		let groups_result =
+			match self.user {
+
+			Some ((ref user_name, _)) => {
+
+				let user_name_c =
+					CString::new (
+						user_name.as_str (),
+					).map_err (|_|
+						format! (
+							"Invalid user name: {}",
+							user_name)
+					) ?;
+
+				unsafe {
+					libc::initgroups (
+						user_name_c.as_ptr (),
+						self.group_id)
+				}
+
+			},
+
+			None =>
+				unsafe {
+					libc::setgroups (
+						1,
+						& self.group_id)
+				},
+
+		};
+
+		if groups_result != 0 {
+
+			return Err (
+				format! (
+					"Error setting supplementary groups: {}",
+					IoError::last_os_error ()));
+
+		}
+
+		if unsafe { libc::setgid (self.group_id) } != 0 {
+
+			return Err (
+				format! (
+					"Error changing to group {}: {}",
+					self.group_id,
+					IoError::last_os_error ()));
+
+		}
+
+		if let Some ((ref user_name, user_id)) = self.user {
+
+			if unsafe { libc::setuid (user_id) } != 0 {
+
+				return Err (
+					format! (
+						"Error changing to user {}: {}",
+						user_name,
+						IoError::last_os_error ()));
+
+			}
+
+			if user_id != 0 && unsafe { libc::setuid (0) } == 0 {
+
+				return Err (
+					format! (
+						"Still able to regain root after changing to user {}",
+						user_name));
+
+			}
+
+			println! (
+				"Running as user {}, group {}",
+				user_name,
+				self.group_id);
+
+		} else {
+
+			println! (
+				"Running as group {}",
+				self.group_id);
+
+		}
+
+		Ok (())
+
+	}
+
+}
+
+/// Changes the owner and group of a file. An id of -1 leaves it unchanged.
+pub fn path_chown (
+	path: & Path,
+	user_id: libc::uid_t,
+	group_id: libc::gid_t,
+) -> Result <(), String> {
+
+	let path_c =
+		CString::new (
+			path.as_os_str ().as_bytes (),
+		).map_err (|_|
+			format! (
+				"Invalid path: {}",
+				path.to_string_lossy ())
+		) ?;
+
+	if unsafe { libc::chown (path_c.as_ptr (), user_id, group_id) } != 0 {
+
+		return Err (
+			format! (
+				"Error changing ownership of {}: {}",
+				path.to_string_lossy (),
+				IoError::last_os_error ()));
+
+	}
+
+	Ok (())
+
+}
+
+pub fn user_id_for_name (
+	name: & str,
+) -> Result <libc::uid_t, String> {
+
+	user_ids_for_name (
+		name,
+	).map (|(user_id, _)|
+		user_id
+	)
+
+}
+
+/// Returns a user's id and the id of their primary group.
+pub fn user_ids_for_name (
+	name: & str,
+) -> Result <(libc::uid_t, libc::gid_t), String> {
+
+	let name_c =
+		CString::new (
+			name,
+		).map_err (|_|
+			format! (
+				"Invalid user name: {}",
+				name)
+		) ?;
+
+	let passwd =
+		unsafe { libc::getpwnam (name_c.as_ptr ()) };
+
+	if passwd.is_null () {
+
+		return Err (
+			format! (
+				"User not found: {}",
+				name));
+
+	}
+
+	Ok (unsafe { ((* passwd).pw_uid, (* passwd).pw_gid) })
+
+}
+
+pub fn group_id_for_name (
+	name: & str,
+) -> Result <libc::gid_t, String> {
+
+	let name_c =
+		CString::new (
+			name,
+		).map_err (|_|
+			format! (
+				"Invalid group name: {}",
+				name)
+		) ?;
+
+	let group =
+		unsafe { libc::getgrnam (name_c.as_ptr ()) };
+
+	if group.is_null () {
+
+		return Err (
+			format! (
+				"Group not found: {}",
+				name));
+
+	}
+
+	Ok (unsafe { (* group).gr_gid })
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,435 @@
+use serde_json::Value as JsonValue;
+
+use crate::auth::*;
+use crate::routes::*;
+use crate::server::*;
+
+pub static ROUTE_ADMIN_RELOAD_PROJECT: Route = Route {
+	name: "admin_reload_project",
+	summary: "Reloads the project data, keeping the previous data on failure",
+	methods: & [ RouteMethod::Post ],
+	path: "/admin/reload-project",
+	query_params: & [],
+	scope: Some (Scope::Admin),
+	formats: DATA_FORMATS,
+	response_schema: admin_reload_project_schema,
+	handler: & route_admin_reload_project,
+};
+
+pub static ROUTE_ADMIN_RESYNC: Route = Route {
+	name: "admin_resync",
+	summary: "Replaces the upstream cache with a full load from etcd",
+	methods: & [ RouteMethod::Post ],
+	path: "/admin/resync",
+	query_params: & [],
+	scope: Some (Scope::Admin),
+	formats: DATA_FORMATS,
+	response_schema: admin_resync_schema,
+	handler: & route_admin_resync,
+};
+
+pub static ROUTE_ADMIN_STATE: Route = Route {
+	name: "admin_state",
+	summary: "Returns the state of the upstream watch, cache and project",
+	methods: & [ RouteMethod::Get ],
+	path: "/admin/state",
+	query_params: & [],
+	scope: Some (Scope::Admin),
+	formats: DATA_FORMATS,
+	response_schema: admin_state_schema,
+	handler: & route_admin_state,
+};
+
+pub static ROUTE_ADMIN_DUMP: Route = Route {
+	name: "admin_dump",
+	summary: "Returns every key in the upstream cache, from one snapshot",
+	methods: & [ RouteMethod::Get ],
+	path: "/admin/dump",
+	query_params: & [],
+	scope: Some (Scope::Admin),
+	formats: DATA_FORMATS,
+	response_schema: admin_dump_schema,
+	handler: & route_admin_dump,
+};
+
+fn admin_reload_project_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"project_name": { "type": "string" },
+			"namespaces": { "type": "integer" },
+			"classes": { "type": "integer" }
+		}
+	})
+
+}
+
+fn admin_resync_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"etcd_index": { "type": "integer" },
+			"cache_size": { "type": "integer" }
+		}
+	})
+
+}
+
+fn admin_state_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"uptime_seconds": { "type": "number" },
+			"upstream": {
+				"type": "object",
+				"properties": {
+					"member": { "type": "string" },
+					"etcd_index": { "type": "integer" },
+					"cache_size": { "type": "integer" },
+					"watch_alive": { "type": "boolean" },
+					"seconds_since_update": { "type": "number" },
+					"watch_errors": { "type": "integer" },
+					"watch_reconnects": { "type": "integer" },
+					"watch_consecutive_errors": { "type": "integer" },
+					"watch_last_error": { "type": "string" }
+				}
+			},
+			"project": {
+				"type": "object",
+				"properties": {
+					"project_name": { "type": "string" },
+					"namespaces": { "type": "integer" },
+					"classes": { "type": "integer" },
+					"load_seconds": { "type": "number" },
+					"last_error": { "type": "string" }
+				}
+			}
+		}
+	})
+
+}
+
+fn admin_dump_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"etcd_index": { "type": "integer" },
+			"nodes": {
+				"type": "array",
+				"items": { "$ref": "#/components/schemas/KeyNode" }
+			}
+		}
+	})
+
+}
+
+#[ derive (Serialize) ]
+struct AdminProject <'a> {
+
+	#[ serde (rename = "project_name") ]
+	project_name: & 'a str,
+
+	#[ serde (rename = "namespaces") ]
+	namespaces: usize,
+
+	#[ serde (rename = "classes") ]
+	classes: usize,
+
+}
+
+#[ derive (Serialize) ]
+struct AdminResync {
+
+	#[ serde (rename = "etcd_index") ]
+	etcd_index: u64,
+
+	#[ serde (rename = "cache_size") ]
+	cache_size: usize,
+
+}
+
+#[ derive (Serialize) ]
+struct AdminState <'a> {
+
+	#[ serde (rename = "uptime_seconds") ]
+	uptime_seconds: f64,
+
+	#[ serde (rename = "upstream") ]
+	upstream: AdminStateUpstream,
+
+	#[ serde (rename = "project") ]
+	project: AdminStateProject <'a>,
+
+}
+
+#[ derive (Serialize) ]
+struct AdminStateUpstream {
+
+	#[ serde (rename = "member") ]
+	member: String,
+
+	#[ serde (rename = "etcd_index") ]
+	etcd_index: u64,
+
+	#[ serde (rename = "cache_size") ]
+	cache_size: usize,
+
+	#[ serde (rename = "watch_alive") ]
+	watch_alive: bool,
+
+	#[ serde (rename = "seconds_since_update") ]
+	seconds_since_update: f64,
+
+	#[ serde (rename = "watch_errors") ]
+	watch_errors: u64,
+
+	#[ serde (rename = "watch_reconnects") ]
+	watch_reconnects: u64,
+
+	#[ serde (rename = "watch_consecutive_errors") ]
+	watch_consecutive_errors: u64,
+
+	#[ serde (rename = "watch_last_error", skip_serializing_if = "Option::is_none") ]
+	watch_last_error: Option <String>,
+
+}
+
+#[ derive (Serialize) ]
+struct AdminStateProject <'a> {
+
+	#[ serde (rename = "project_name") ]
+	project_name: & 'a str,
+
+	#[ serde (rename = "namespaces") ]
+	namespaces: usize,
+
+	#[ serde (rename = "classes") ]
+	classes: usize,
+
+	#[ serde (rename = "load_seconds") ]
+	load_seconds: f64,
+
+	#[ serde (rename = "last_error", skip_serializing_if = "Option::is_none") ]
+	last_error: Option <String>,
+
+}
+
+#[ derive (Serialize) ]
+struct AdminDump <'a> {
+
+	#[ serde (rename = "etcd_index") ]
+	etcd_index: u64,
+
+	#[ serde (rename = "nodes") ]
+	nodes: Vec <AdminDumpNode <'a>>,
+
+}
+
+#[ derive (Serialize) ]
+struct AdminDumpNode <'a> {
+
+	#[ serde (rename = "key") ]
+	key: & 'a str,
+
+	#[ serde (rename = "dir") ]
+	dir: bool,
+
+	#[ serde (rename = "value", skip_serializing_if = "Option::is_none") ]
+	value: Option <& 'a str>,
+
+	#[ serde (rename = "created_index") ]
+	created_index: u64,
+
+	#[ serde (rename = "modified_index") ]
+	modified_index: u64,
+
+}
+
+/// Reloads the project data, as on SIGHUP, but reports the outcome. Requests
+/// already being handled keep the inventory they started with.
+fn route_admin_reload_project (
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_ADMIN_RELOAD_PROJECT.formats,
+		) ?;
+
+	let inventory =
+		context.shared_inventory.reload (
+		).map_err (|error| {
+
+			println! (
+				"Error reloading project data, keeping previous: {}",
+				error);
+
+			ApiError::OperationFailed (
+				format! (
+					"Error reloading project data: {}",
+					error))
+
+		}) ?;
+
+	println! (
+		"Reloaded project: {}",
+		inventory.project ().project_name ());
+
+	format.send (
+		response,
+		& AdminProject {
+			project_name: inventory.project ().project_name (),
+			namespaces: inventory.namespaces_list ().len (),
+			classes: inventory.classes_list ().len (),
+		})
+
+}
+
+/// Replaces the cache with a full load from etcd. The watch is paused while
+/// this happens, and resumes from the new etcd index.
+fn route_admin_resync (
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_ADMIN_RESYNC.formats,
+		) ?;
+
+	context.upstream.resync (
+	).map_err (|error| {
+
+		println! (
+			"Error resyncing upstream: {}",
+			error);
+
+		ApiError::OperationFailed (
+			format! (
+				"Error resyncing upstream: {}",
+				error))
+
+	}) ?;
+
+	// the watch may already have moved on, so report the latest snapshot
+
+	let snapshot =
+		context.upstream.snapshot ();
+
+	format.send (
+		response,
+		& AdminResync {
+			etcd_index: snapshot.etcd_index (),
+			cache_size: snapshot.data ().len (),
+		})
+
+}
+
+fn route_admin_state (
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_ADMIN_STATE.formats,
+		) ?;
+
+	let upstream_status =
+		context.upstream.status ();
+
+	let start_time =
+		context.state.start_time;
+
+	format.send (
+		response,
+		& AdminState {
+
+			uptime_seconds: duration_seconds (
+				start_time.elapsed ()),
+
+			upstream: AdminStateUpstream {
+				member: upstream_status.member,
+				etcd_index: upstream_status.etcd_index,
+				cache_size: upstream_status.cache_size,
+				watch_alive: upstream_status.watch_alive,
+				seconds_since_update: duration_seconds (
+					upstream_status.last_update.elapsed ()),
+				watch_errors: upstream_status.watch_errors,
+				watch_reconnects: upstream_status.watch_reconnects,
+				watch_consecutive_errors:
+					upstream_status.watch_consecutive_errors,
+				watch_last_error: upstream_status.watch_last_error,
+			},
+
+			project: AdminStateProject {
+				project_name: context.inventory.project ().project_name (),
+				namespaces: context.inventory.namespaces_list ().len (),
+				classes: context.inventory.classes_list ().len (),
+				load_seconds: duration_seconds (
+					context.inventory.load_duration ()),
+				last_error: context.shared_inventory.last_error (),
+			},
+
+		})
+
+}
+
+/// Returns every key in the cache, from one snapshot, in key order.
+fn route_admin_dump (
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_ADMIN_DUMP.formats,
+		) ?;
+
+	let snapshot =
+		context.upstream.snapshot ();
+
+	let nodes: Vec <AdminDumpNode> =
+		snapshot.data ().descendants (
+			"",
+		).map (|node|
+			AdminDumpNode {
+				key: node.key (),
+				dir: node.dir (),
+				value: if node.dir () { None } else { Some (node.value ()) },
+				created_index: node.created_index (),
+				modified_index: node.modified_index (),
+			}
+		).collect ();
+
+	format.send (
+		response,
+		& AdminDump {
+			etcd_index: snapshot.etcd_index (),
+			nodes,
+		})
+
+}
+
+// ex: noet ts=4 filetype=rust
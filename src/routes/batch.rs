@@ -0,0 +1,316 @@
+use serde_json::Value as JsonValue;
+
+use crate::auth::*;
+use crate::inventory::*;
+use crate::routes::*;
+use crate::server::*;
+use crate::upstream::*;
+
+/// Limits on a single batch, so that one request can't take too long or use
+/// unbounded memory.
+const BATCH_MAX_BODY_SIZE: u64 = 1024 * 1024;
+const BATCH_MAX_ITEMS: usize = 1000;
+
+pub static ROUTE_BATCH: Route = Route {
+	name: "batch",
+	summary: "Returns many resources and raw keys from one snapshot",
+	methods: & [ RouteMethod::Post ],
+	path: "/batch",
+	query_params: & [],
+	scope: Some (Scope::ReadResources),
+	formats: DATA_FORMATS,
+	response_schema: batch_schema,
+	handler: & route_batch,
+};
+
+fn batch_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"etcd_index": { "type": "integer" },
+			"resources": {
+				"type": "array",
+				"items": {
+					"type": "object",
+					"properties": {
+						"name": { "type": "string" },
+						"resource": { "type": "string" },
+						"error": { "$ref": "#/components/schemas/BatchError" }
+					}
+				}
+			},
+			"keys": {
+				"type": "array",
+				"items": {
+					"type": "object",
+					"properties": {
+						"key": { "type": "string" },
+						"value": { "type": "string" },
+						"error": { "$ref": "#/components/schemas/BatchError" }
+					}
+				}
+			}
+		}
+	})
+
+}
+
+#[ derive (Deserialize) ]
+struct BatchRequest {
+
+	#[ serde (rename = "resources", default) ]
+	resources: Vec <String>,
+
+	#[ serde (rename = "keys", default) ]
+	keys: Vec <String>,
+
+}
+
+#[ derive (Serialize) ]
+struct BatchResponse <'a> {
+
+	#[ serde (rename = "etcd_index") ]
+	etcd_index: u64,
+
+	#[ serde (rename = "resources") ]
+	resources: Vec <BatchResource <'a>>,
+
+	#[ serde (rename = "keys") ]
+	keys: Vec <BatchKey <'a>>,
+
+}
+
+#[ derive (Serialize) ]
+struct BatchResource <'a> {
+
+	#[ serde (rename = "name") ]
+	name: & 'a str,
+
+	#[ serde (rename = "resource", skip_serializing_if = "Option::is_none") ]
+	resource: Option <& 'a str>,
+
+	#[ serde (rename = "error", skip_serializing_if = "Option::is_none") ]
+	error: Option <BatchError>,
+
+}
+
+#[ derive (Serialize) ]
+struct BatchKey <'a> {
+
+	#[ serde (rename = "key") ]
+	key: & 'a str,
+
+	#[ serde (rename = "value", skip_serializing_if = "Option::is_none") ]
+	value: Option <& 'a str>,
+
+	#[ serde (rename = "error", skip_serializing_if = "Option::is_none") ]
+	error: Option <BatchError>,
+
+}
+
+#[ derive (Serialize) ]
+struct BatchError {
+
+	#[ serde (rename = "code") ]
+	code: & 'static str,
+
+	#[ serde (rename = "message") ]
+	message: String,
+
+}
+
+impl From <ApiError> for BatchError {
+
+	fn from (
+		error: ApiError,
+	) -> BatchError {
+
+		BatchError {
+			code: error.code (),
+			message: error.message (),
+		}
+
+	}
+
+}
+
+/// Fetches resources and raw keys in one request, reporting errors per item.
+fn route_batch (
+	context: & RouteContext,
+	mut request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_BATCH.formats,
+		) ?;
+
+	let batch_request =
+		batch_request_read (
+			& mut request,
+		) ?;
+
+	// every item is read from the same snapshot, so the response is
+	// consistent with a single etcd index
+
+	let snapshot =
+		context.upstream.snapshot ();
+
+	let data =
+		snapshot.data ();
+
+	let permissions =
+		context.identity ().permissions ();
+
+	let resource_permitted = |resource_name: & str, node: & NodeData| -> bool {
+		! permissions.is_restricted ()
+		|| permissions.allows_resource (
+			& InventoryResource::from_json (
+				& context.inventory,
+				resource_name,
+				node.value ()))
+	};
+
+	let resources: Vec <BatchResource> =
+		batch_request.resources.iter ().map (|resource_name|
+			match data.resource (resource_name) {
+
+				// forbidden resources are reported as missing, as for single
+				// resources
+
+				Some (node) if resource_permitted (resource_name, node) =>
+					BatchResource {
+						name: resource_name,
+						resource: Some (node.value ()),
+						error: None,
+					},
+
+				_ => BatchResource {
+					name: resource_name,
+					resource: None,
+					error: Some (
+						ApiError::ResourceNotFound (
+							resource_name.clone (),
+						).into ()),
+				},
+
+			}
+		).collect ();
+
+	let keys_permitted =
+		permissions.has_scope (
+			Scope::ReadRawKeys);
+
+	let keys: Vec <BatchKey> =
+		batch_request.keys.iter ().map (|key| {
+
+			// as for single keys, restricted identities may only read keys
+			// belonging to a resource they are allowed to see
+
+			let permitted =
+				! permissions.is_restricted ()
+				|| InventoryResource::name_for_key (
+					key,
+				).and_then (|resource_name|
+					data.resource (
+						resource_name,
+					).map (|resource_node|
+						resource_permitted (
+							resource_name,
+							resource_node))
+				).unwrap_or (false);
+
+			// directories are listed by the keys route, not returned here
+
+			let node =
+				data.get (
+					key,
+				).and_then (|node|
+					if node.dir () { None } else { Some (node) }
+				);
+
+			// keys outside the identity's resources are reported as missing,
+			// but lacking the scope for keys at all is not hidden
+
+			match node {
+
+				_ if ! keys_permitted => BatchKey {
+					key,
+					value: None,
+					error: Some (ApiError::Forbidden.into ()),
+				},
+
+				Some (node) if permitted => BatchKey {
+					key,
+					value: Some (node.value ()),
+					error: None,
+				},
+
+				_ => BatchKey {
+					key,
+					value: None,
+					error: Some (
+						ApiError::KeyNotFound (
+							key.clone (),
+						).into ()),
+				},
+
+			}
+
+		}).collect ();
+
+	format.send (
+		response,
+		& BatchResponse {
+			etcd_index: snapshot.etcd_index (),
+			resources,
+			keys,
+		})
+
+}
+
+fn batch_request_read (
+	request: & mut ServerRequest,
+) -> Result <BatchRequest, ApiError> {
+
+	let body =
+		String::from_utf8 (
+			request.read_body (
+				BATCH_MAX_BODY_SIZE,
+			) ?,
+		).map_err (|_|
+			ApiError::BadRequest (
+				"Request body must be valid UTF-8".to_string ())
+		) ?;
+
+	let batch_request: BatchRequest =
+		serde_json::from_str (
+			& body,
+		).map_err (|error|
+			ApiError::BadRequest (
+				format! (
+					"Invalid batch request: {}",
+					error))
+		) ?;
+
+	if batch_request.resources.len () + batch_request.keys.len ()
+		> BATCH_MAX_ITEMS {
+
+		return Err (
+			ApiError::BadRequest (
+				format! (
+					"Batch must not contain more than {} items",
+					BATCH_MAX_ITEMS)));
+
+	}
+
+	Ok (batch_request)
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,267 @@
+use hyper::StatusCode as HyperStatusCode;
+
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+use crate::routes::*;
+use crate::server::*;
+
+pub static ROUTE_HEALTH_LIVE: Route = Route {
+	name: "health_live",
+	summary: "Shows that the server is answering requests",
+	methods: & [ RouteMethod::Get ],
+	path: "/health/live",
+	query_params: & [],
+	scope: None,
+	formats: DATA_FORMATS,
+	response_schema: health_live_schema,
+	handler: & route_health_live,
+};
+
+pub static ROUTE_HEALTH_READY: Route = Route {
+	name: "health_ready",
+	summary: "Shows whether the data being served is complete and current",
+	methods: & [ RouteMethod::Get ],
+	path: "/health/ready",
+	query_params: & [],
+	scope: None,
+	formats: DATA_FORMATS,
+	response_schema: health_ready_schema,
+	handler: & route_health_ready,
+};
+
+#[ derive (Serialize) ]
+struct HealthLive {
+
+	#[ serde (rename = "status") ]
+	status: & 'static str,
+
+}
+
+#[ derive (Serialize) ]
+struct HealthReady {
+
+	#[ serde (rename = "status") ]
+	status: & 'static str,
+
+	#[ serde (rename = "components") ]
+	components: HealthReadyComponents,
+
+}
+
+#[ derive (Serialize) ]
+struct HealthReadyComponents {
+
+	#[ serde (rename = "upstream") ]
+	upstream: HealthReadyUpstream,
+
+	#[ serde (rename = "inventory") ]
+	inventory: HealthReadyInventory,
+
+}
+
+#[ derive (Serialize) ]
+struct HealthReadyUpstream {
+
+	#[ serde (rename = "status") ]
+	status: & 'static str,
+
+	#[ serde (rename = "watch_alive") ]
+	watch_alive: bool,
+
+	#[ serde (rename = "watch_consecutive_errors") ]
+	watch_consecutive_errors: u64,
+
+	#[ serde (rename = "watch_error_threshold") ]
+	watch_error_threshold: u64,
+
+	#[ serde (rename = "watch_last_error") ]
+	watch_last_error: Option <String>,
+
+	#[ serde (rename = "etcd_index") ]
+	etcd_index: u64,
+
+	#[ serde (rename = "seconds_since_update") ]
+	seconds_since_update: f64,
+
+}
+
+#[ derive (Serialize) ]
+struct HealthReadyInventory {
+
+	#[ serde (rename = "status") ]
+	status: & 'static str,
+
+	#[ serde (rename = "project") ]
+	project: String,
+
+	#[ serde (rename = "last_error") ]
+	last_error: Option <String>,
+
+}
+
+fn health_live_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"status": { "type": "string" }
+		}
+	})
+
+}
+
+fn health_ready_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"status": { "type": "string", "enum": [ "ready", "not_ready" ] },
+			"components": {
+				"type": "object",
+				"properties": {
+					"upstream": {
+						"type": "object",
+						"properties": {
+							"status": { "type": "string" },
+							"watch_alive": { "type": "boolean" },
+							"watch_consecutive_errors": { "type": "integer" },
+							"watch_error_threshold": { "type": "integer" },
+							"watch_last_error": { "type": "string", "nullable": true },
+							"etcd_index": { "type": "integer" },
+							"seconds_since_update": { "type": "number" }
+						}
+					},
+					"inventory": {
+						"type": "object",
+						"properties": {
+							"status": { "type": "string" },
+							"project": { "type": "string" },
+							"last_error": { "type": "string", "nullable": true }
+						}
+					}
+				}
+			}
+		}
+	})
+
+}
+
+/// Liveness only shows that the process is answering requests.
+fn route_health_live (
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_HEALTH_LIVE.formats,
+		) ?;
+
+	send_health (
+		format,
+		response,
+		HyperStatusCode::OK,
+		& HealthLive {
+			status: "ok",
+		})
+
+}
+
+/// Readiness shows that the data being served is current. The upstream must
+/// still be watching for changes without repeated errors, and the project
+/// data must have loaded.
+fn route_health_ready (
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_HEALTH_READY.formats,
+		) ?;
+
+	let upstream_status =
+		context.upstream.status ();
+
+	let watch_error_threshold =
+		context.settings.upstream.watch_error_threshold;
+
+	let upstream_ready =
+		upstream_status.watch_alive
+			&& upstream_status.watch_consecutive_errors
+				<= watch_error_threshold;
+
+	let inventory_last_error =
+		context.shared_inventory.last_error ();
+
+	let inventory_ready =
+		inventory_last_error.is_none ();
+
+	let ready =
+		upstream_ready && inventory_ready;
+
+	send_health (
+		format,
+		response,
+		if ready {
+			HyperStatusCode::OK
+		} else {
+			HyperStatusCode::SERVICE_UNAVAILABLE
+		},
+		& HealthReady {
+
+			status: if ready { "ready" } else { "not_ready" },
+
+			components: HealthReadyComponents {
+
+				upstream: HealthReadyUpstream {
+					status: if upstream_ready { "ok" } else { "failed" },
+					watch_alive: upstream_status.watch_alive,
+					watch_consecutive_errors:
+						upstream_status.watch_consecutive_errors,
+					watch_error_threshold,
+					watch_last_error: upstream_status.watch_last_error,
+					etcd_index: upstream_status.etcd_index,
+					seconds_since_update:
+						duration_seconds (upstream_status.last_update.elapsed ()),
+				},
+
+				inventory: HealthReadyInventory {
+					status: if inventory_ready { "ok" } else { "failed" },
+					project: context.inventory.project ().project_name ()
+						.to_owned (),
+					last_error: inventory_last_error,
+				},
+
+			},
+
+		})
+
+}
+
+fn send_health <Body: Serialize> (
+	format: OutputFormat,
+	response: & mut ServerResponse,
+	status: HyperStatusCode,
+	body: & Body,
+) -> Result <(), ApiError> {
+
+	* response.status_mut () =
+		status;
+
+	format.send (
+		response,
+		body)
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use hyper::header;
+
+use serde_json::Value as JsonValue;
+
+use crate::auth::*;
+use crate::inventory::*;
+use crate::routes::*;
+use crate::server::*;
+
+pub static ROUTE_METRICS: Route = Route {
+	name: "metrics",
+	summary: "Returns metrics in the Prometheus text format",
+	methods: & [ RouteMethod::Get ],
+	path: "/metrics",
+	query_params: & [],
+	scope: Some (Scope::ReadMetrics),
+	formats: & [],
+	response_schema: metrics_schema,
+	handler: & route_metrics,
+};
+
+fn metrics_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "string"
+	})
+
+}
+
+fn route_metrics (
+	context: & RouteContext,
+	_request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let upstream_status =
+		context.upstream.status ();
+
+	let snapshot =
+		context.upstream.snapshot ();
+
+	let mut class_counts: BTreeMap <String, u64> =
+		BTreeMap::new ();
+
+	let mut namespace_counts: BTreeMap <String, u64> =
+		BTreeMap::new ();
+
+	for (resource_name, node) in snapshot.data ().resources () {
+
+		let resource =
+			InventoryResource::from_json (
+				& context.inventory,
+				resource_name,
+				node.value ());
+
+		* class_counts.entry (
+			resource.identity_class ().clone ().unwrap_or_default (),
+		).or_insert (0) += 1;
+
+		* namespace_counts.entry (
+			resource.identity_namespace ().clone ().unwrap_or_default (),
+		).or_insert (0) += 1;
+
+	}
+
+	let mut body: Vec <u8> =
+		Vec::new ();
+
+	context.state.metrics.write_prometheus (
+		& mut body,
+	) ?;
+
+	context.state.rate_limiter.write_prometheus (
+		& mut body,
+	) ?;
+
+	prometheus_header (
+		& mut body,
+		"gridlinker_upstream_etcd_index",
+		"gauge",
+		"Most recent etcd index applied to the cache",
+	) ?;
+
+	writeln! (
+		body,
+		"gridlinker_upstream_etcd_index {}",
+		upstream_status.etcd_index,
+	) ?;
+
+	prometheus_header (
+		& mut body,
+		"gridlinker_upstream_watch_errors_total",
+		"counter",
+		"Errors encountered while watching etcd for changes",
+	) ?;
+
+	writeln! (
+		body,
+		"gridlinker_upstream_watch_errors_total {}",
+		upstream_status.watch_errors,
+	) ?;
+
+	prometheus_header (
+		& mut body,
+		"gridlinker_upstream_watch_reconnects_total",
+		"counter",
+		"Times the etcd watch was retried after an error",
+	) ?;
+
+	writeln! (
+		body,
+		"gridlinker_upstream_watch_reconnects_total {}",
+		upstream_status.watch_reconnects,
+	) ?;
+
+	prometheus_header (
+		& mut body,
+		"gridlinker_upstream_seconds_since_update",
+		"gauge",
+		"Time since an update was last received from etcd",
+	) ?;
+
+	writeln! (
+		body,
+		"gridlinker_upstream_seconds_since_update {}",
+		duration_seconds (upstream_status.last_update.elapsed ()),
+	) ?;
+
+	prometheus_header (
+		& mut body,
+		"gridlinker_cache_nodes",
+		"gauge",
+		"Keys held in the upstream cache",
+	) ?;
+
+	writeln! (
+		body,
+		"gridlinker_cache_nodes {}",
+		upstream_status.cache_size,
+	) ?;
+
+	prometheus_header (
+		& mut body,
+		"gridlinker_resources_by_class",
+		"gauge",
+		"Resources in the cache, by class",
+	) ?;
+
+	for (class_name, count) in class_counts.iter () {
+
+		writeln! (
+			body,
+			"gridlinker_resources_by_class{} {}",
+			prometheus_labels (& [ ("class", class_name) ]),
+			count,
+		) ?;
+
+	}
+
+	prometheus_header (
+		& mut body,
+		"gridlinker_resources_by_namespace",
+		"gauge",
+		"Resources in the cache, by namespace",
+	) ?;
+
+	for (namespace_name, count) in namespace_counts.iter () {
+
+		writeln! (
+			body,
+			"gridlinker_resources_by_namespace{} {}",
+			prometheus_labels (& [ ("namespace", namespace_name) ]),
+			count,
+		) ?;
+
+	}
+
+	prometheus_header (
+		& mut body,
+		"gridlinker_project_load_seconds",
+		"gauge",
+		"Time taken to load the project data",
+	) ?;
+
+	writeln! (
+		body,
+		"gridlinker_project_load_seconds {}",
+		duration_seconds (context.inventory.load_duration ()),
+	) ?;
+
+	response.header_set (
+		header::CONTENT_TYPE,
+		"text/plain; version=0.0.4");
+
+	response.send (
+		& body,
+	) ?;
+
+	Ok (())
+
+}
+
+// ex: noet ts=4 filetype=rust
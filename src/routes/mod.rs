@@ -1,11 +1,26 @@
+mod admin;
+mod batch;
+mod health;
+mod metrics;
+mod openapi;
 mod raw_key;
+mod raw_keys;
 mod raw_resource;
 mod raw_resources;
+mod resource_query;
+#[ allow (clippy::module_inception) ]
 mod routes;
 
+pub use self::admin::*;
+pub use self::batch::*;
+pub use self::health::*;
+pub use self::metrics::*;
+pub use self::openapi::*;
 pub use self::raw_key::*;
+pub use self::raw_keys::*;
 pub use self::raw_resource::*;
 pub use self::raw_resources::*;
+pub use self::resource_query::*;
 pub use self::routes::*;
 
 // ex: noet ts=4 filetype=rust
@@ -0,0 +1,469 @@
+use regex::Regex;
+
+use serde_json::Map as JsonMap;
+use serde_json::Value as JsonValue;
+
+use crate::routes::*;
+use crate::server::*;
+
+pub static ROUTE_OPENAPI: Route = Route {
+	name: "openapi",
+	summary: "Returns this OpenAPI description of the API",
+	methods: & [ RouteMethod::Get ],
+	path: "/openapi.json",
+	query_params: & [],
+	scope: None,
+	formats: DATA_FORMATS,
+	response_schema: openapi_schema,
+	handler: & route_openapi,
+};
+
+fn openapi_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object"
+	})
+
+}
+
+fn route_openapi (
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_OPENAPI.formats,
+		) ?;
+
+	format.send (
+		response,
+		& openapi_document ())
+
+}
+
+/// Builds the OpenAPI document from the registered routes, so that every
+/// route is described and the description can't drift from the router.
+pub fn openapi_document (
+) -> JsonValue {
+
+	let mut paths: JsonMap <String, JsonValue> =
+		JsonMap::new ();
+
+	for route in routes_all () {
+
+		for (path_parts, name_suffix) in openapi_path_variants (route) {
+
+			let mut operations: JsonMap <String, JsonValue> =
+				JsonMap::new ();
+
+			for method in route.methods {
+
+				let mut operation_id =
+					format! (
+						"{}{}",
+						route.name,
+						name_suffix);
+
+				if route.methods.len () > 1 {
+
+					operation_id.push ('_');
+
+					operation_id.push_str (
+						& method.hyper_method ().to_string ().to_lowercase ());
+
+				}
+
+				operations.insert (
+					method.hyper_method ().to_string ().to_lowercase (),
+					openapi_operation (
+						route,
+						& path_parts,
+						operation_id));
+
+			}
+
+			paths.insert (
+				openapi_path (& path_parts),
+				JsonValue::Object (operations));
+
+		}
+
+	}
+
+	json! ({
+		"openapi": "3.0.0",
+		"info": {
+			"title": "gridlinker-inventory",
+			"version": env! ("CARGO_PKG_VERSION")
+		},
+		"paths": paths,
+		"components": {
+			"securitySchemes": {
+				"bearerToken": {
+					"type": "http",
+					"scheme": "bearer",
+					"description": "A configured token. When the server uses \
+						TLS, a configured client certificate is accepted \
+						instead."
+				}
+			},
+			"schemas": {
+				"BatchError": {
+					"type": "object",
+					"properties": {
+						"code": { "type": "string" },
+						"message": { "type": "string" }
+					}
+				},
+				"KeyNode": {
+					"type": "object",
+					"properties": {
+						"key": { "type": "string" },
+						"dir": { "type": "boolean" },
+						"value": { "type": "string" },
+						"created_index": { "type": "integer" },
+						"modified_index": { "type": "integer" },
+						"nodes": {
+							"type": "array",
+							"items": { "$ref": "#/components/schemas/KeyNode" }
+						}
+					}
+				},
+				"Error": {
+					"type": "object",
+					"properties": {
+						"error": {
+							"type": "object",
+							"properties": {
+								"code": { "type": "string" },
+								"message": { "type": "string" },
+								"path": { "type": "string" },
+								"details": {
+									"type": "object",
+									"additionalProperties": { "type": "string" }
+								}
+							}
+						}
+					}
+				}
+			}
+		}
+	})
+
+}
+
+/// Path parameters are always required in OpenAPI, so a route whose
+/// parameters may be empty is also described without them.
+fn openapi_path_variants (
+	route: & Route,
+) -> Vec <(Vec <RoutePathPart>, String)> {
+
+	let path_parts =
+		route.path_parts ();
+
+	let optional_names: Vec <& str> =
+		path_parts.iter ().filter_map (|part|
+			match * part {
+				RoutePathPart::Param (parameter_name, parameter_pattern)
+					if pattern_matches (parameter_pattern, "") =>
+						Some (parameter_name),
+				_ => None,
+			}
+		).collect ();
+
+	if optional_names.is_empty () {
+		return vec! [ (path_parts, String::new ()) ];
+	}
+
+	let required_parts =
+		path_parts.iter ().filter (|part|
+			match * part {
+				RoutePathPart::Param (parameter_name, _) =>
+					! optional_names.contains (parameter_name),
+				RoutePathPart::Literal (_) => true,
+			}
+		).cloned ().collect ();
+
+	vec! [
+		(path_parts, String::new ()),
+		(required_parts, format! ("_without_{}", optional_names.join ("_"))),
+	]
+
+}
+
+fn pattern_matches (
+	parameter_pattern: & str,
+	value: & str,
+) -> bool {
+
+	Regex::new (
+		& format! ("^(?:{})$", parameter_pattern),
+	).map (|regex|
+		regex.is_match (value)
+	).unwrap_or (false)
+
+}
+
+fn openapi_path (
+	path_parts: & [RoutePathPart],
+) -> String {
+
+	path_parts.iter ().map (|part|
+		match * part {
+			RoutePathPart::Literal (literal) =>
+				literal.to_owned (),
+			RoutePathPart::Param (parameter_name, _) =>
+				format! ("{{{}}}", parameter_name),
+		}
+	).collect ()
+
+}
+
+fn openapi_operation (
+	route: & Route,
+	path_parts: & [RoutePathPart],
+	operation_id: String,
+) -> JsonValue {
+
+	let mut parameters: Vec <JsonValue> =
+		Vec::new ();
+
+	for part in path_parts {
+
+		if let RoutePathPart::Param (parameter_name, parameter_pattern) = * part {
+
+			let mut parameter =
+				json! ({
+					"name": parameter_name,
+					"in": "path",
+					"required": true,
+					"schema": {
+						"type": "string",
+						"pattern": format! ("^(?:{})$", parameter_pattern),
+						"minLength": 1
+					}
+				});
+
+			// the router decodes the path before matching it, so a value
+			// spanning several segments works whether or not "/" is encoded
+
+			if pattern_matches (parameter_pattern, "/a/b") {
+
+				parameter ["description"] =
+					json! ("May contain \"/\", which may be sent as is or \
+						encoded as %2F");
+
+				parameter ["allowReserved"] =
+					json! (true);
+
+			}
+
+			parameters.push (
+				parameter);
+
+		}
+
+	}
+
+	for query_param in route.query_params {
+
+		parameters.push (json! ({
+			"name": query_param.name,
+			"in": "query",
+			"required": false,
+			"description": query_param.description,
+			"schema": { "type": "string" }
+		}));
+
+	}
+
+	if ! route.formats.is_empty () {
+
+		let format_names: Vec <& str> =
+			route.formats.iter ().map (|format|
+				format.name ()
+			).collect ();
+
+		parameters.push (json! ({
+			"name": "format",
+			"in": "query",
+			"required": false,
+			"description": "The output format, instead of the Accept header",
+			"schema": { "type": "string", "enum": format_names }
+		}));
+
+	}
+
+	let mut content: JsonMap <String, JsonValue> =
+		JsonMap::new ();
+
+	if route.formats.is_empty () {
+
+		content.insert (
+			"text/plain".to_owned (),
+			json! ({ "schema": (route.response_schema) () }));
+
+	}
+
+	for format in route.formats {
+
+		let schema =
+			if * format == OutputFormat::Ini {
+				json! ({ "type": "string" })
+			} else {
+				(route.response_schema) ()
+			};
+
+		content.insert (
+			format.content_type ().to_owned (),
+			json! ({ "schema": schema }));
+
+	}
+
+	let security =
+		match route.scope {
+			Some (_) => json! ([ { "bearerToken": [] } ]),
+			None => json! ([]),
+		};
+
+	json! ({
+		"operationId": operation_id,
+		"summary": route.summary,
+		"parameters": parameters,
+		"security": security,
+		"x-scope": route.scope.map (|scope| scope.name ()),
+		"responses": {
+			"200": {
+				"description": route.summary,
+				"content": content
+			},
+			"default": {
+				"description": "An error",
+				"content": {
+					"application/json": {
+						"schema": { "$ref": "#/components/schemas/Error" }
+					}
+				}
+			}
+		}
+	})
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn path_parameters (
+		document: & JsonValue,
+		path: & str,
+		method: & str,
+	) -> Vec <JsonValue> {
+
+		document ["paths"] [path] [method] ["parameters"].as_array ().unwrap_or_else (||
+			panic! (
+				"Missing {} {}",
+				method,
+				path)
+		).iter ().filter (|parameter|
+			parameter ["in"] == "path"
+		).cloned ().collect ()
+
+	}
+
+	#[ test ]
+	fn multi_segment_parameters_are_described () {
+
+		let document =
+			openapi_document ();
+
+		let key_parameters =
+			path_parameters (& document, "/raw/key{key}", "get");
+
+		assert_eq! (key_parameters.len (), 1);
+		assert_eq! (key_parameters [0] ["name"], "key");
+		assert_eq! (key_parameters [0] ["required"], true);
+		assert_eq! (key_parameters [0] ["allowReserved"], true);
+		assert_eq! (key_parameters [0] ["schema"] ["pattern"], "^(?:/.+)$");
+
+		let name_parameters =
+			path_parameters (& document, "/raw/resource/{name}", "get");
+
+		assert_eq! (name_parameters [0] ["allowReserved"], true);
+		assert_eq! (name_parameters [0] ["schema"] ["pattern"], "^(?:.+)$");
+
+		assert_eq! (
+			document ["paths"] ["/raw/resource/{name}"] ["get"] ["operationId"],
+			"raw_resource");
+
+	}
+
+	#[ test ]
+	fn optional_parameters_get_a_path_without_them () {
+
+		let document =
+			openapi_document ();
+
+		let prefix_parameters =
+			path_parameters (& document, "/raw/keys{prefix}", "get");
+
+		assert_eq! (prefix_parameters.len (), 1);
+		assert_eq! (prefix_parameters [0] ["required"], true);
+		assert_eq! (prefix_parameters [0] ["schema"] ["minLength"], 1);
+		assert_eq! (prefix_parameters [0] ["schema"] ["pattern"], "^(?:(?:/.*)?)$");
+
+		assert! (
+			path_parameters (& document, "/raw/keys", "get").is_empty ());
+
+		assert_eq! (
+			document ["paths"] ["/raw/keys"] ["get"] ["operationId"],
+			"raw_keys_without_prefix");
+
+		assert_eq! (
+			document ["paths"] ["/raw/keys{prefix}"] ["get"] ["operationId"],
+			"raw_keys");
+
+	}
+
+	#[ test ]
+	fn routes_are_described_with_schemas_and_security () {
+
+		let document =
+			openapi_document ();
+
+		let health =
+			& document ["paths"] ["/health/live"] ["get"];
+
+		assert_eq! (health ["security"], json! ([]));
+		assert! (health ["x-scope"].is_null ());
+
+		let batch =
+			& document ["paths"] ["/batch"] ["post"];
+
+		assert_eq! (batch ["security"], json! ([ { "bearerToken": [] } ]));
+		assert! (batch ["x-scope"].is_string ());
+
+		assert_eq! (
+			batch ["responses"] ["default"] ["content"] ["application/json"]
+				["schema"] ["$ref"],
+			"#/components/schemas/Error");
+
+		assert! (
+			batch ["responses"] ["200"] ["content"] ["application/json"]
+				["schema"].is_object ());
+
+		assert! (
+			document ["components"] ["schemas"] ["KeyNode"] ["properties"]
+				["nodes"].is_object ());
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
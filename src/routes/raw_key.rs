@@ -1,90 +1,109 @@
-use std::io::Write;
-use std::sync::Arc;
-use std::sync::Mutex;
+use serde_json::Value as JsonValue;
+
+use crate::auth::*;
+use crate::inventory::*;
+use crate::routes::*;
+use crate::server::*;
+
+pub static ROUTE_RAW_KEY: Route = Route {
+	name: "raw_key",
+	summary: "Returns the value of a single upstream key",
+	methods: & [ RouteMethod::Get ],
+	path: "/raw/key{key:/.+}",
+	query_params: & [],
+	scope: Some (Scope::ReadRawKeys),
+	formats: DATA_FORMATS,
+	response_schema: raw_key_schema,
+	handler: & route_raw_key,
+};
+
+fn raw_key_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"value": { "type": "string" }
+		}
+	})
 
-use hyper::header;
-use hyper::server::Request as HyperRequest;
-use hyper::server::Response as HyperResponse;
-
-use regex::Captures as RegexCaptures;
-
-use serde_json;
+}
 
-use routes::*;
-use server::*;
-use upstream::*;
+#[ derive (Serialize) ]
+struct RawKeyBody <'a> {
 
-pub const ROUTE_RAW_KEY_REGEX: & 'static str =
-	"^/raw/key(/.+)$";
+	#[ serde (rename = "value") ]
+	value: & 'a str,
 
-pub const ROUTE_RAW_KEY_HANDLER: & 'static RouteHandlerFn =
-	& route_raw_key;
+}
 
 fn route_raw_key (
-	state: Arc <Mutex <ServerState>>,
-	upstream: Arc <Upstream>,
-	captures: RegexCaptures,
-	request: HyperRequest,
-	mut response: HyperResponse,
-) {
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_RAW_KEY.formats,
+		) ?;
 
 	let key =
-		captures.get (1).unwrap ().as_str ();
+		context.param ("key");
 
-	let node: Option <Arc <NodeData>> = {
+	let snapshot =
+		context.upstream.snapshot ();
 
-		let data =
-			upstream.data ();
+	let data =
+		snapshot.data ();
 
-		let data =
-			data.lock ().unwrap ();
+	// identities restricted to some namespaces or classes may only read keys
+	// which belong to a resource they are allowed to see
 
+	let permitted =
+		! context.identity ().permissions ().is_restricted ()
+		|| InventoryResource::name_for_key (
+			key,
+		).and_then (|resource_name|
+			data.resource (
+				resource_name,
+			).map (|resource_node|
+				context.identity ().permissions ().allows_resource (
+					& InventoryResource::from_json (
+						& context.inventory,
+						resource_name,
+						resource_node.value ()))
+			)
+		).unwrap_or (false);
+
+	// directories are listed by the keys route, not returned here
+
+	let node =
 		data.get (
 			key,
-		).map (
-			|value| value.clone (),
-		)
-
-	};
-
-	if node.is_none () {
-
-		return send_not_found (
-			response);
-
-	}
-
-	let node = node.unwrap ();
-
-	{
-
-		let mut headers =
-			response.headers_mut ();
-
-		headers.set (
-			header::ContentType::json ());
-
-	}
-
-	let mut response =
-		response.start ().unwrap ();
-
-	let mut first = true;
-
-	write! (
-		response,
-		"{{\n  \"value\":",
-	).unwrap ();
-
-	serde_json::to_writer (
-		& mut response,
-		& node.value (),
-	).unwrap ();
-
-	write! (
+		).and_then (|node|
+			if node.dir () { None } else { Some (node) }
+		);
+
+	// keys the identity may not see are reported as missing, so that their
+	// existence isn't revealed
+
+	let node =
+		node.filter (|_|
+			permitted
+		).ok_or_else (||
+			ApiError::KeyNotFound (
+				key.to_owned ())
+		) ?;
+
+	format.send (
 		response,
-		"\n}}\n",
-	).unwrap ();
+		& RawKeyBody {
+			value: node.value (),
+		},
+	)
 
 }
 
@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value as JsonValue;
+
+use crate::auth::*;
+use crate::inventory::*;
+use crate::routes::*;
+use crate::server::*;
+use crate::upstream::*;
+
+pub static ROUTE_RAW_KEYS: Route = Route {
+	name: "raw_keys",
+	summary: "Lists the upstream keys under a prefix, as a list or a tree",
+	methods: & [ RouteMethod::Get ],
+	path: "/raw/keys{prefix:(?:/.*)?}",
+	query_params: & [
+		RouteQueryParam {
+			name: "recursive",
+			description: "Include every key below the prefix, rather than \
+				only its immediate children, if \"true\"",
+		},
+		RouteQueryParam {
+			name: "view",
+			description: "Either \"list\", the default, or \"tree\"",
+		},
+	],
+	scope: Some (Scope::ReadRawKeys),
+	formats: DATA_FORMATS,
+	response_schema: raw_keys_schema,
+	handler: & route_raw_keys,
+};
+
+fn raw_keys_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"etcd_index": { "type": "integer" },
+			"prefix": { "type": "string" },
+			"nodes": {
+				"type": "array",
+				"items": { "$ref": "#/components/schemas/KeyNode" }
+			},
+			"node": { "$ref": "#/components/schemas/KeyNode" }
+		}
+	})
+
+}
+
+#[ derive (Serialize) ]
+struct RawKeysList <'a> {
+
+	#[ serde (rename = "etcd_index") ]
+	etcd_index: u64,
+
+	#[ serde (rename = "prefix") ]
+	prefix: & 'a str,
+
+	#[ serde (rename = "nodes") ]
+	nodes: Vec <RawKeysNode <'a>>,
+
+}
+
+#[ derive (Serialize) ]
+struct RawKeysTree <'a> {
+
+	#[ serde (rename = "etcd_index") ]
+	etcd_index: u64,
+
+	#[ serde (rename = "node") ]
+	node: RawKeysNode <'a>,
+
+}
+
+/// A key in the output. Directories which are implied by the keys below them
+/// but aren't in the cache themselves, such as the root, have no indexes.
+#[ derive (Serialize) ]
+struct RawKeysNode <'a> {
+
+	#[ serde (rename = "key") ]
+	key: String,
+
+	#[ serde (rename = "dir") ]
+	dir: bool,
+
+	#[ serde (rename = "value", skip_serializing_if = "Option::is_none") ]
+	value: Option <& 'a str>,
+
+	#[ serde (rename = "created_index") ]
+	created_index: Option <u64>,
+
+	#[ serde (rename = "modified_index") ]
+	modified_index: Option <u64>,
+
+	#[ serde (rename = "nodes", skip_serializing_if = "Option::is_none") ]
+	nodes: Option <Vec <RawKeysNode <'a>>>,
+
+}
+
+impl <'a> RawKeysNode <'a> {
+
+	fn new (
+		key: String,
+		node: Option <& 'a NodeData>,
+		nodes: Option <Vec <RawKeysNode <'a>>>,
+	) -> RawKeysNode <'a> {
+
+		RawKeysNode {
+			key,
+			dir: node.map (|node| node.dir ()).unwrap_or (true),
+			value: node.and_then (|node|
+				if node.dir () { None } else { Some (node.value ()) }
+			),
+			created_index: node.map (|node| node.created_index ()),
+			modified_index: node.map (|node| node.modified_index ()),
+			nodes,
+		}
+
+	}
+
+}
+
+#[ derive (Default) ]
+struct KeyTree <'a> {
+	node: Option <& 'a NodeData>,
+	children: BTreeMap <& 'a str, KeyTree <'a>>,
+}
+
+impl <'a> KeyTree <'a> {
+
+	fn insert (
+		& mut self,
+		path: & 'a str,
+		node: & 'a NodeData,
+	) {
+
+		let mut tree =
+			self;
+
+		for part in path.split ('/').filter (|part| ! part.is_empty ()) {
+
+			tree =
+				tree.children.entry (
+					part,
+				).or_default ();
+
+		}
+
+		tree.node =
+			Some (node);
+
+	}
+
+	fn output (
+		self,
+		key: String,
+	) -> RawKeysNode <'a> {
+
+		let is_dir =
+			self.node.map (|node| node.dir ()).unwrap_or (true);
+
+		let nodes =
+			if is_dir {
+				Some (
+					self.children.into_iter ().map (|(name, child)|
+						child.output (
+							format! ("{}/{}", key, name))
+					).collect ())
+			} else {
+				None
+			};
+
+		RawKeysNode::new (
+			key,
+			self.node,
+			nodes)
+
+	}
+
+}
+
+fn route_raw_keys (
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_RAW_KEYS.formats,
+		) ?;
+
+	let prefix =
+		context.param ("prefix").trim_end_matches ('/');
+
+	let recursive =
+		match context.query_param ("recursive") {
+			None | Some ("false") => false,
+			Some ("true") => true,
+			Some (value) =>
+				return Err (
+					ApiError::BadRequest (
+						format! (
+							"Invalid recursive: {}",
+							value))),
+		};
+
+	let tree_view =
+		match context.query_param ("view") {
+			None | Some ("list") => false,
+			Some ("tree") => true,
+			Some (value) =>
+				return Err (
+					ApiError::BadRequest (
+						format! (
+							"Invalid view: {}",
+							value))),
+		};
+
+	let permissions =
+		context.identity ().permissions ();
+
+	let snapshot =
+		context.upstream.snapshot ();
+
+	let data =
+		snapshot.data ();
+
+	let prefix_node =
+		data.get (prefix);
+
+	if ! prefix.is_empty () && prefix_node.is_none () {
+
+		return Err (
+			ApiError::KeyNotFound (
+				prefix.to_owned ()));
+
+	}
+
+	let nodes: Vec <& NodeData> =
+		if recursive {
+			data.descendants (prefix).map (|node| & ** node).collect ()
+		} else {
+			data.children (prefix).map (|node| & ** node).collect ()
+		};
+
+	// restricted identities only see values belonging to resources they are
+	// allowed to see, and no directories except those implied by them; each
+	// resource is checked once, however many of its keys are listed
+
+	let (nodes, prefix_node) =
+		if permissions.is_restricted () {
+
+			let resource_permitted = |resource_name: & str| -> bool {
+				data.resource (
+					resource_name,
+				).map (|resource_node|
+					permissions.allows_resource (
+						& InventoryResource::from_json (
+							& context.inventory,
+							resource_name,
+							resource_node.value ()))
+				).unwrap_or (false)
+			};
+
+			let mut permitted_resources: BTreeMap <& str, bool> =
+				BTreeMap::new ();
+
+			let nodes: Vec <& NodeData> =
+				nodes.into_iter ().filter (|node|
+					! node.dir ()
+					&& InventoryResource::name_for_key (
+						node.key (),
+					).map (|resource_name|
+						* permitted_resources.entry (
+							resource_name,
+						).or_insert_with (||
+							resource_permitted (resource_name))
+					).unwrap_or (false)
+				).collect ();
+
+			// a prefix with nothing visible under it is reported as missing,
+			// so that forbidden keys can't be told apart from absent ones
+
+			let prefix_visible =
+				prefix_node.map (|prefix_node|
+					! prefix_node.dir ()
+					&& InventoryResource::name_for_key (
+						prefix,
+					).map (& resource_permitted).unwrap_or (false)
+				).unwrap_or (false);
+
+			if ! prefix.is_empty () && nodes.is_empty () && ! prefix_visible {
+
+				return Err (
+					ApiError::KeyNotFound (
+						prefix.to_owned ()));
+
+			}
+
+			(nodes, None)
+
+		} else {
+
+			(nodes, prefix_node.map (|prefix_node| & ** prefix_node))
+
+		};
+
+	if tree_view {
+
+		let mut tree =
+			KeyTree {
+				node: prefix_node,
+				children: BTreeMap::new (),
+			};
+
+		for node in nodes {
+
+			tree.insert (
+				& node.key () [prefix.len () .. ],
+				node);
+
+		}
+
+		format.send (
+			response,
+			& RawKeysTree {
+				etcd_index: snapshot.etcd_index (),
+				node: tree.output (
+					prefix.to_owned ()),
+			})
+
+	} else {
+
+		format.send (
+			response,
+			& RawKeysList {
+				etcd_index: snapshot.etcd_index (),
+				prefix,
+				nodes: nodes.into_iter ().map (|node|
+					RawKeysNode::new (
+						node.key ().to_owned (),
+						Some (node),
+						None)
+				).collect (),
+			})
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
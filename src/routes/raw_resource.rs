@@ -1,92 +1,84 @@
-use std::io::Write;
-use std::sync::Arc;
-use std::sync::Mutex;
+use serde_json::Value as JsonValue;
+
+use crate::auth::*;
+use crate::inventory::*;
+use crate::routes::*;
+use crate::server::*;
+
+pub static ROUTE_RAW_RESOURCE: Route = Route {
+	name: "raw_resource",
+	summary: "Returns the data for a single resource, as a JSON string",
+	methods: & [ RouteMethod::Get ],
+	path: "/raw/resource/{name:.+}",
+	query_params: & [],
+	scope: Some (Scope::ReadResources),
+	formats: DATA_FORMATS,
+	response_schema: raw_resource_schema,
+	handler: & route_raw_resource,
+};
+
+fn raw_resource_schema (
+) -> JsonValue {
+
+	json! ({
+		"type": "object",
+		"properties": {
+			"resource": { "type": "string" }
+		}
+	})
 
-use hyper::header;
-use hyper::server::Request as HyperRequest;
-use hyper::server::Response as HyperResponse;
-
-use regex::Captures as RegexCaptures;
-
-use serde_json;
+}
 
-use routes::*;
-use server::*;
-use upstream::*;
+#[ derive (Serialize) ]
+struct RawResourceBody <'a> {
 
-pub const ROUTE_RAW_RESOURCE_REGEX: & 'static str =
-	"^/raw/resource/(.+)$";
+	#[ serde (rename = "resource") ]
+	resource: & 'a str,
 
-pub const ROUTE_RAW_RESOURCE_HANDLER: & 'static RouteHandlerFn =
-	& route_raw_resource;
+}
 
 fn route_raw_resource (
-	state: Arc <Mutex <ServerState>>,
-	upstream: Arc <Upstream>,
-	captures: RegexCaptures,
-	request: HyperRequest,
-	mut response: HyperResponse,
-) {
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
+
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_RAW_RESOURCE.formats,
+		) ?;
 
 	let resource_name =
-		captures.get (1).unwrap ().as_str ();
-
-	let resource: Option <Arc <NodeData>> = {
-
-		let data =
-			upstream.data ();
-
-		let data =
-			data.lock ().unwrap ();
-
-		data.get (
-			& format! (
-				"/resource/{}/data",
-				resource_name),
-		).map (
-			|value| value.clone (),
-		)
-
-	};
-
-	if resource.is_none () {
-
-		return send_not_found (
-			response);
-
-	}
-
-	let resource = resource.unwrap ();
-
-	{
-
-		let mut headers =
-			response.headers_mut ();
-
-		headers.set (
-			header::ContentType::json ());
-
-	}
-
-	let mut response =
-		response.start ().unwrap ();
-
-	let mut first = true;
-
-	write! (
-		response,
-		"{{\n  \"resource\":",
-	).unwrap ();
-
-	serde_json::to_writer (
-		& mut response,
-		& resource.value (),
-	).unwrap ();
-
-	write! (
+		context.param ("name");
+
+	let snapshot =
+		context.upstream.snapshot ();
+
+	// resources the identity may not see are reported as missing, so that
+	// their existence isn't revealed
+
+	let resource =
+		snapshot.data ().resource (
+			resource_name,
+		).filter (|resource|
+			context.identity ().permissions ().allows_resource (
+				& InventoryResource::from_json (
+					& context.inventory,
+					resource_name,
+					resource.value ()))
+		).ok_or_else (||
+			ApiError::ResourceNotFound (
+				resource_name.to_owned ())
+		) ?;
+
+	format.send (
 		response,
-		"\n}}\n",
-	).unwrap ();
+		& RawResourceBody {
+			resource: resource.value (),
+		},
+	)
 
 }
 
@@ -1,103 +1,353 @@
+use std::collections::BTreeMap;
 use std::io::Write;
-use std::sync::Arc;
-use std::sync::Mutex;
 
-use hyper::header;
-use hyper::server::Request as HyperRequest;
-use hyper::server::Response as HyperResponse;
+use serde_json::Value as JsonValue;
 
-use regex::Captures as RegexCaptures;
+use crate::auth::*;
+use crate::routes::*;
+use crate::server::*;
 
-use serde_json;
+pub static ROUTE_RAW_RESOURCES: Route = Route {
+	name: "raw_resources",
+	summary: "Lists resources, with filtering, sorting and pagination",
+	methods: & [ RouteMethod::Get ],
+	path: "/raw/resources",
+	query_params: & [
+		RouteQueryParam {
+			name: "class",
+			description: "Only resources of this class, may be repeated",
+		},
+		RouteQueryParam {
+			name: "namespace",
+			description: "Only resources in this namespace, may be repeated",
+		},
+		RouteQueryParam {
+			name: "group",
+			description: "Only resources in this group, may be repeated",
+		},
+		RouteQueryParam {
+			name: "name",
+			description: "Only resources with names matching this glob",
+		},
+		RouteQueryParam {
+			name: "field.{path}",
+			description: "Only resources where the field at this dotted path \
+				has this value",
+		},
+		RouteQueryParam {
+			name: "sort",
+			description: "Sort by \"name\" or \"field.{path}\", prefixed with \
+				\"-\" to reverse",
+		},
+		RouteQueryParam {
+			name: "limit",
+			description: "The maximum number of resources to return",
+		},
+		RouteQueryParam {
+			name: "cursor",
+			description: "The next_cursor from the previous page",
+		},
+	],
+	scope: Some (Scope::ReadResources),
+	formats: INVENTORY_FORMATS,
+	response_schema: raw_resources_schema,
+	handler: & route_raw_resources,
+};
 
-use routes::*;
-use server::*;
-use upstream::*;
+fn raw_resources_schema (
+) -> JsonValue {
 
-pub const ROUTE_RAW_RESOURCES_REGEX: & 'static str =
-	"^/raw/resources$";
+	json! ({
+		"type": "object",
+		"properties": {
+			"total": { "type": "integer" },
+			"etcd_index": { "type": "integer" },
+			"next_cursor": { "type": "string", "nullable": true },
+			"resources": {
+				"type": "array",
+				"items": { "type": "string" }
+			}
+		}
+	})
+
+}
+
+#[ derive (Serialize) ]
+struct RawResourcesBody <'a> {
+
+	#[ serde (rename = "total") ]
+	total: usize,
 
-pub const ROUTE_RAW_RESOURCES_HANDLER: & 'static RouteHandlerFn =
-	& route_raw_resources;
+	#[ serde (rename = "etcd_index") ]
+	etcd_index: u64,
+
+	#[ serde (rename = "next_cursor") ]
+	next_cursor: & 'a Option <String>,
+
+	#[ serde (rename = "resources") ]
+	resources: Vec <& 'a str>,
+
+}
 
 fn route_raw_resources (
-	state: Arc <Mutex <ServerState>>,
-	upstream: Arc <Upstream>,
-	captures: RegexCaptures,
-	request: HyperRequest,
-	mut response: HyperResponse,
-) {
+	context: & RouteContext,
+	request: ServerRequest,
+	response: & mut ServerResponse,
+) -> Result <(), ApiError> {
 
-	let resources_temp: Vec <Arc <NodeData>> = {
+	let format =
+		OutputFormat::negotiate (
+			context,
+			& request,
+			ROUTE_RAW_RESOURCES.formats,
+		) ?;
 
-		let data =
-			upstream.data ();
+	let query =
+		ResourceQuery::parse (
+			context,
+		) ?;
 
-		let data =
-			data.lock ().unwrap ();
+	let permissions =
+		context.identity ().permissions ();
 
-		data.iter ().filter (
-			|& (ref key, ref _node)|
-			key.starts_with ("/resource/")
-			&& key.ends_with ("/data")
-		).map (
-			|(ref _key, ref node)|
-			(* node).clone ()
-		).collect ()
+	let snapshot =
+		context.upstream.snapshot ();
 
-	};
+	let etcd_index =
+		snapshot.etcd_index ();
 
-	{
+	let nodes =
+		query.candidates (
+			& context.inventory,
+			snapshot.data ());
 
-		let mut headers =
-			response.headers_mut ();
+	let items: Vec <ResourceQueryItem> =
+		nodes.into_iter ().map (
+			|(resource_name, node)|
+			ResourceQueryItem::new (
+				& context.inventory,
+				& resource_name,
+				node)
+		).filter (
+			|item|
+			! permissions.is_restricted ()
+			|| permissions.allows_resource (
+				item.resource ())
+		).collect ();
 
-		headers.set (
-			header::ContentType::json ());
+	let page =
+		query.apply (
+			items);
+
+	if format == OutputFormat::Ini {
+
+		return send_ini (
+			format,
+			response,
+			& page,
+			etcd_index);
 
 	}
 
-	let mut response =
-		response.start ().unwrap ();
+	format.send (
+		response,
+		& RawResourcesBody {
+			total: page.total,
+			etcd_index,
+			next_cursor: & page.next_cursor,
+			resources: page.items.iter ().map (|item|
+				item.node ().value ()
+			).collect (),
+		},
+	)
+
+}
+
+/// Writes the resources as an Ansible INI inventory. Each resource is a host,
+/// with its data flattened into variables named "<section>_<name>", and is a
+/// member of a group for its class and for each of the class's groups.
+fn send_ini (
+	format: OutputFormat,
+	response: & mut ServerResponse,
+	page: & ResourcePage,
+	etcd_index: u64,
+) -> Result <(), ApiError> {
+
+	let mut groups: BTreeMap <String, Vec <& str>> =
+		BTreeMap::new ();
+
+	for item in page.items.iter () {
 
-	let mut first = true;
+		let resource =
+			item.resource ();
+
+		for group_name in resource.identity_class ().iter ().chain (
+			resource.identity_groups ().iter ()) {
+
+			groups.entry (
+				ini_identifier (group_name),
+			).or_default (
+			).push (
+				resource.identity_name ());
+
+		}
+
+	}
+
+	let mut response =
+		format.start (
+			response,
+		) ?;
 
 	write! (
 		response,
-		"{{\n  \"resources\": [",
-	).unwrap ();
+		"# total: {}\n# etcd index: {}\n",
+		page.total,
+		etcd_index,
+	) ?;
 
-	for resource in resources_temp {
+	if let Some (ref next_cursor) = page.next_cursor {
 
-		if first {
+		writeln! (
+			response,
+			"# next cursor: {}",
+			next_cursor,
+		) ?;
+
+	}
+
+	writeln! (
+		response,
+	) ?;
+
+	for item in page.items.iter () {
+
+		write! (
+			response,
+			"{}",
+			item.resource ().identity_name (),
+		) ?;
+
+		for (variable_name, variable_value) in ini_variables (item.value ()) {
 
 			write! (
 				response,
-				"\n    ",
-			).unwrap ();
+				" {}={}",
+				variable_name,
+				variable_value,
+			) ?;
 
-			first = false;
+		}
 
-		} else {
+		writeln! (
+			response,
+		) ?;
 
-			write! (
+	}
+
+	for (group_name, host_names) in groups {
+
+		write! (
+			response,
+			"\n[{}]\n",
+			group_name,
+		) ?;
+
+		for host_name in host_names {
+
+			writeln! (
 				response,
-				",\n    ",
-			).unwrap ();
+				"{}",
+				host_name,
+			) ?;
 
 		}
 
-		serde_json::to_writer (
-			& mut response,
-			& resource.value (),
-		).unwrap ();
+	}
+
+	response.end () ?;
+
+	Ok (())
+
+}
+
+fn ini_identifier (
+	name: & str,
+) -> String {
+
+	name.chars ().map (|character|
+		if character.is_alphanumeric () { character } else { '_' }
+	).collect ()
+
+}
+
+fn ini_variables (
+	value: & JsonValue,
+) -> Vec <(String, String)> {
+
+	let mut variables: Vec <(String, String)> =
+		Vec::new ();
+
+	let sections =
+		match * value {
+			JsonValue::Object (ref sections) => sections,
+			_ => return variables,
+		};
+
+	for (section_name, section_value) in sections.iter () {
+
+		match * section_value {
+
+			JsonValue::Object (ref section) =>
+				for (name, value) in section.iter () {
+
+					variables.push ((
+						ini_identifier (
+							& format! (
+								"{}_{}",
+								section_name,
+								name)),
+						ini_value (value),
+					));
+
+				},
+
+			ref value =>
+				variables.push ((
+					ini_identifier (section_name),
+					ini_value (value),
+				)),
+
+		}
 
 	}
 
-	write! (
-		response,
-		"\n  ]\n}}\n",
-	).unwrap ();
+	variables
+
+}
+
+/// Formats a variable value. Simple strings are written bare and anything
+/// else as JSON, which Ansible reads as a quoted string or literal.
+fn ini_value (
+	value: & JsonValue,
+) -> String {
+
+	match * value {
+
+		JsonValue::String (ref value)
+			if ! value.is_empty ()
+				&& value.chars ().all (|character|
+					! character.is_whitespace ()
+					&& character != '"'
+					&& character != '\''
+					&& character != '#'
+					&& character != '=') =>
+			value.clone (),
+
+		ref value =>
+			value.to_string (),
+
+	}
 
 }
 
@@ -0,0 +1,724 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use serde_json::Value as JsonValue;
+
+use crate::hex::*;
+use crate::inventory::*;
+use crate::routes::*;
+use crate::server::*;
+use crate::upstream::*;
+
+/// A resource as seen by a query, with its data parsed once up front so that
+/// filtering and sorting don't parse it repeatedly.
+pub struct ResourceQueryItem {
+	resource: InventoryResource,
+	node: Arc <NodeData>,
+	value: JsonValue,
+}
+
+impl ResourceQueryItem {
+
+	pub fn new (
+		inventory: & Inventory,
+		resource_name: & str,
+		node: Arc <NodeData>,
+	) -> ResourceQueryItem {
+
+		let value: JsonValue =
+			serde_json::from_str (
+				node.value (),
+			).unwrap_or (JsonValue::Null);
+
+		ResourceQueryItem {
+			resource: InventoryResource::from_value (
+				inventory,
+				resource_name,
+				Some (& value)),
+			node,
+			value,
+		}
+
+	}
+
+	fn field (
+		& self,
+		pointer: & str,
+	) -> & JsonValue {
+
+		self.value.pointer (
+			pointer,
+		).unwrap_or (& JsonValue::Null)
+
+	}
+
+	property_accessors! {
+		ref resource: & InventoryResource;
+		ref node: & Arc <NodeData>;
+		ref value: & JsonValue;
+	}
+
+}
+
+enum ResourceSortKey {
+	Name,
+	Field (String),
+}
+
+/// A page of query results. The total counts every resource which matched
+/// the filters, not only those on this page.
+pub struct ResourcePage {
+	pub total: usize,
+	pub items: Vec <ResourceQueryItem>,
+	pub next_cursor: Option <String>,
+}
+
+/// Filtering, sorting and pagination for resource collections, read from the
+/// query string:
+///
+/// * class, namespace, group — may be repeated, any value matches
+/// * name — a glob, in which "*" and "?" are wildcards
+/// * field.<path>=<value> — equality on the data, with a dotted path
+/// * sort — "name" or "field.<path>", prefixed with "-" to reverse
+/// * limit, cursor — the cursor is taken from a previous page
+///
+/// A cursor records the last result's position, rather than an offset.
+pub struct ResourceQuery {
+	classes: Vec <String>,
+	namespaces: Vec <String>,
+	groups: Vec <String>,
+	name_pattern: Option <Regex>,
+	name_prefix: String,
+	fields: Vec <(String, String)>,
+	sort_name: String,
+	sort_key: ResourceSortKey,
+	sort_reverse: bool,
+	limit: Option <usize>,
+	cursor: Option <(JsonValue, String)>,
+}
+
+impl ResourceQuery {
+
+	pub fn parse (
+		context: & RouteContext,
+	) -> Result <ResourceQuery, ApiError> {
+
+		let strings = |name: & str| -> Vec <String> {
+			context.query_params (
+				name,
+			).into_iter ().map (|value|
+				value.to_owned ()
+			).collect ()
+		};
+
+		let name_pattern =
+			match context.query_param ("name") {
+				Some (name_glob) =>
+					Some (Self::glob_compile (name_glob) ?),
+				None =>
+					None,
+			};
+
+		// the part of the glob before any wildcard narrows the range of names
+		// to read
+
+		let name_prefix =
+			context.query_param (
+				"name",
+			).map (|name_glob|
+				name_glob.split (
+					[ '*', '?' ],
+				).next ().unwrap_or ("").to_owned ()
+			).unwrap_or_default ();
+
+		let fields =
+			context.query.iter ().filter (|& (key, _)|
+				key.starts_with ("field.")
+			).map (|(key, value)|
+				Ok ((
+					Self::field_pointer (
+						key.strip_prefix ("field.").unwrap ()) ?,
+					value.to_owned (),
+				))
+			).collect::<Result <Vec <(String, String)>, ApiError>> () ?;
+
+		let sort_name =
+			context.query_param (
+				"sort",
+			).unwrap_or ("name").to_owned ();
+
+		let (sort_reverse, sort_field) =
+			match sort_name.strip_prefix ('-') {
+				Some (sort_field) => (true, sort_field),
+				None => (false, & sort_name [ .. ]),
+			};
+
+		let sort_key =
+			if sort_field == "name" {
+				ResourceSortKey::Name
+			} else if let Some (field_name) = sort_field.strip_prefix ("field.") {
+				ResourceSortKey::Field (
+					Self::field_pointer (
+						field_name) ?)
+			} else {
+				return Err (
+					ApiError::BadRequest (
+						format! (
+							"Invalid sort: {}",
+							sort_name)));
+			};
+
+		let limit =
+			match context.query_param ("limit") {
+				Some (limit_string) =>
+					Some (
+						limit_string.parse::<usize> (
+						).ok ().and_then (|limit|
+							if limit > 0 { Some (limit) } else { None }
+						).ok_or_else (||
+							ApiError::BadRequest (
+								format! (
+									"Invalid limit: {}",
+									limit_string))
+						) ?
+					),
+				None =>
+					None,
+			};
+
+		let cursor =
+			match context.query_param ("cursor") {
+				Some (cursor_string) =>
+					Some (
+						Self::cursor_decode (
+							& sort_name,
+							cursor_string,
+						).ok_or_else (||
+							ApiError::BadRequest (
+								format! (
+									"Invalid cursor: {}",
+									cursor_string))
+						) ?
+					),
+				None =>
+					None,
+			};
+
+		Ok (ResourceQuery {
+			classes: strings ("class"),
+			namespaces: strings ("namespace"),
+			groups: strings ("group"),
+			name_pattern,
+			name_prefix,
+			fields,
+			sort_name: sort_name.clone (),
+			sort_key,
+			sort_reverse,
+			limit,
+			cursor,
+		})
+
+	}
+
+	fn glob_compile (
+		glob: & str,
+	) -> Result <Regex, ApiError> {
+
+		let pattern =
+			regex::escape (glob)
+				.replace ("\\*", ".*")
+				.replace ("\\?", ".");
+
+		Regex::new (
+			& format! ("^{}$", pattern),
+		).map_err (|_|
+			ApiError::BadRequest (
+				format! (
+					"Invalid name pattern: {}",
+					glob))
+		)
+
+	}
+
+	fn field_pointer (
+		path: & str,
+	) -> Result <String, ApiError> {
+
+		if path.is_empty ()
+			|| path.split ('.').any (|part| part.is_empty ()) {
+
+			return Err (
+				ApiError::BadRequest (
+					format! (
+						"Invalid field path: {}",
+						path)));
+
+		}
+
+		Ok (
+			path.split ('.').map (|part|
+				format! (
+					"/{}",
+					part.replace ("~", "~0").replace ("/", "~1"))
+			).collect ()
+		)
+
+	}
+
+	/// Reads the resources which could match, using the cache's indexes where
+	/// the filters allow. The filters must still be applied.
+	pub fn candidates (
+		& self,
+		inventory: & Inventory,
+		data: & UpstreamData,
+	) -> Vec <(String, Arc <NodeData>)> {
+
+		let mut candidates: BTreeMap <& str, & Arc <NodeData>> =
+			BTreeMap::new ();
+
+		if ! self.classes.is_empty () {
+
+			for class_name in self.classes.iter () {
+				candidates.extend (
+					data.resources_for_class (
+						class_name));
+			}
+
+		} else if ! self.namespaces.is_empty () {
+
+			// resources may name their namespace, or take it from their class
+
+			for namespace_name in self.namespaces.iter () {
+
+				candidates.extend (
+					data.resources_for_namespace (
+						namespace_name));
+
+				for (class_name, class) in inventory.classes_map ().iter () {
+
+					if class.class_namespace () == namespace_name {
+						candidates.extend (
+							data.resources_for_class (
+								class_name));
+					}
+
+				}
+
+			}
+
+		} else if ! self.groups.is_empty () {
+
+			// groups come from the class, so only those classes are needed
+
+			for (class_name, class) in inventory.classes_map ().iter () {
+
+				if class.class_groups ().iter ().any (|group|
+					self.groups.contains (group)) {
+
+					candidates.extend (
+						data.resources_for_class (
+							class_name));
+
+				}
+
+			}
+
+		} else {
+
+			candidates.extend (
+				data.resources_with_prefix (
+					& self.name_prefix));
+
+		}
+
+		candidates.into_iter ().map (|(resource_name, node)|
+			(resource_name.to_owned (), node.clone ())
+		).collect ()
+
+	}
+
+	pub fn matches (
+		& self,
+		item: & ResourceQueryItem,
+	) -> bool {
+
+		let resource =
+			item.resource ();
+
+		Self::matches_any (
+			& self.classes,
+			resource.identity_class ().as_ref ())
+
+		&& Self::matches_any (
+			& self.namespaces,
+			resource.identity_namespace ().as_ref ())
+
+		&& (
+			self.groups.is_empty ()
+			|| self.groups.iter ().any (|group|
+				resource.identity_groups ().contains (group))
+		)
+
+		&& self.name_pattern.as_ref ().map (|name_pattern|
+			name_pattern.is_match (
+				resource.identity_name ())
+		).unwrap_or (true)
+
+		&& self.fields.iter ().all (|(pointer, expected)|
+			match * item.field (pointer) {
+				JsonValue::Null => false,
+				JsonValue::String (ref value) => value == expected,
+				ref value => {
+					let value = value.to_string ();
+					value == * expected
+				},
+			}
+		)
+
+	}
+
+	fn matches_any (
+		allowed_values: & [String],
+		value: Option <& String>,
+	) -> bool {
+
+		allowed_values.is_empty ()
+		|| value.map (|value|
+			allowed_values.contains (value)
+		).unwrap_or (false)
+
+	}
+
+	/// Filters, sorts and paginates the given resources.
+	pub fn apply (
+		& self,
+		items: Vec <ResourceQueryItem>,
+	) -> ResourcePage {
+
+		let mut items: Vec <ResourceQueryItem> =
+			items.into_iter ().filter (|item|
+				self.matches (item)
+			).collect ();
+
+		let total =
+			items.len ();
+
+		items.sort_by (|left, right|
+			self.compare (
+				& self.sort_value (left),
+				left.resource ().identity_name (),
+				& self.sort_value (right),
+				right.resource ().identity_name ())
+		);
+
+		if let Some ((ref cursor_value, ref cursor_name)) = self.cursor {
+
+			items.retain (|item|
+				self.compare (
+					& self.sort_value (item),
+					item.resource ().identity_name (),
+					cursor_value,
+					cursor_name,
+				) == Ordering::Greater
+			);
+
+		}
+
+		let next_cursor =
+			match self.limit {
+
+			Some (limit) if items.len () > limit => {
+
+				items.truncate (limit);
+
+				items.last ().map (|item|
+					self.cursor_encode (
+						self.sort_value (item),
+						item.resource ().identity_name ())
+				)
+
+			},
+
+			_ => None,
+
+		};
+
+		ResourcePage {
+			total,
+			items,
+			next_cursor,
+		}
+
+	}
+
+	fn sort_value (
+		& self,
+		item: & ResourceQueryItem,
+	) -> JsonValue {
+
+		match self.sort_key {
+
+			ResourceSortKey::Name =>
+				JsonValue::Null,
+
+			ResourceSortKey::Field (ref pointer) =>
+				item.field (pointer).clone (),
+
+		}
+
+	}
+
+	fn compare (
+		& self,
+		left_value: & JsonValue,
+		left_name: & str,
+		right_value: & JsonValue,
+		right_name: & str,
+	) -> Ordering {
+
+		let ordering =
+			json_compare (
+				left_value,
+				right_value,
+			).then_with (||
+				left_name.cmp (right_name)
+			);
+
+		if self.sort_reverse {
+			ordering.reverse ()
+		} else {
+			ordering
+		}
+
+	}
+
+	/// Cursors are opaque to clients. They hold the sort they were made for,
+	/// so that they can't be used with a different one, and the sort value
+	/// and name of the last resource on the page.
+	fn cursor_encode (
+		& self,
+		sort_value: JsonValue,
+		resource_name: & str,
+	) -> String {
+
+		let cursor_value =
+			JsonValue::Array (vec! [
+				JsonValue::String (self.sort_name.clone ()),
+				sort_value,
+				JsonValue::String (
+					resource_name.to_owned ()),
+			]);
+
+		hex_encode (
+			cursor_value.to_string ().as_bytes ())
+
+	}
+
+	fn cursor_decode (
+		sort_name: & str,
+		cursor_string: & str,
+	) -> Option <(JsonValue, String)> {
+
+		let cursor_bytes =
+			hex_decode (
+				cursor_string,
+			).ok () ?;
+
+		let mut cursor_value: Vec <JsonValue> =
+			serde_json::from_slice (
+				& cursor_bytes,
+			).ok () ?;
+
+		if cursor_value.len () != 3
+			|| cursor_value [0].as_str () != Some (sort_name) {
+
+			return None;
+
+		}
+
+		let name =
+			cursor_value [2].as_str () ?.to_owned ();
+
+		Some ((
+			cursor_value.swap_remove (1),
+			name,
+		))
+
+	}
+
+}
+
+/// Orders JSON values for sorting: null first, then booleans, numbers and
+/// strings in their natural order, then anything else by its serialised
+/// form.
+fn json_compare (
+	left: & JsonValue,
+	right: & JsonValue,
+) -> Ordering {
+
+	fn rank (
+		value: & JsonValue,
+	) -> u8 {
+
+		match * value {
+			JsonValue::Null => 0,
+			JsonValue::Bool (_) => 1,
+			JsonValue::Number (_) => 2,
+			JsonValue::String (_) => 3,
+			JsonValue::Array (_) => 4,
+			JsonValue::Object (_) => 5,
+		}
+
+	}
+
+	match (left, right) {
+
+		(JsonValue::Bool (left), JsonValue::Bool (right)) =>
+			left.cmp (right),
+
+		(JsonValue::Number (left), JsonValue::Number (right)) =>
+			left.as_f64 ().partial_cmp (
+				& right.as_f64 (),
+			).unwrap_or (Ordering::Equal),
+
+		(JsonValue::String (left), JsonValue::String (right)) =>
+			left.cmp (right),
+
+		_ =>
+			rank (left).cmp (& rank (right)).then_with (||
+				left.to_string ().cmp (& right.to_string ())
+			),
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn query (
+		sort_name: & str,
+		sort_key: ResourceSortKey,
+		sort_reverse: bool,
+	) -> ResourceQuery {
+
+		ResourceQuery {
+			classes: Vec::new (),
+			namespaces: Vec::new (),
+			groups: Vec::new (),
+			name_pattern: None,
+			name_prefix: String::new (),
+			fields: Vec::new (),
+			sort_name: sort_name.to_owned (),
+			sort_key,
+			sort_reverse,
+			limit: None,
+			cursor: None,
+		}
+
+	}
+
+	#[ test ]
+	fn cursors_round_trip () {
+
+		let query =
+			query (
+				"-field.size",
+				ResourceSortKey::Field ("/size".to_owned ()),
+				true);
+
+		let cursor =
+			query.cursor_encode (
+				json! (12.5),
+				"host-a");
+
+		assert_eq! (
+			ResourceQuery::cursor_decode ("-field.size", & cursor),
+			Some ((json! (12.5), "host-a".to_owned ())));
+
+	}
+
+	#[ test ]
+	fn cursors_are_rejected_for_a_different_sort () {
+
+		let cursor =
+			query ("name", ResourceSortKey::Name, false).cursor_encode (
+				JsonValue::Null,
+				"host-a");
+
+		assert_eq! (
+			ResourceQuery::cursor_decode ("-name", & cursor),
+			None);
+
+		assert_eq! (
+			ResourceQuery::cursor_decode ("field.size", & cursor),
+			None);
+
+		assert_eq! (
+			ResourceQuery::cursor_decode ("name", "not a cursor"),
+			None);
+
+		assert_eq! (
+			ResourceQuery::cursor_decode (
+				"name",
+				& hex_encode (b"[\"name\", null]")),
+			None);
+
+	}
+
+	#[ test ]
+	fn results_after_a_cursor_follow_the_sort () {
+
+		let forward =
+			query ("field.size", ResourceSortKey::Field ("/size".to_owned ()), false);
+
+		let reverse =
+			query ("-field.size", ResourceSortKey::Field ("/size".to_owned ()), true);
+
+		// the cursor's own resource comes after it in neither direction
+
+		assert_eq! (
+			forward.compare (& json! (2), "b", & json! (2), "b"),
+			Ordering::Equal);
+
+		assert_eq! (
+			reverse.compare (& json! (2), "b", & json! (2), "b"),
+			Ordering::Equal);
+
+		// equal values are ordered by name
+
+		for (value, name, after_forward) in [
+			(json! (2), "a", false),
+			(json! (2), "c", true),
+			(json! (3), "a", true),
+			(json! (1), "z", false),
+			(JsonValue::Null, "z", false),
+			(json! ("2"), "a", true),
+		] {
+
+			assert_eq! (
+				forward.compare (& value, name, & json! (2), "b") == Ordering::Greater,
+				after_forward,
+				"{} {}",
+				value,
+				name);
+
+			assert_eq! (
+				reverse.compare (& value, name, & json! (2), "b") == Ordering::Greater,
+				! after_forward,
+				"{} {} reversed",
+				value,
+				name);
+
+		}
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
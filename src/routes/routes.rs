@@ -1,57 +1,508 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::sync::Mutex;
 
-use hyper::server::Request as HyperRequest;
-use hyper::server::Response as HyperResponse;
+use hyper::Method as HyperMethod;
+
+use percent_encoding::percent_decode_str;
 
-use regex::Captures as RegexCaptures;
 use regex::Regex;
 
-use routes::*;
-use server::*;
-use upstream::*;
+use serde_json::Value as JsonValue;
+
+use crate::auth::*;
+use crate::inventory::*;
+use crate::routes::*;
+use crate::server::*;
+use crate::settings::*;
+use crate::upstream::*;
 
 pub type RouteHandlerFn =
-	Fn (
-		Arc <Mutex <ServerState>>,
-		Arc <Upstream>,
-		RegexCaptures,
-		HyperRequest,
-		HyperResponse,
-	) + Sync;
+	dyn Fn (
+		& RouteContext,
+		ServerRequest,
+		& mut ServerResponse,
+	) -> Result <(), ApiError> + Sync;
 
 pub type RouteHandler =
 	& 'static RouteHandlerFn;
 
-macro_rules! route {
+#[ derive (Clone, Copy, Debug, PartialEq, Eq) ]
+pub enum RouteMethod {
+	Get,
+	Post,
+}
+
+impl RouteMethod {
+
+	pub fn hyper_method (
+		& self,
+	) -> HyperMethod {
+
+		match * self {
+			RouteMethod::Get => HyperMethod::GET,
+			RouteMethod::Post => HyperMethod::POST,
+		}
+
+	}
+
+}
+
+/// In the path, "{name}" matches one segment and "{name:regex}" matches the
+/// regular expression. Paths are percent-decoded before matching, so a "/" in
+/// a parameter may be sent encoded. HEAD is answered by routes accepting GET.
+/// A route with no scope is public, and one with no formats writes plain text.
+pub struct Route {
+	pub name: & 'static str,
+	pub summary: & 'static str,
+	pub methods: & 'static [RouteMethod],
+	pub path: & 'static str,
+	pub query_params: & 'static [RouteQueryParam],
+	pub scope: Option <Scope>,
+	pub formats: & 'static [OutputFormat],
+	pub response_schema: fn () -> JsonValue,
+	pub handler: RouteHandler,
+}
+
+pub struct RouteQueryParam {
+	pub name: & 'static str,
+	pub description: & 'static str,
+}
+
+#[ derive (Clone, Copy) ]
+pub enum RoutePathPart {
+	Literal (& 'static str),
+	Param (& 'static str, & 'static str),
+}
+
+impl Route {
+
+	/// Every method which this route answers, including the implicit HEAD
+	/// and OPTIONS.
+	pub fn allowed_methods (
+		& self,
+	) -> Vec <HyperMethod> {
+
+		let mut allowed_methods: Vec <HyperMethod> =
+			self.methods.iter ().map (|method|
+				method.hyper_method ()
+			).collect ();
+
+		if self.methods.contains (& RouteMethod::Get) {
+
+			allowed_methods.push (
+				HyperMethod::HEAD);
+
+		}
+
+		allowed_methods.push (
+			HyperMethod::OPTIONS);
+
+		allowed_methods
+
+	}
+
+	/// Splits the path into literal text and parameters, with the pattern
+	/// for each parameter.
+	pub fn path_parts (
+		& self,
+	) -> Vec <RoutePathPart> {
+
+		let mut parts: Vec <RoutePathPart> =
+			Vec::new ();
+
+		let mut rest =
+			self.path;
+
+		while let Some (start) = rest.find ('{') {
+
+			if start > 0 {
+
+				parts.push (
+					RoutePathPart::Literal (
+						& rest [ .. start]));
+
+			}
+
+			let end =
+				rest [start .. ].find ('}').unwrap_or_else (||
+					panic! (
+						"Unterminated parameter in route {}",
+						self.name)
+				) + start;
+
+			let parameter =
+				& rest [start + 1 .. end];
+
+			parts.push (
+				match parameter.find (':') {
+					Some (colon) =>
+						RoutePathPart::Param (
+							& parameter [ .. colon],
+							& parameter [colon + 1 .. ]),
+					None =>
+						RoutePathPart::Param (
+							parameter,
+							"[^/]+"),
+				});
+
+			rest =
+				& rest [end + 1 .. ];
+
+		}
+
+		if ! rest.is_empty () {
 
-	($regex: ident, $handler: ident) => {
-		(
-			Regex::new ($regex).unwrap (),
-			$handler,
+			parts.push (
+				RoutePathPart::Literal (
+					rest));
+
+		}
+
+		parts
+
+	}
+
+	fn accepts (
+		& self,
+		method: & HyperMethod,
+	) -> bool {
+
+		self.methods.iter ().any (|route_method|
+			route_method.hyper_method () == * method
+		) || (
+			* method == HyperMethod::HEAD
+			&& self.methods.contains (& RouteMethod::Get)
 		)
+
 	}
 
 }
 
-lazy_static! {
+pub struct RouteContext {
+	pub settings: Arc <Settings>,
+	pub state: Arc <ServerState>,
+	pub upstream: Arc <Upstream>,
+	pub shared_inventory: Arc <SharedInventory>,
+	pub inventory: Arc <Inventory>,
+	pub identity: Option <Identity>,
+	pub query: Vec <(String, String)>,
+	pub params: HashMap <String, String>,
+}
+
+impl RouteContext {
+
+	/// Returns the authenticated identity. The handler ensures this is
+	/// present for every route which has a scope.
+	pub fn identity (
+		& self,
+	) -> & Identity {
+
+		self.identity.as_ref ().expect (
+			"Route requires authentication")
+
+	}
+
+	/// Returns a named path parameter. The router guarantees that every
+	/// parameter in the route's path is present.
+	pub fn param (
+		& self,
+		name: & str,
+	) -> & str {
+
+		self.params.get (
+			name,
+		).map (|value|
+			value.as_str ()
+		).unwrap_or_else (||
+			panic! (
+				"Route has no parameter {}",
+				name)
+		)
+
+	}
+
+	/// Returns the last value given for a query parameter, if any.
+	pub fn query_param (
+		& self,
+		name: & str,
+	) -> Option <& str> {
+
+		self.query.iter ().rev ().find (|& (key, _)|
+			key == name
+		).map (|(_, value)|
+			value.as_str ()
+		)
+
+	}
+
+	/// Returns every value given for a query parameter, in order.
+	pub fn query_params (
+		& self,
+		name: & str,
+	) -> Vec <& str> {
+
+		self.query.iter ().filter (|& (key, _)|
+			key == name
+		).map (|(_, value)|
+			value.as_str ()
+		).collect ()
+
+	}
+
+}
+
+pub enum RouteMatch {
+	Found (& 'static Route, HashMap <String, String>),
+	Options (Vec <HyperMethod>),
+	MethodNotAllowed (Vec <HyperMethod>),
+	NotFound,
+}
+
+struct CompiledRoute {
+	route: & 'static Route,
+	regex: Regex,
+}
+
+fn route_compile (
+	route: & 'static Route,
+) -> CompiledRoute {
+
+	let mut pattern =
+		String::from ("^");
+
+	for part in route.path_parts () {
+
+		match part {
 
-	pub static ref ROUTES: Vec <(Regex, RouteHandler)> = vec! [
+			RoutePathPart::Literal (literal) =>
+				pattern.push_str (
+					& regex::escape (literal)),
 
-		route! (
-			ROUTE_RAW_KEY_REGEX,
-			ROUTE_RAW_KEY_HANDLER),
+			RoutePathPart::Param (parameter_name, parameter_pattern) =>
+				pattern.push_str (
+					& format! (
+						"(?P<{}>{})",
+						parameter_name,
+						parameter_pattern)),
 
-		route! (
-			ROUTE_RAW_RESOURCE_REGEX,
-			ROUTE_RAW_RESOURCE_HANDLER),
+		}
 
-		route! (
-			ROUTE_RAW_RESOURCES_REGEX,
-			ROUTE_RAW_RESOURCES_HANDLER),
+	}
+
+	pattern.push ('$');
+
+	CompiledRoute {
+		route,
+		regex: Regex::new (& pattern).unwrap_or_else (|error|
+			panic! (
+				"Invalid path for route {}: {}",
+				route.name,
+				error)
+		),
+	}
+
+}
+
+pub fn route_resolve (
+	method: & HyperMethod,
+	path: & str,
+) -> RouteMatch {
+
+	let path =
+		match percent_decode_str (path).decode_utf8 () {
+			Ok (path) => path,
+			Err (_) => return RouteMatch::NotFound,
+		};
+
+	let mut allowed_methods: Vec <HyperMethod> =
+		Vec::new ();
+
+	for compiled_route in ROUTES.iter () {
+
+		let captures =
+			match compiled_route.regex.captures (& path) {
+				Some (captures) => captures,
+				None => continue,
+			};
+
+		if compiled_route.route.accepts (method) {
+
+			let params: HashMap <String, String> =
+				compiled_route.regex.capture_names ().flatten (
+				).filter_map (|name|
+					captures.name (name).map (|value|
+						(name.to_owned (), value.as_str ().to_owned ())
+					)
+				).collect ();
+
+			return RouteMatch::Found (
+				compiled_route.route,
+				params);
+
+		}
+
+		for allowed_method in compiled_route.route.allowed_methods () {
+
+			if ! allowed_methods.contains (& allowed_method) {
+				allowed_methods.push (allowed_method);
+			}
+
+		}
+
+	}
 
+	if allowed_methods.is_empty () {
+		RouteMatch::NotFound
+	} else if * method == HyperMethod::OPTIONS {
+		RouteMatch::Options (allowed_methods)
+	} else {
+		RouteMatch::MethodNotAllowed (allowed_methods)
+	}
+
+}
+
+/// Every registered route, in the order they are matched.
+pub fn routes_all (
+) -> Vec <& 'static Route> {
+
+	ROUTES.iter ().map (|compiled_route|
+		compiled_route.route
+	).collect ()
+
+}
+
+lazy_static! {
+
+	static ref ROUTES: Vec <CompiledRoute> = vec! [
+		route_compile (& ROUTE_HEALTH_LIVE),
+		route_compile (& ROUTE_HEALTH_READY),
+		route_compile (& ROUTE_METRICS),
+		route_compile (& ROUTE_OPENAPI),
+		route_compile (& ROUTE_RAW_KEY),
+		route_compile (& ROUTE_RAW_KEYS),
+		route_compile (& ROUTE_RAW_RESOURCE),
+		route_compile (& ROUTE_RAW_RESOURCES),
+		route_compile (& ROUTE_BATCH),
+		route_compile (& ROUTE_ADMIN_RELOAD_PROJECT),
+		route_compile (& ROUTE_ADMIN_RESYNC),
+		route_compile (& ROUTE_ADMIN_STATE),
+		route_compile (& ROUTE_ADMIN_DUMP),
 	];
 
 }
 
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn found (
+		method: HyperMethod,
+		path: & str,
+	) -> (& 'static str, HashMap <String, String>) {
+
+		match route_resolve (& method, path) {
+			RouteMatch::Found (route, params) => (route.name, params),
+			_ => panic! ("No route found for {} {}", method, path),
+		}
+
+	}
+
+	#[ test ]
+	fn routes_are_found_with_their_params () {
+
+		let (route_name, params) =
+			found (HyperMethod::GET, "/raw/resource/host-a");
+
+		assert_eq! (route_name, "raw_resource");
+		assert_eq! (params ["name"], "host-a");
+
+		// HEAD is answered by the GET route
+
+		let (route_name, _) =
+			found (HyperMethod::HEAD, "/health/live");
+
+		assert_eq! (route_name, "health_live");
+
+		let (route_name, params) =
+			found (HyperMethod::GET, "/raw/keys");
+
+		assert_eq! (route_name, "raw_keys");
+		assert_eq! (params ["prefix"], "");
+
+	}
+
+	#[ test ]
+	fn options_lists_the_allowed_methods () {
+
+		match route_resolve (& HyperMethod::OPTIONS, "/batch") {
+
+			RouteMatch::Options (allowed_methods) =>
+				assert_eq! (
+					allowed_methods,
+					vec! [ HyperMethod::POST, HyperMethod::OPTIONS ]),
+
+			_ => panic! ("Expected options"),
+
+		}
+
+	}
+
+	#[ test ]
+	fn other_methods_are_not_allowed () {
+
+		match route_resolve (& HyperMethod::DELETE, "/health/live") {
+
+			RouteMatch::MethodNotAllowed (allowed_methods) =>
+				assert_eq! (
+					allowed_methods,
+					vec! [ HyperMethod::GET, HyperMethod::HEAD, HyperMethod::OPTIONS ]),
+
+			_ => panic! ("Expected method not allowed"),
+
+		}
+
+	}
+
+	#[ test ]
+	fn unknown_paths_are_not_found () {
+
+		for path in [ "/", "/raw", "/health/live/", "/raw/resource/", "/%FF" ] {
+
+			assert! (
+				matches! (
+					route_resolve (& HyperMethod::GET, path),
+					RouteMatch::NotFound),
+				"{}",
+				path);
+
+		}
+
+	}
+
+	#[ test ]
+	fn encoded_slashes_are_decoded_before_matching () {
+
+		let (route_name, params) =
+			found (HyperMethod::GET, "/raw/key%2Fresource%2Fa%20b");
+
+		assert_eq! (route_name, "raw_key");
+		assert_eq! (params ["key"], "/resource/a b");
+
+		let (route_name, params) =
+			found (HyperMethod::GET, "/raw/keys%2Fresource");
+
+		assert_eq! (route_name, "raw_keys");
+		assert_eq! (params ["prefix"], "/resource");
+
+		let (route_name, params) =
+			found (HyperMethod::GET, "/raw/resource/a%2Fb");
+
+		assert_eq! (route_name, "raw_resource");
+		assert_eq! (params ["name"], "a/b");
+
+	}
+
+}
+
 // ex: noet ts=4 filetype=rust
@@ -0,0 +1,350 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use openssl::rand;
+
+use time::OffsetDateTime;
+use time::format_description::FormatItem;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+
+use crate::hex::*;
+use crate::server::*;
+use crate::settings::*;
+
+const COMMON_TIME_FORMAT: & [FormatItem <'static>] =
+	format_description! (
+		"[day]/[month repr:short]/[year]:[hour]:[minute]:[second] \
+		[offset_hour sign:mandatory][offset_minute]");
+
+#[ derive (Clone, Copy, Debug, PartialEq, Eq) ]
+pub enum AccessLogFormat {
+	Common,
+	Combined,
+	Json,
+}
+
+impl AccessLogFormat {
+
+	pub fn parse (
+		name: & str,
+	) -> Result <AccessLogFormat, String> {
+
+		match name {
+
+			"common" => Ok (AccessLogFormat::Common),
+			"combined" => Ok (AccessLogFormat::Combined),
+			"json" => Ok (AccessLogFormat::Json),
+
+			_ => Err (
+				format! (
+					"Invalid access log format: {}",
+					name)),
+
+		}
+
+	}
+
+}
+
+/// Everything recorded about a single request.
+pub struct AccessLogEntry {
+	pub request_id: String,
+	pub client_address: ClientAddress,
+	pub identity_name: Option <String>,
+	pub method: String,
+	pub uri: String,
+	pub version: String,
+	pub status: u16,
+	pub bytes: u64,
+	pub duration: Duration,
+	pub referer: Option <String>,
+	pub user_agent: Option <String>,
+}
+
+#[ derive (Serialize) ]
+struct AccessLogJson <'a> {
+
+	#[ serde (rename = "time") ]
+	time: String,
+
+	#[ serde (rename = "request_id") ]
+	request_id: & 'a str,
+
+	#[ serde (rename = "client_address") ]
+	client_address: String,
+
+	#[ serde (rename = "identity") ]
+	identity_name: & 'a Option <String>,
+
+	#[ serde (rename = "method") ]
+	method: & 'a str,
+
+	#[ serde (rename = "uri") ]
+	uri: & 'a str,
+
+	#[ serde (rename = "version") ]
+	version: & 'a str,
+
+	#[ serde (rename = "status") ]
+	status: u16,
+
+	#[ serde (rename = "bytes") ]
+	bytes: u64,
+
+	#[ serde (rename = "duration_ms") ]
+	duration_ms: f64,
+
+	#[ serde (rename = "referer") ]
+	referer: & 'a Option <String>,
+
+	#[ serde (rename = "user_agent") ]
+	user_agent: & 'a Option <String>,
+
+}
+
+pub struct AccessLog {
+	settings: AccessLogSettings,
+	format: AccessLogFormat,
+	output: Mutex <Box <dyn Write + Send>>,
+}
+
+impl AccessLog {
+
+	pub fn new (
+		settings: & AccessLogSettings,
+	) -> Result <AccessLog, String> {
+
+		Ok (AccessLog {
+			settings: settings.clone (),
+			format: AccessLogFormat::parse (& settings.format) ?,
+			output: Mutex::new (Self::output_open (settings) ?),
+		})
+
+	}
+
+	/// Opens the log file again, so that it can be rotated.
+	pub fn reopen (
+		& self,
+	) -> Result <(), String> {
+
+		let output =
+			Self::output_open (
+				& self.settings,
+			) ?;
+
+		* self.output.lock ().unwrap () =
+			output;
+
+		Ok (())
+
+	}
+
+	fn output_open (
+		settings: & AccessLogSettings,
+	) -> Result <Box <dyn Write + Send>, String> {
+
+		match settings.path {
+
+			Some (ref path) =>
+				Ok (Box::new (
+					OpenOptions::new ()
+						.create (true)
+						.append (true)
+						.open (path)
+						.map_err (|error|
+							format! (
+								"Error opening access log {}: {}",
+								path.to_string_lossy (),
+								error)
+						) ?
+				)),
+
+			None =>
+				Ok (Box::new (io::stdout ())),
+
+		}
+
+	}
+
+	pub fn log (
+		& self,
+		entry: & AccessLogEntry,
+	) {
+
+		let line =
+			match self.format {
+
+			AccessLogFormat::Common =>
+				Self::format_common (entry),
+
+			AccessLogFormat::Combined =>
+				format! (
+					"{} \"{}\" \"{}\"",
+					Self::format_common (entry),
+					escape (entry.referer.as_deref ().unwrap_or ("-")),
+					escape (entry.user_agent.as_deref ().unwrap_or ("-"))),
+
+			AccessLogFormat::Json =>
+				Self::format_json (entry),
+
+		};
+
+		let mut output =
+			self.output.lock ().unwrap ();
+
+		writeln! (
+			output,
+			"{}",
+			line,
+		).and_then (|_|
+			output.flush ()
+		).unwrap_or_else (|error|
+			println! (
+				"Error writing access log: {}",
+				error)
+		);
+
+	}
+
+	/// The common log format, followed by the request ID and duration, which
+	/// log parsers generally ignore.
+	fn format_common (
+		entry: & AccessLogEntry,
+	) -> String {
+
+		format! (
+			"{} - {} [{}] \"{} {} {}\" {} {} request_id={} duration_ms={:.3}",
+			entry.client_address,
+			entry.identity_name.as_ref ().map (|identity_name|
+				escape (identity_name)).unwrap_or ("-".to_string ()),
+			OffsetDateTime::now_utc ().format (
+				COMMON_TIME_FORMAT,
+			).unwrap_or_default (),
+			entry.method,
+			escape (& entry.uri),
+			entry.version,
+			entry.status,
+			entry.bytes,
+			entry.request_id,
+			duration_ms (entry.duration))
+
+	}
+
+	fn format_json (
+		entry: & AccessLogEntry,
+	) -> String {
+
+		serde_json::to_string (
+			& AccessLogJson {
+				time: OffsetDateTime::now_utc ().format (
+					& Rfc3339,
+				).unwrap_or_default (),
+				request_id: & entry.request_id,
+				client_address: entry.client_address.to_string (),
+				identity_name: & entry.identity_name,
+				method: & entry.method,
+				uri: & entry.uri,
+				version: & entry.version,
+				status: entry.status,
+				bytes: entry.bytes,
+				duration_ms: duration_ms (entry.duration),
+				referer: & entry.referer,
+				user_agent: & entry.user_agent,
+			},
+		).unwrap ()
+
+	}
+
+}
+
+/// Uses the client's X-Request-Id if it supplied a reasonable one, so that
+/// requests can be traced through proxies, otherwise generates a new one.
+pub fn request_id_for (
+	request: & ServerRequest,
+) -> String {
+
+	if let Some (request_id) =
+		request.header (
+			"X-Request-Id",
+		).map (|value|
+			value.to_owned ()
+		) {
+
+		if ! request_id.is_empty ()
+			&& request_id.len () <= 64
+			&& request_id.chars ().all (|character|
+				character.is_alphanumeric ()
+					|| character == '-'
+					|| character == '_') {
+
+			return request_id;
+
+		}
+
+	}
+
+	let mut request_id_bytes: [u8; 8] = [0; 8];
+
+	rand::rand_bytes (
+		& mut request_id_bytes,
+	).unwrap_or_else (|error|
+		panic! (
+			"Error generating request id: {}",
+			error)
+	);
+
+	hex_encode (
+		& request_id_bytes)
+
+}
+
+fn duration_ms (
+	duration: Duration,
+) -> f64 {
+
+	duration.as_secs () as f64 * 1000.0
+		+ duration.subsec_nanos () as f64 / 1000000.0
+
+}
+
+/// Escapes quotes, backslashes and control characters, so that a field
+/// can't break the log line apart.
+fn escape (
+	value: & str,
+) -> String {
+
+	let mut escaped =
+		String::with_capacity (
+			value.len ());
+
+	for character in value.chars () {
+
+		match character {
+
+			'"' | '\\' => {
+				escaped.push ('\\');
+				escaped.push (character);
+			},
+
+			character if character.is_control () =>
+				escaped.push_str (
+					& format! (
+						"\\x{:02x}",
+						character as u32)),
+
+			character =>
+				escaped.push (character),
+
+		}
+
+	}
+
+	escaped
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,70 @@
+use crate::server::*;
+use crate::settings::*;
+
+/// The content encodings which responses can be compressed with. Deflate is
+/// sent in the zlib format, which is what HTTP means by it.
+#[ derive (Clone, Copy, Debug, PartialEq, Eq) ]
+pub enum ResponseEncoding {
+	Gzip,
+	Deflate,
+}
+
+impl ResponseEncoding {
+
+	pub fn header_value (
+		& self,
+	) -> & 'static str {
+
+		match * self {
+			ResponseEncoding::Gzip => "gzip",
+			ResponseEncoding::Deflate => "deflate",
+		}
+
+	}
+
+	/// Chooses an encoding from Accept-Encoding, preferring gzip on a tie. A
+	/// "*" only applies to encodings not listed by name.
+	pub fn negotiate (
+		compression_settings: & CompressionSettings,
+		request: & ServerRequest,
+	) -> Option <ResponseEncoding> {
+
+		if ! compression_settings.enabled {
+			return None;
+		}
+
+		let mut gzip_quality: Option <u16> = None;
+		let mut deflate_quality: Option <u16> = None;
+		let mut any_quality: Option <u16> = None;
+
+		for (accept_item, quality) in request.header_quality_list (
+			"Accept-Encoding") {
+
+			match accept_item.as_str () {
+				"gzip" => gzip_quality = Some (quality),
+				"deflate" => deflate_quality = Some (quality),
+				"*" => any_quality = Some (quality),
+				_ => (),
+			}
+
+		}
+
+		let gzip_quality =
+			gzip_quality.or (any_quality).unwrap_or (0);
+
+		let deflate_quality =
+			deflate_quality.or (any_quality).unwrap_or (0);
+
+		if gzip_quality > 0 && gzip_quality >= deflate_quality {
+			Some (ResponseEncoding::Gzip)
+		} else if deflate_quality > 0 {
+			Some (ResponseEncoding::Deflate)
+		} else {
+			None
+		}
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
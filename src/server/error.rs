@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Error as IoError;
+
+use hyper::Method as HyperMethod;
+use hyper::StatusCode as HyperStatusCode;
+use hyper::header;
+
+use crate::server::*;
+
+/// Every error which the API can return, each with a stable code.
+#[ derive (Debug) ]
+pub enum ApiError {
+	Unauthenticated,
+	Forbidden,
+	RouteNotFound,
+	MethodNotAllowed (Vec <HyperMethod>),
+	KeyNotFound (String),
+	ResourceNotFound (String),
+	BadRequest (String),
+	NotAcceptable (Vec <& 'static str>),
+	TooManyRequests (u64),
+	ServiceUnavailable,
+	OperationFailed (String),
+	Internal (String),
+}
+
+#[ derive (Serialize) ]
+struct ApiErrorBody <'a> {
+
+	#[ serde (rename = "error") ]
+	error: ApiErrorBodyContent <'a>,
+
+}
+
+#[ derive (Serialize) ]
+struct ApiErrorBodyContent <'a> {
+
+	#[ serde (rename = "code") ]
+	code: & 'static str,
+
+	#[ serde (rename = "message") ]
+	message: String,
+
+	#[ serde (rename = "path") ]
+	path: & 'a str,
+
+	#[ serde (rename = "details") ]
+	details: BTreeMap <& 'static str, String>,
+
+}
+
+impl ApiError {
+
+	pub fn code (
+		& self,
+	) -> & 'static str {
+
+		match * self {
+			ApiError::Unauthenticated => "unauthenticated",
+			ApiError::Forbidden => "forbidden",
+			ApiError::RouteNotFound => "route_not_found",
+			ApiError::MethodNotAllowed (_) => "method_not_allowed",
+			ApiError::KeyNotFound (_) => "key_not_found",
+			ApiError::ResourceNotFound (_) => "resource_not_found",
+			ApiError::BadRequest (_) => "bad_request",
+			ApiError::NotAcceptable (_) => "not_acceptable",
+			ApiError::TooManyRequests (_) => "too_many_requests",
+			ApiError::ServiceUnavailable => "service_unavailable",
+			ApiError::OperationFailed (_) => "operation_failed",
+			ApiError::Internal (_) => "internal_error",
+		}
+
+	}
+
+	pub fn status (
+		& self,
+	) -> HyperStatusCode {
+
+		match * self {
+			ApiError::Unauthenticated => HyperStatusCode::UNAUTHORIZED,
+			ApiError::Forbidden => HyperStatusCode::FORBIDDEN,
+			ApiError::RouteNotFound => HyperStatusCode::NOT_FOUND,
+			ApiError::MethodNotAllowed (_) => HyperStatusCode::METHOD_NOT_ALLOWED,
+			ApiError::KeyNotFound (_) => HyperStatusCode::NOT_FOUND,
+			ApiError::ResourceNotFound (_) => HyperStatusCode::NOT_FOUND,
+			ApiError::BadRequest (_) => HyperStatusCode::BAD_REQUEST,
+			ApiError::NotAcceptable (_) => HyperStatusCode::NOT_ACCEPTABLE,
+			ApiError::TooManyRequests (_) => HyperStatusCode::TOO_MANY_REQUESTS,
+			ApiError::ServiceUnavailable => HyperStatusCode::SERVICE_UNAVAILABLE,
+			ApiError::OperationFailed (_) => HyperStatusCode::INTERNAL_SERVER_ERROR,
+			ApiError::Internal (_) => HyperStatusCode::INTERNAL_SERVER_ERROR,
+		}
+
+	}
+
+	pub fn message (
+		& self,
+	) -> String {
+
+		match * self {
+
+			ApiError::Unauthenticated =>
+				"Must authenticate with a bearer token or client certificate"
+					.to_string (),
+
+			ApiError::Forbidden =>
+				"Not permitted to access this".to_string (),
+
+			ApiError::RouteNotFound =>
+				"No route matches this path".to_string (),
+
+			ApiError::MethodNotAllowed (_) =>
+				"Method not allowed for this path".to_string (),
+
+			ApiError::KeyNotFound (ref key) =>
+				format! (
+					"Key not found: {}",
+					key),
+
+			ApiError::ResourceNotFound (ref resource_name) =>
+				format! (
+					"Resource not found: {}",
+					resource_name),
+
+			ApiError::BadRequest (ref message) =>
+				message.clone (),
+
+			ApiError::NotAcceptable (_) =>
+				"None of the requested formats are available".to_string (),
+
+			ApiError::TooManyRequests (_) =>
+				"Too many requests, try again later".to_string (),
+
+			ApiError::ServiceUnavailable =>
+				"Too many requests in progress, try again later".to_string (),
+
+			ApiError::OperationFailed (ref message) =>
+				message.clone (),
+
+			ApiError::Internal (_) =>
+				"Internal server error".to_string (),
+
+		}
+
+	}
+
+	fn details (
+		& self,
+	) -> BTreeMap <& 'static str, String> {
+
+		let mut details: BTreeMap <& 'static str, String> =
+			BTreeMap::new ();
+
+		match * self {
+
+			ApiError::MethodNotAllowed (ref allowed_methods) => {
+
+				details.insert (
+					"allowed_methods",
+					allowed_methods.iter ().map (|method|
+						method.to_string ()
+					).collect::<Vec <String>> ().join (", "));
+
+			},
+
+			ApiError::KeyNotFound (ref key) => {
+
+				details.insert (
+					"key",
+					key.clone ());
+
+			},
+
+			ApiError::ResourceNotFound (ref resource_name) => {
+
+				details.insert (
+					"resource_name",
+					resource_name.clone ());
+
+			},
+
+			ApiError::NotAcceptable (ref available_formats) => {
+
+				details.insert (
+					"available_formats",
+					available_formats.join (", "));
+
+			},
+
+			ApiError::TooManyRequests (retry_after) => {
+
+				details.insert (
+					"retry_after",
+					retry_after.to_string ());
+
+			},
+
+			_ => (),
+
+		}
+
+		details
+
+	}
+
+	pub fn send (
+		self,
+		path: & str,
+		response: & mut ServerResponse,
+	) -> Result <(), IoError> {
+
+		let body =
+			serde_json::to_vec (
+				& ApiErrorBody {
+					error: ApiErrorBodyContent {
+						code: self.code (),
+						message: self.message (),
+						path,
+						details: self.details (),
+					},
+				},
+			).map_err (|error|
+				IoError::other (
+					error.to_string ())
+			) ?;
+
+		* response.status_mut () =
+			self.status ();
+
+		response.header_set (
+			header::CONTENT_TYPE,
+			"application/json");
+
+		if let ApiError::MethodNotAllowed (ref allowed_methods) = self {
+
+			response.allow (
+				allowed_methods);
+
+		}
+
+		if let ApiError::TooManyRequests (retry_after) = self {
+
+			response.header_set (
+				header::RETRY_AFTER,
+				& retry_after.to_string ());
+
+		}
+
+		response.send (
+			& body)
+
+	}
+
+}
+
+impl fmt::Display for ApiError {
+
+	fn fmt (
+		& self,
+		formatter: & mut fmt::Formatter,
+	) -> fmt::Result {
+
+		match * self {
+
+			ApiError::Internal (ref message) =>
+				write! (
+					formatter,
+					"{}: {}",
+					self.code (),
+					message),
+
+			_ =>
+				write! (
+					formatter,
+					"{}: {}",
+					self.code (),
+					self.message ()),
+
+		}
+
+	}
+
+}
+
+impl From <IoError> for ApiError {
+
+	fn from (
+		error: IoError,
+	) -> ApiError {
+
+		ApiError::Internal (
+			format! (
+				"Error writing response: {}",
+				error))
+
+	}
+
+}
+
+impl From <serde_yaml::Error> for ApiError {
+
+	fn from (
+		error: serde_yaml::Error,
+	) -> ApiError {
+
+		ApiError::Internal (
+			format! (
+				"Error encoding response: {}",
+				error))
+
+	}
+
+}
+
+impl From <serde_json::Error> for ApiError {
+
+	fn from (
+		error: serde_json::Error,
+	) -> ApiError {
+
+		ApiError::Internal (
+			format! (
+				"Error encoding response: {}",
+				error))
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,215 @@
+use std::cmp;
+use std::io::Write;
+
+use hyper::header;
+
+use serde::Serialize;
+
+use crate::routes::*;
+use crate::server::*;
+
+/// The formats which a route can write its output in. INI is only offered by
+/// routes which can write an Ansible inventory.
+#[ derive (Clone, Copy, Debug, PartialEq, Eq) ]
+pub enum OutputFormat {
+	Json,
+	PrettyJson,
+	Yaml,
+	Ini,
+}
+
+pub const DATA_FORMATS: & [OutputFormat] = & [
+	OutputFormat::Json,
+	OutputFormat::PrettyJson,
+	OutputFormat::Yaml,
+];
+
+pub const INVENTORY_FORMATS: & [OutputFormat] = & [
+	OutputFormat::Json,
+	OutputFormat::PrettyJson,
+	OutputFormat::Yaml,
+	OutputFormat::Ini,
+];
+
+impl OutputFormat {
+
+	pub fn name (
+		& self,
+	) -> & 'static str {
+
+		match * self {
+			OutputFormat::Json => "json",
+			OutputFormat::PrettyJson => "json-pretty",
+			OutputFormat::Yaml => "yaml",
+			OutputFormat::Ini => "ini",
+		}
+
+	}
+
+	pub fn content_type (
+		& self,
+	) -> & 'static str {
+
+		match * self {
+			OutputFormat::Json => "application/json",
+			OutputFormat::PrettyJson => "application/json",
+			OutputFormat::Yaml => "application/x-yaml",
+			OutputFormat::Ini => "text/plain; charset=utf-8",
+		}
+
+	}
+
+	fn media_types (
+		& self,
+	) -> & 'static [& 'static str] {
+
+		match * self {
+			OutputFormat::Json => & [ "application/json" ],
+			OutputFormat::PrettyJson => & [],
+			OutputFormat::Yaml => & [
+				"application/x-yaml",
+				"application/yaml",
+				"text/yaml",
+				"text/x-yaml",
+			],
+			OutputFormat::Ini => & [ "text/x-ini", "text/plain" ],
+		}
+
+	}
+
+	/// Chooses a format from the "format" query parameter, or else the Accept
+	/// header. The first supported format is the default.
+	pub fn negotiate (
+		context: & RouteContext,
+		request: & ServerRequest,
+		supported: & 'static [OutputFormat],
+	) -> Result <OutputFormat, ApiError> {
+
+		let not_acceptable = ||
+			ApiError::NotAcceptable (
+				supported.iter ().map (|format|
+					format.name ()
+				).collect ());
+
+		if let Some (format_name) = context.query_param ("format") {
+
+			return supported.iter ().find (|format|
+				format.name () == format_name
+			).cloned ().ok_or_else (
+				not_acceptable);
+
+		}
+
+		if ! request.headers ().contains_key (header::ACCEPT) {
+			return Ok (supported [0]);
+		}
+
+		let mut accept_items: Vec <(String, u16)> =
+			request.header_quality_list (
+				"Accept",
+			).into_iter ().filter (|& (_, quality)|
+				quality > 0
+			).collect ();
+
+		accept_items.sort_by_key (|& (_, quality)|
+			cmp::Reverse (quality)
+		);
+
+		for (media_type, _) in accept_items {
+
+			let format =
+				if media_type == "*/*" {
+					Some (supported [0])
+				} else if media_type.ends_with ("/*") {
+					supported.iter ().find (|format|
+						format.media_types ().iter ().any (|format_type|
+							format_type.starts_with (
+								& media_type [ .. media_type.len () - 1]))
+					).cloned ()
+				} else {
+					supported.iter ().find (|format|
+						format.media_types ().contains (
+							& media_type.as_str ())
+					).cloned ()
+				};
+
+			if let Some (format) = format {
+				return Ok (format);
+			}
+
+		}
+
+		Err (not_acceptable ())
+
+	}
+
+	/// Sets the headers for this format and starts the response, for routes
+	/// which write the output themselves.
+	pub fn start (
+		& self,
+		response: & mut ServerResponse,
+	) -> Result <ServerResponseWriter, ApiError> {
+
+		response.header_set (
+			header::CONTENT_TYPE,
+			self.content_type ());
+
+		response.vary (
+			"Accept");
+
+		Ok (response.start () ?)
+
+	}
+
+	/// Writes a value in this format, streaming it as it is serialised. Routes
+	/// offering INI write it themselves.
+	pub fn send <Value: Serialize> (
+		& self,
+		response: & mut ServerResponse,
+		value: & Value,
+	) -> Result <(), ApiError> {
+
+		let mut writer =
+			self.start (
+				response,
+			) ?;
+
+		match * self {
+
+			OutputFormat::Json =>
+				serde_json::to_writer (
+					& mut writer,
+					value,
+				) ?,
+
+			OutputFormat::PrettyJson =>
+				serde_json::to_writer_pretty (
+					& mut writer,
+					value,
+				) ?,
+
+			OutputFormat::Yaml =>
+				serde_yaml::to_writer (
+					& mut writer,
+					value,
+				) ?,
+
+			OutputFormat::Ini =>
+				return Err (
+					ApiError::Internal (
+						"INI output must be written by the route".to_string ())),
+
+		}
+
+		writer.write_all (
+			b"\n") ?;
+
+		writer.end () ?;
+
+		Ok (())
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,159 +1,401 @@
+use std::convert::Infallible;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::time::Instant;
 
-use hyper::header;
-use hyper::server::Handler as HyperHandler;
-use hyper::server::Request as HyperRequest;
-use hyper::server::Response as HyperResponse;
-use hyper::status::StatusCode as HyperStatusCode;
-use hyper::uri::RequestUri as HyperRequestUri;
+use hyper::Method as HyperMethod;
+use hyper::Response as HyperResponse;
+use hyper::StatusCode as HyperStatusCode;
+use hyper::header::HeaderName as HyperHeaderName;
 
-use routes::*;
-use server::*;
-use settings::*;
-use upstream::*;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::oneshot;
+use tokio::task;
 
+use url::form_urlencoded;
+
+use crate::auth::*;
+use crate::inventory::*;
+use crate::routes::*;
+use crate::server::*;
+use crate::settings::*;
+use crate::upstream::*;
+
+#[ derive (Clone) ]
 pub struct ServerHandler {
 	pub settings: Arc <Settings>,
-	pub state: Arc <Mutex <ServerState>>,
+	pub state: Arc <ServerState>,
 	pub upstream: Arc <Upstream>,
+	pub inventory: Arc <SharedInventory>,
+	pub authenticator: Arc <Authenticator>,
+	pub access_log: Arc <AccessLog>,
 }
 
-impl HyperHandler for ServerHandler {
+impl ServerHandler {
+
+	/// Answers a request on the blocking pool, since route handlers are
+	/// synchronous. Requests beyond the concurrency limit are refused, rather
+	/// than queued for a thread.
+	pub async fn handle (
+		self,
+		request: ServerRequest,
+	) -> Result <HyperResponse <ServerBody>, Infallible> {
+
+		let (response_sender, response_receiver) =
+			oneshot::channel ();
+
+		let request_slot =
+			self.state.request_slots.clone ().try_acquire_owned ().ok ();
+
+		task::spawn_blocking (move ||
+			self.handle_blocking (
+				request,
+				request_slot,
+				response_sender)
+		);
+
+		// the handler always sends a response, unless it panicked
+
+		Ok (
+			response_receiver.await.unwrap_or_else (|_| {
+
+				let mut hyper_response =
+					HyperResponse::new (
+						ServerBody::empty ());
+
+				* hyper_response.status_mut () =
+					HyperStatusCode::INTERNAL_SERVER_ERROR;
+
+				hyper_response
+
+			})
+		)
+
+	}
 
-	fn handle (
+	fn handle_blocking (
 		& self,
-		request: HyperRequest,
-		mut response: HyperResponse,
+		request: ServerRequest,
+		request_slot: Option <OwnedSemaphorePermit>,
+		response_sender: ServerResponseSender,
 	) {
 
-		{
-
-			type BearerHeader =
-				header::Authorization <header::Bearer>;
+		let start_time =
+			Instant::now ();
 
-			if ! request.headers.get::<BearerHeader> (
-			).map (|bearer_header|
+		let head_only =
+			* request.method () == HyperMethod::HEAD;
 
-				bearer_header.token
-					== self.settings.server.authorization_token,
+		let compression_settings =
+			& self.settings.server.compression;
 
-			).unwrap_or (false) {
+		let mut response =
+			ServerResponse::new (
+				response_sender,
+				head_only,
+				ResponseEncoding::negotiate (
+					compression_settings,
+					& request),
+				compression_settings.minimum_size);
 
-				return send_unauthenticated (
-					response);
+		if compression_settings.enabled {
 
-			}
+			response.vary (
+				"Accept-Encoding");
 
 		}
 
-		let uri =
-			request.uri.clone ();
+		let request_id =
+			request_id_for (
+				& request);
 
-		if let HyperRequestUri::AbsolutePath (path) =
-			uri {
+		response.header_set (
+			HyperHeaderName::from_static ("x-request-id"),
+			& request_id);
 
-			for & (ref route_regex, ref route_handler)
-			in ROUTES.iter () {
+		let client_address =
+			request.client_address ();
 
-				if let Some (route_captures) =
-					route_regex.captures (
-						& path) {
+		let method =
+			request.method ().to_string ();
 
-					return route_handler (
-						self.state.clone (),
-						self.upstream.clone (),
-						route_captures,
-						request,
-						response,
-					);
+		let uri =
+			request.uri ().to_string ();
+
+		let version =
+			format! (
+				"{:?}",
+				request.version ());
+
+		let referer =
+			request.header (
+				"Referer",
+			).map (|referer|
+				referer.to_owned ()
+			);
 
-				}
+		let user_agent =
+			request.header (
+				"User-Agent",
+			).map (|user_agent|
+				user_agent.to_owned ()
+			);
 
-			}
+		let identity =
+			self.authenticator.authenticate (
+				& request);
 
-			send_not_found (
-				response,
+		let identity_name =
+			identity.as_ref ().map (|identity|
+				identity.name ().to_owned ()
 			);
 
+		// requests in absolute form, as sent to a proxy, are not accepted
+
+		let (path, query) =
+			if request.uri ().scheme ().is_none ()
+				&& request.uri ().path ().starts_with ('/') {
+
+			(
+				Some (request.uri ().path ().to_owned ()),
+				request.uri ().query ().map (|query|
+					query.to_owned ()),
+			)
+
 		} else {
 
-			send_error (
-				response,
+			(
+				None,
+				None,
+			)
+
+		};
+
+		let route_match =
+			path.as_ref ().map (|path|
+				route_resolve (
+					request.method (),
+					path)
 			);
 
+		let route_name =
+			match route_match {
+				Some (RouteMatch::Found (route, _)) => route.name,
+				_ => "none",
+			};
+
+		// public routes, such as health checks, are never limited, and nor
+		// are requests which are refused anyway
+
+		let rate_limit =
+			match route_match {
+
+			Some (RouteMatch::Found (route, _))
+				if route.scope.is_some () && request_slot.is_some () =>
+				self.state.rate_limiter.acquire (
+					identity.as_ref (),
+					client_address,
+				).map (|keys|
+					Some (RateLimitPermit {
+						state: self.state.clone (),
+						keys,
+					})
+				),
+
+			_ =>
+				Ok (None),
+
+		};
+
+		let result =
+			match (path.as_ref (), route_match, rate_limit) {
+
+			_ if request_slot.is_none () =>
+				Err (
+					ApiError::ServiceUnavailable),
+
+			(_, _, Err (retry_after)) =>
+				Err (
+					ApiError::TooManyRequests (
+						retry_after)),
+
+			(Some (_), Some (route_match), Ok (_rate_limit_permit)) =>
+				self.handle_request (
+					request,
+					& mut response,
+					identity,
+					route_match,
+					query),
+
+			_ =>
+				Err (
+					ApiError::BadRequest (
+						"Request URI must be an absolute path".to_string ())),
+
+		};
+
+		if let Err (error) = result {
+
+			send_api_error (
+				path.as_deref ().unwrap_or (""),
+				error,
+				& mut response);
+
 		}
 
+		let duration =
+			start_time.elapsed ();
+
+		self.state.metrics.request_record (
+			route_name,
+			& method,
+			response.status ().as_u16 (),
+			duration);
+
+		self.access_log.log (
+			& AccessLogEntry {
+				request_id,
+				client_address,
+				identity_name,
+				method,
+				uri,
+				version,
+				status: response.status ().as_u16 (),
+				bytes: response.bytes_written (),
+				duration,
+				referer,
+				user_agent,
+			});
+
 	}
 
-}
+	fn handle_request (
+		& self,
+		request: ServerRequest,
+		response: & mut ServerResponse,
+		identity: Option <Identity>,
+		route_match: RouteMatch,
+		query: Option <String>,
+	) -> Result <(), ApiError> {
 
-pub fn send_unauthenticated (
-	mut response: HyperResponse,
-) {
+		match route_match {
 
-	* response.status_mut () =
-		HyperStatusCode::Unauthorized;
+			RouteMatch::Found (route, params) => {
 
-	{
+				if let Some (scope) = route.scope {
 
-		let headers =
-			response.headers_mut ();
+					let identity =
+						identity.as_ref ().ok_or (
+							ApiError::Unauthenticated,
+						) ?;
 
-		headers.set (
-			header::ContentType::plaintext ());
+					if ! identity.permissions ().has_scope (
+						scope) {
 
-	}
+						return Err (
+							ApiError::Forbidden);
 
-	response.send (
-		b"MUST AUTHENTICATE\n",
-	).unwrap ();
+					}
+
+				}
+
+				let context =
+					RouteContext {
+						settings: self.settings.clone (),
+						state: self.state.clone (),
+						upstream: self.upstream.clone (),
+						shared_inventory: self.inventory.clone (),
+						inventory: self.inventory.get (),
+						identity,
+						query: query.map (|query|
+							form_urlencoded::parse (
+								query.as_bytes (),
+							).into_owned ().collect ()
+						).unwrap_or_default (),
+						params,
+					};
+
+				(route.handler) (
+					& context,
+					request,
+					response,
+				)
+
+			},
+
+			RouteMatch::Options (allowed_methods) =>
+				send_options (
+					response,
+					allowed_methods),
+
+			RouteMatch::MethodNotAllowed (allowed_methods) =>
+				Err (
+					ApiError::MethodNotAllowed (
+						allowed_methods)),
+
+			RouteMatch::NotFound =>
+				Err (
+					ApiError::RouteNotFound),
+
+		}
+
+	}
 
 }
 
-pub fn send_not_found (
-	mut response: HyperResponse,
+fn send_api_error (
+	path: & str,
+	error: ApiError,
+	response: & mut ServerResponse,
 ) {
 
-	* response.status_mut () =
-		HyperStatusCode::NotFound;
+	if let ApiError::Internal (_) = error {
+
+		println! (
+			"Error handling {}: {}",
+			path,
+			error);
 
-	{
+	}
+
+	if response.is_started () {
 
-		let headers =
-			response.headers_mut ();
+		println! (
+			"Error after response started for {}: {}",
+			path,
+			error);
 
-		headers.set (
-			header::ContentType::plaintext ());
+		return;
 
 	}
 
-	response.send (
-		b"NOT FOUND\n",
-	).unwrap ();
+	error.send (
+		path,
+		response,
+	).unwrap_or_else (|error|
+		println! (
+			"Error sending error response for {}: {}",
+			path,
+			error)
+	);
 
 }
 
-pub fn send_error (
-	mut response: HyperResponse,
-) {
+pub fn send_options (
+	response: & mut ServerResponse,
+	allowed_methods: Vec <HyperMethod>,
+) -> Result <(), ApiError> {
 
 	* response.status_mut () =
-		HyperStatusCode::InternalServerError;
-
-	{
-
-		let headers =
-			response.headers_mut ();
+		HyperStatusCode::NO_CONTENT;
 
-		headers.set (
-			header::ContentType::plaintext ());
-
-	}
+	response.allow (
+		& allowed_methods);
 
 	response.send (
-		b"ERROR\n",
-	).unwrap ();
+		b"",
+	) ?;
+
+	Ok (())
 
 }
 
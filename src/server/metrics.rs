@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+use crate::server::*;
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const DURATION_BUCKETS: [f64; 11] = [
+	0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[ derive (Clone, PartialEq, Eq, PartialOrd, Ord, Hash) ]
+struct RequestMetricsKey {
+	route: & 'static str,
+	method: & 'static str,
+	status: u16,
+}
+
+#[ derive (Default) ]
+struct RequestMetrics {
+	count: u64,
+	duration_sum: f64,
+	duration_buckets: [u64; 11],
+}
+
+/// Counters collected by the server itself, as opposed to those derived on
+/// demand from the upstream and inventory state.
+pub struct ServerMetrics {
+	shards: Vec <Mutex <BTreeMap <RequestMetricsKey, RequestMetrics>>>,
+}
+
+impl ServerMetrics {
+
+	pub fn new (
+	) -> ServerMetrics {
+
+		ServerMetrics {
+			shards: state_shards_new (BTreeMap::new),
+		}
+
+	}
+
+	pub fn request_record (
+		& self,
+		route: & 'static str,
+		method: & str,
+		status: u16,
+		duration: Duration,
+	) {
+
+		// hyper accepts any method, so others are counted together to keep
+		// the number of series bounded
+
+		let method =
+			match method {
+				"GET" => "GET",
+				"HEAD" => "HEAD",
+				"POST" => "POST",
+				"PUT" => "PUT",
+				"DELETE" => "DELETE",
+				"OPTIONS" => "OPTIONS",
+				_ => "other",
+			};
+
+		let key =
+			RequestMetricsKey {
+				route,
+				method,
+				status,
+			};
+
+		let mut shard =
+			self.shards [state_shard_index (& key)].lock ().unwrap ();
+
+		let request_metrics =
+			shard.entry (
+				key,
+			).or_default ();
+
+		let duration_seconds =
+			duration_seconds (duration);
+
+		request_metrics.count += 1;
+		request_metrics.duration_sum += duration_seconds;
+
+		for (bucket_index, bucket_limit)
+		in DURATION_BUCKETS.iter ().enumerate () {
+
+			if duration_seconds <= * bucket_limit {
+				request_metrics.duration_buckets [bucket_index] += 1;
+			}
+
+		}
+
+	}
+
+	pub fn write_prometheus (
+		& self,
+		target: & mut dyn Write,
+	) -> io::Result <()> {
+
+		// each key is only ever in one shard, so they can simply be merged
+
+		let shards: Vec <MutexGuard <BTreeMap <RequestMetricsKey, RequestMetrics>>> =
+			self.shards.iter ().map (|shard|
+				shard.lock ().unwrap ()
+			).collect ();
+
+		let requests: BTreeMap <& RequestMetricsKey, & RequestMetrics> =
+			shards.iter ().flat_map (|shard|
+				shard.iter ()
+			).collect ();
+
+		prometheus_header (
+			target,
+			"gridlinker_http_requests_total",
+			"counter",
+			"Requests handled, by route, method and status",
+		) ?;
+
+		for (key, request_metrics) in requests.iter () {
+
+			writeln! (
+				target,
+				"gridlinker_http_requests_total{} {}",
+				Self::labels (key, None),
+				request_metrics.count,
+			) ?;
+
+		}
+
+		prometheus_header (
+			target,
+			"gridlinker_http_request_duration_seconds",
+			"histogram",
+			"Time taken to handle requests, by route, method and status",
+		) ?;
+
+		for (key, request_metrics) in requests.iter () {
+
+			for (bucket_index, bucket_limit)
+			in DURATION_BUCKETS.iter ().enumerate () {
+
+				writeln! (
+					target,
+					"gridlinker_http_request_duration_seconds_bucket{} {}",
+					Self::labels (key, Some (& bucket_limit.to_string ())),
+					request_metrics.duration_buckets [bucket_index],
+				) ?;
+
+			}
+
+			write! (
+				target,
+				"gridlinker_http_request_duration_seconds_bucket{} {}\n\
+				gridlinker_http_request_duration_seconds_sum{} {}\n\
+				gridlinker_http_request_duration_seconds_count{} {}\n",
+				Self::labels (key, Some ("+Inf")),
+				request_metrics.count,
+				Self::labels (key, None),
+				request_metrics.duration_sum,
+				Self::labels (key, None),
+				request_metrics.count,
+			) ?;
+
+		}
+
+		Ok (())
+
+	}
+
+	fn labels (
+		key: & RequestMetricsKey,
+		bucket: Option <& str>,
+	) -> String {
+
+		let status =
+			key.status.to_string ();
+
+		let mut labels: Vec <(& str, & str)> = vec! [
+			("route", key.route),
+			("method", key.method),
+			("status", & status),
+		];
+
+		if let Some (bucket) = bucket {
+			labels.push (("le", bucket));
+		}
+
+		prometheus_labels (
+			& labels)
+
+	}
+
+}
+
+pub fn prometheus_header (
+	target: & mut dyn Write,
+	name: & str,
+	metric_type: & str,
+	help: & str,
+) -> io::Result <()> {
+
+	write! (
+		target,
+		"# HELP {} {}\n# TYPE {} {}\n",
+		name,
+		help,
+		name,
+		metric_type)
+
+}
+
+pub fn prometheus_labels (
+	labels: & [(& str, & str)],
+) -> String {
+
+	let labels: Vec <String> =
+		labels.iter ().map (|& (name, value)|
+			format! (
+				"{}=\"{}\"",
+				name,
+				value
+					.replace ("\\", "\\\\")
+					.replace ("\"", "\\\"")
+					.replace ("\n", "\\n"))
+		).collect ();
+
+	format! (
+		"{{{}}}",
+		labels.join (","))
+
+}
+
+pub fn duration_seconds (
+	duration: Duration,
+) -> f64 {
+
+	duration.as_secs () as f64
+		+ duration.subsec_nanos () as f64 / 1000000000.0
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,8 +1,25 @@
+mod access_log;
+mod compression;
+mod error;
+mod format;
 mod handler;
+mod metrics;
+mod rate_limit;
+mod request;
+mod response;
+#[ allow (clippy::module_inception) ]
 mod server;
 mod state;
 
+pub use self::access_log::*;
+pub use self::compression::*;
+pub use self::error::*;
+pub use self::format::*;
 pub use self::handler::*;
+pub use self::metrics::*;
+pub use self::rate_limit::*;
+pub use self::request::*;
+pub use self::response::*;
 pub use self::server::*;
 pub use self::state::*;
 
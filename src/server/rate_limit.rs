@@ -0,0 +1,579 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::auth::*;
+use crate::server::*;
+use crate::settings::*;
+
+/// How often idle buckets are discarded, so that the state doesn't grow with
+/// every client address ever seen.
+const PRUNE_INTERVAL_SECS: u64 = 60;
+
+const REJECTION_KINDS: [& str; 2] = [ "address", "token" ];
+const REJECTION_REASONS: [& str; 2] = [ "concurrency", "rate" ];
+
+/// Identities are keyed by where they came from as well as by name, since a
+/// token may share its name with a certificate or peer identity.
+#[ derive (Clone, Debug, PartialEq, Eq, Hash) ]
+pub enum RateLimitKey {
+	Token (IdentitySource, String),
+	Address (ClientAddress),
+}
+
+impl RateLimitKey {
+
+	fn kind (
+		& self,
+	) -> & 'static str {
+
+		match * self {
+			RateLimitKey::Token (_, _) => "token",
+			RateLimitKey::Address (_) => "address",
+		}
+
+	}
+
+}
+
+struct RateLimitBucket {
+	tokens: f64,
+	updated: Instant,
+	concurrent: u64,
+}
+
+struct RateLimitShard {
+	buckets: HashMap <RateLimitKey, RateLimitBucket>,
+	last_prune: Instant,
+}
+
+/// Token buckets and concurrency counts, per token and per client address. A
+/// request is rejected without taking from any bucket if one is exhausted.
+pub struct RateLimiter {
+	settings: RateLimitsSettings,
+	shards: Vec <Mutex <RateLimitShard>>,
+	rejected: BTreeMap <(& 'static str, & 'static str), AtomicU64>,
+}
+
+impl RateLimiter {
+
+	pub fn new (
+		settings: & RateLimitsSettings,
+	) -> RateLimiter {
+
+		let now =
+			Instant::now ();
+
+		RateLimiter {
+			settings: settings.clone (),
+			shards: state_shards_new (||
+				RateLimitShard {
+					buckets: HashMap::new (),
+					last_prune: now,
+				}
+			),
+			rejected: REJECTION_KINDS.iter ().flat_map (|& kind|
+				REJECTION_REASONS.iter ().map (move |& reason|
+					((kind, reason), AtomicU64::new (0)))
+			).collect (),
+		}
+
+	}
+
+	fn settings_for (
+		& self,
+		key: & RateLimitKey,
+	) -> Option <& RateLimitSettings> {
+
+		match * key {
+			RateLimitKey::Token (_, _) => self.settings.per_token.as_ref (),
+			RateLimitKey::Address (_) => self.settings.per_address.as_ref (),
+		}
+
+	}
+
+	/// Admits a request, returning the keys which must be released when it
+	/// completes, or the number of seconds after which to retry.
+	pub fn acquire (
+		& self,
+		identity: Option <& Identity>,
+		address: ClientAddress,
+	) -> Result <Vec <RateLimitKey>, u64> {
+
+		let now =
+			Instant::now ();
+
+		let mut keys: Vec <RateLimitKey> =
+			Vec::new ();
+
+		if let (Some (identity), Some (_)) =
+			(identity, self.settings.per_token.as_ref ()) {
+
+			keys.push (
+				RateLimitKey::Token (
+					identity.source (),
+					identity.name ().to_owned ()));
+
+		}
+
+		if self.settings.per_address.is_some () {
+
+			keys.push (
+				RateLimitKey::Address (
+					address));
+
+		}
+
+		// every shard involved is locked for the whole check, in index order
+		// so that concurrent requests can't deadlock
+
+		let mut shard_indexes: Vec <usize> =
+			keys.iter ().map (state_shard_index).collect ();
+
+		shard_indexes.sort ();
+		shard_indexes.dedup ();
+
+		let mut shards: Vec <(usize, MutexGuard <RateLimitShard>)> =
+			shard_indexes.into_iter ().map (|shard_index|
+				(shard_index, self.shards [shard_index].lock ().unwrap ())
+			).collect ();
+
+		for & mut (_, ref mut shard) in shards.iter_mut () {
+
+			self.prune (
+				shard,
+				now);
+
+		}
+
+		// check every bucket before taking from any, so that a rejection
+		// doesn't count against the others
+
+		for key in keys.iter () {
+
+			let settings =
+				self.settings_for (key).unwrap ().clone ();
+
+			let bucket =
+				Self::shard_for (
+					& mut shards,
+					key,
+				).buckets.entry (
+					key.clone (),
+				).or_insert_with (||
+					RateLimitBucket {
+						tokens: settings.burst as f64,
+						updated: now,
+						concurrent: 0,
+					}
+				);
+
+			Self::refill (
+				bucket,
+				& settings,
+				now);
+
+			let rejection =
+				if settings.max_concurrent.map (|max_concurrent|
+					bucket.concurrent >= max_concurrent
+				).unwrap_or (false) {
+
+					Some (("concurrency", 1))
+
+				} else if bucket.tokens < 1.0 {
+
+					Some ((
+						"rate",
+						((1.0 - bucket.tokens) / settings.rate).ceil () as u64,
+					))
+
+				} else {
+
+					None
+
+				};
+
+			if let Some ((reason, retry_after)) = rejection {
+
+				self.rejected [& (key.kind (), reason)].fetch_add (
+					1,
+					Ordering::Relaxed);
+
+				return Err (
+					retry_after.max (1));
+
+			}
+
+		}
+
+		for key in keys.iter () {
+
+			let bucket =
+				Self::shard_for (
+					& mut shards,
+					key,
+				).buckets.get_mut (key).unwrap ();
+
+			bucket.tokens -= 1.0;
+			bucket.concurrent += 1;
+
+		}
+
+		Ok (keys)
+
+	}
+
+	fn shard_for <'a, 'b> (
+		shards: & 'a mut [(usize, MutexGuard <'b, RateLimitShard>)],
+		key: & RateLimitKey,
+	) -> & 'a mut RateLimitShard {
+
+		let shard_index =
+			state_shard_index (key);
+
+		shards.iter_mut ().find (|& & mut (index, _)|
+			index == shard_index
+		).map (|& mut (_, ref mut shard)|
+			& mut ** shard
+		).unwrap ()
+
+	}
+
+	pub fn release (
+		& self,
+		keys: & [RateLimitKey],
+	) {
+
+		for key in keys {
+
+			// this runs when a handler panics, so a poisoned lock is expected
+
+			let mut shard =
+				self.shards [state_shard_index (key)].lock ().unwrap_or_else (
+					|error| error.into_inner ());
+
+			if let Some (bucket) = shard.buckets.get_mut (key) {
+				bucket.concurrent -= 1;
+			}
+
+		}
+
+	}
+
+	fn refill (
+		bucket: & mut RateLimitBucket,
+		settings: & RateLimitSettings,
+		now: Instant,
+	) {
+
+		bucket.tokens = (
+			bucket.tokens
+				+ duration_seconds (now - bucket.updated) * settings.rate
+		).min (settings.burst as f64);
+
+		bucket.updated = now;
+
+	}
+
+	fn prune (
+		& self,
+		shard: & mut RateLimitShard,
+		now: Instant,
+	) {
+
+		if now - shard.last_prune < Duration::from_secs (PRUNE_INTERVAL_SECS) {
+			return;
+		}
+
+		shard.buckets.retain (|key, bucket| {
+
+			match self.settings_for (key) {
+
+				Some (bucket_settings) => {
+
+					Self::refill (
+						bucket,
+						bucket_settings,
+						now);
+
+					bucket.concurrent > 0
+						|| bucket.tokens < bucket_settings.burst as f64
+
+				},
+
+				None => false,
+
+			}
+
+		});
+
+		shard.last_prune = now;
+
+	}
+
+	pub fn write_prometheus (
+		& self,
+		target: & mut dyn Write,
+	) -> io::Result <()> {
+
+		let mut buckets: BTreeMap <& 'static str, (u64, u64)> =
+			BTreeMap::new ();
+
+		for shard in self.shards.iter () {
+
+			let shard =
+				shard.lock ().unwrap ();
+
+			for (key, bucket) in shard.buckets.iter () {
+
+				let entry =
+					buckets.entry (
+						key.kind (),
+					).or_insert ((0, 0));
+
+				entry.0 += 1;
+				entry.1 += bucket.concurrent;
+
+			}
+
+		}
+
+		prometheus_header (
+			target,
+			"gridlinker_rate_limit_buckets",
+			"gauge",
+			"Rate limit buckets currently tracked, by kind",
+		) ?;
+
+		for (kind, & (count, _)) in buckets.iter () {
+
+			writeln! (
+				target,
+				"gridlinker_rate_limit_buckets{} {}",
+				prometheus_labels (& [ ("kind", kind) ]),
+				count,
+			) ?;
+
+		}
+
+		prometheus_header (
+			target,
+			"gridlinker_rate_limit_concurrent_requests",
+			"gauge",
+			"Requests in progress which count against a limit, by kind",
+		) ?;
+
+		for (kind, & (_, concurrent)) in buckets.iter () {
+
+			writeln! (
+				target,
+				"gridlinker_rate_limit_concurrent_requests{} {}",
+				prometheus_labels (& [ ("kind", kind) ]),
+				concurrent,
+			) ?;
+
+		}
+
+		prometheus_header (
+			target,
+			"gridlinker_rate_limit_rejected_total",
+			"counter",
+			"Requests rejected by a limit, by kind and reason",
+		) ?;
+
+		for (& (kind, reason), count) in self.rejected.iter () {
+
+			writeln! (
+				target,
+				"gridlinker_rate_limit_rejected_total{} {}",
+				prometheus_labels (& [ ("kind", kind), ("reason", reason) ]),
+				count.load (Ordering::Relaxed),
+			) ?;
+
+		}
+
+		Ok (())
+
+	}
+
+}
+
+/// Releases a request's concurrency slots when it completes, including when
+/// its handler panics.
+pub struct RateLimitPermit {
+	pub state: Arc <ServerState>,
+	pub keys: Vec <RateLimitKey>,
+}
+
+impl Drop for RateLimitPermit {
+
+	fn drop (
+		& mut self,
+	) {
+
+		self.state.rate_limiter.release (
+			& self.keys);
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::net::IpAddr;
+	use std::net::Ipv4Addr;
+
+	use super::*;
+
+	const ADDRESS: ClientAddress =
+		ClientAddress::Remote (IpAddr::V4 (Ipv4Addr::LOCALHOST));
+
+	fn limit (
+		burst: u64,
+		max_concurrent: Option <u64>,
+	) -> Option <RateLimitSettings> {
+
+		Some (RateLimitSettings {
+			rate: 0.5,
+			burst,
+			max_concurrent,
+		})
+
+	}
+
+	fn identity (
+		source: IdentitySource,
+	) -> Identity {
+
+		Identity::new (
+			"client".to_owned (),
+			source,
+			Arc::new (Permissions::full ()))
+
+	}
+
+	#[ test ]
+	fn requests_are_rejected_once_the_burst_is_used () {
+
+		let rate_limiter =
+			RateLimiter::new (& RateLimitsSettings {
+				per_token: None,
+				per_address: limit (2, None),
+			});
+
+		for _ in 0 .. 2 {
+
+			let keys =
+				rate_limiter.acquire (None, ADDRESS).unwrap ();
+
+			rate_limiter.release (& keys);
+
+		}
+
+		// at half a token a second, the next is two seconds away
+
+		assert_eq! (
+			rate_limiter.acquire (None, ADDRESS),
+			Err (2));
+
+		assert_eq! (
+			rate_limiter.rejected [& ("address", "rate")].load (Ordering::Relaxed),
+			1);
+
+		// other addresses have their own bucket
+
+		rate_limiter.acquire (
+			None,
+			ClientAddress::Local (1000),
+		).unwrap ();
+
+	}
+
+	#[ test ]
+	fn concurrent_requests_are_limited_until_released () {
+
+		let rate_limiter =
+			RateLimiter::new (& RateLimitsSettings {
+				per_token: None,
+				per_address: limit (10, Some (1)),
+			});
+
+		let keys =
+			rate_limiter.acquire (None, ADDRESS).unwrap ();
+
+		assert_eq! (
+			rate_limiter.acquire (None, ADDRESS),
+			Err (1));
+
+		rate_limiter.release (& keys);
+
+		rate_limiter.acquire (None, ADDRESS).unwrap ();
+
+	}
+
+	#[ test ]
+	fn a_rejection_takes_nothing_from_the_other_buckets () {
+
+		let rate_limiter =
+			RateLimiter::new (& RateLimitsSettings {
+				per_token: limit (1, None),
+				per_address: limit (2, None),
+			});
+
+		let token =
+			identity (IdentitySource::BearerToken);
+
+		rate_limiter.acquire (Some (& token), ADDRESS).unwrap ();
+
+		assert! (rate_limiter.acquire (Some (& token), ADDRESS).is_err ());
+
+		// the address bucket still has its second token
+
+		rate_limiter.acquire (None, ADDRESS).unwrap ();
+
+		assert! (rate_limiter.acquire (None, ADDRESS).is_err ());
+
+	}
+
+	#[ test ]
+	fn identities_with_the_same_name_have_separate_buckets () {
+
+		let rate_limiter =
+			RateLimiter::new (& RateLimitsSettings {
+				per_token: limit (1, None),
+				per_address: None,
+			});
+
+		for source in [
+			IdentitySource::BearerToken,
+			IdentitySource::ClientCertificate,
+			IdentitySource::PeerCredentials,
+		] {
+
+			rate_limiter.acquire (
+				Some (& identity (source)),
+				ADDRESS,
+			).unwrap ();
+
+		}
+
+		assert! (
+			rate_limiter.acquire (
+				Some (& identity (IdentitySource::BearerToken)),
+				ADDRESS,
+			).is_err ());
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
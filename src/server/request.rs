@@ -0,0 +1,221 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use http_body_util::BodyExt;
+use http_body_util::LengthLimitError;
+use http_body_util::Limited;
+
+use hyper::HeaderMap as HyperHeaderMap;
+use hyper::Method as HyperMethod;
+use hyper::Request as HyperRequest;
+use hyper::Uri as HyperUri;
+use hyper::Version as HyperVersion;
+use hyper::body::Incoming as HyperIncoming;
+
+use tokio::runtime::Handle as RuntimeHandle;
+
+use crate::server::*;
+use crate::unix_socket::*;
+
+/// Where a request came from, for rate limiting and logging. Unix socket
+/// clients have no address, so they are told apart by user id instead.
+#[ derive (Clone, Copy, Debug, PartialEq, Eq, Hash) ]
+pub enum ClientAddress {
+	Remote (IpAddr),
+	Local (libc::uid_t),
+}
+
+impl fmt::Display for ClientAddress {
+
+	fn fmt (
+		& self,
+		formatter: & mut fmt::Formatter,
+	) -> fmt::Result {
+
+		match * self {
+
+			ClientAddress::Remote (address) =>
+				write! (formatter, "{}", address),
+
+			ClientAddress::Local (uid) =>
+				write! (formatter, "uid:{}", uid),
+
+		}
+
+	}
+
+}
+
+/// What is known about a connection once it has been accepted, and any TLS
+/// handshake has completed. This is shared by every request on it.
+#[ derive (Clone) ]
+pub struct ServerConnection {
+	pub client_address: ClientAddress,
+	pub peer_certificate_names: Vec <String>,
+	pub peer_credentials: Option <PeerCredentials>,
+}
+
+/// A request, as passed to route handlers. Handlers run on the blocking pool
+/// rather than the runtime, so the body is read by blocking on the runtime,
+/// and only if the handler asks for it.
+pub struct ServerRequest {
+	method: HyperMethod,
+	uri: HyperUri,
+	version: HyperVersion,
+	headers: HyperHeaderMap,
+	client_address: ClientAddress,
+	peer_certificate_names: Vec <String>,
+	peer_credentials: Option <PeerCredentials>,
+	body: Option <HyperIncoming>,
+	runtime: RuntimeHandle,
+}
+
+impl ServerRequest {
+
+	pub fn new (
+		hyper_request: HyperRequest <HyperIncoming>,
+		connection: & ServerConnection,
+		runtime: RuntimeHandle,
+	) -> ServerRequest {
+
+		let (parts, body) =
+			hyper_request.into_parts ();
+
+		ServerRequest {
+			method: parts.method,
+			uri: parts.uri,
+			version: parts.version,
+			headers: parts.headers,
+			client_address: connection.client_address,
+			peer_certificate_names: connection.peer_certificate_names.clone (),
+			peer_credentials: connection.peer_credentials,
+			body: Some (body),
+			runtime,
+		}
+
+	}
+
+	property_accessors! {
+		ref method: & HyperMethod;
+		ref uri: & HyperUri;
+		copy version: HyperVersion;
+		ref headers: & HyperHeaderMap;
+		copy client_address: ClientAddress;
+		ref peer_certificate_names: & [String];
+		copy peer_credentials: Option <PeerCredentials>;
+	}
+
+	/// The first value of a header, if it is present and valid text.
+	pub fn header (
+		& self,
+		name: & str,
+	) -> Option <& str> {
+
+		self.headers.get (
+			name,
+		).and_then (|value|
+			value.to_str ().ok ()
+		)
+
+	}
+
+	/// Parses a header such as Accept, giving lower cased values with their
+	/// quality in thousandths. Values with an invalid quality are left out.
+	pub fn header_quality_list (
+		& self,
+		name: & str,
+	) -> Vec <(String, u16)> {
+
+		self.headers.get_all (
+			name,
+		).iter ().filter_map (|value|
+			value.to_str ().ok ()
+		).flat_map (|value|
+			value.split (',')
+		).filter_map (|item| {
+
+			let mut item_parts =
+				item.split (';');
+
+			let value =
+				item_parts.next ().unwrap_or ("").trim ().to_lowercase ();
+
+			if value.is_empty () {
+				return None;
+			}
+
+			let mut quality: u16 = 1000;
+
+			for param in item_parts {
+
+				let param =
+					param.trim ();
+
+				if param.starts_with ("q=") || param.starts_with ("Q=") {
+
+					let quality_float: f32 =
+						param [2 .. ].parse ().ok () ?;
+
+					if ! (0.0 ..= 1.0).contains (& quality_float) {
+						return None;
+					}
+
+					quality =
+						(quality_float * 1000.0).round () as u16;
+
+				}
+
+			}
+
+			Some ((value, quality))
+
+		}).collect ()
+
+	}
+
+	/// Reads the whole body, refusing one larger than the limit. This can
+	/// only be done once.
+	pub fn read_body (
+		& mut self,
+		limit: u64,
+	) -> Result <Vec <u8>, ApiError> {
+
+		let body =
+			self.body.take ().ok_or_else (||
+				ApiError::Internal (
+					"Request body already read".to_string ())
+			) ?;
+
+		let collected =
+			self.runtime.block_on (
+				Limited::new (
+					body,
+					limit as usize,
+				).collect ());
+
+		match collected {
+
+			Ok (collected) =>
+				Ok (collected.to_bytes ().to_vec ()),
+
+			Err (ref error) if error.is::<LengthLimitError> () =>
+				Err (
+					ApiError::BadRequest (
+						format! (
+							"Request body must not exceed {} bytes",
+							limit))),
+
+			Err (error) =>
+				Err (
+					ApiError::BadRequest (
+						format! (
+							"Error reading request body: {}",
+							error))),
+
+		}
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
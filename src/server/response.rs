@@ -0,0 +1,755 @@
+use std::cell::Cell;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::io::Write;
+use std::mem;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::Context;
+use std::task::Poll;
+
+use bytes::Bytes;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use flate2::write::ZlibEncoder;
+
+use hyper::HeaderMap as HyperHeaderMap;
+use hyper::Method as HyperMethod;
+use hyper::Response as HyperResponse;
+use hyper::StatusCode as HyperStatusCode;
+use hyper::body::Body as HyperBody;
+use hyper::body::Frame as HyperFrame;
+use hyper::header;
+use hyper::header::HeaderName as HyperHeaderName;
+use hyper::header::HeaderValue as HyperHeaderValue;
+
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+
+use crate::server::*;
+
+/// The channel is bounded, so that a slow client holds up the handler rather
+/// than using unbounded memory. A None marks the end of the body.
+const BODY_CHUNK_SIZE: usize = 8192;
+const BODY_CHANNEL_SIZE: usize = 16;
+
+pub type ServerResponseSender =
+	oneshot::Sender <HyperResponse <ServerBody>>;
+
+/// Collects a response from a route handler. For HEAD, the body is counted
+/// but discarded, and the headers are sent as they would be for GET. Bodies
+/// below the minimum size are sent uncompressed, with a content length.
+pub struct ServerResponse {
+	pending: Option <PendingResponse>,
+	head_only: bool,
+	encoding: Option <ResponseEncoding>,
+	minimum_size: u64,
+	status: HyperStatusCode,
+	bytes_written: Rc <Cell <u64>>,
+}
+
+/// The status and headers, before they are sent.
+struct PendingResponse {
+	status: HyperStatusCode,
+	headers: HyperHeaderMap,
+	sender: ServerResponseSender,
+}
+
+impl ServerResponse {
+
+	pub fn new (
+		sender: ServerResponseSender,
+		head_only: bool,
+		encoding: Option <ResponseEncoding>,
+		minimum_size: u64,
+	) -> ServerResponse {
+
+		ServerResponse {
+			pending: Some (PendingResponse {
+				status: HyperStatusCode::OK,
+				headers: HyperHeaderMap::new (),
+				sender,
+			}),
+			head_only,
+			encoding,
+			minimum_size,
+			status: HyperStatusCode::OK,
+			bytes_written: Rc::new (Cell::new (0)),
+		}
+
+	}
+
+	/// The status which has been, or will be, sent.
+	pub fn status (
+		& self,
+	) -> HyperStatusCode {
+
+		match self.pending {
+			Some (ref pending) => pending.status,
+			None => self.status,
+		}
+
+	}
+
+	/// The number of body bytes actually sent so far.
+	pub fn bytes_written (
+		& self,
+	) -> u64 {
+
+		self.bytes_written.get ()
+
+	}
+
+	pub fn is_started (
+		& self,
+	) -> bool {
+
+		self.pending.is_none ()
+
+	}
+
+	pub fn status_mut (
+		& mut self,
+	) -> & mut HyperStatusCode {
+
+		& mut self.pending.as_mut ().expect (
+			"Response already started",
+		).status
+
+	}
+
+	pub fn headers_mut (
+		& mut self,
+	) -> & mut HyperHeaderMap {
+
+		& mut self.pending.as_mut ().expect (
+			"Response already started",
+		).headers
+
+	}
+
+	/// Sets a header, replacing any previous value. A value which isn't
+	/// valid in a header is left out rather than sent.
+	pub fn header_set (
+		& mut self,
+		header_name: HyperHeaderName,
+		value: & str,
+	) {
+
+		if let Ok (value) = HyperHeaderValue::from_str (value) {
+
+			self.headers_mut ().insert (
+				header_name,
+				value);
+
+		}
+
+	}
+
+	/// Adds a request header name to Vary, keeping any already present.
+	pub fn vary (
+		& mut self,
+		header_name: & 'static str,
+	) {
+
+		self.headers_mut ().append (
+			header::VARY,
+			HyperHeaderValue::from_static (
+				header_name));
+
+	}
+
+	/// Sets the Allow header to the given methods.
+	pub fn allow (
+		& mut self,
+		allowed_methods: & [HyperMethod],
+	) {
+
+		let allowed_methods =
+			allowed_methods.iter ().map (|method|
+				method.as_str ()
+			).collect::<Vec <& str>> ().join (", ");
+
+		self.header_set (
+			header::ALLOW,
+			& allowed_methods);
+
+	}
+
+	pub fn send (
+		& mut self,
+		body: & [u8],
+	) -> Result <(), IoError> {
+
+		let mut writer =
+			self.start () ?;
+
+		writer.write_all (
+			body) ?;
+
+		writer.end ()
+
+	}
+
+	pub fn start (
+		& mut self,
+	) -> Result <ServerResponseWriter, IoError> {
+
+		let pending =
+			self.pending.take ().ok_or_else (||
+				IoError::other (
+					"Response already started")
+			) ?;
+
+		self.status =
+			pending.status;
+
+		let compressible =
+			self.status != HyperStatusCode::NO_CONTENT
+			&& self.status != HyperStatusCode::NOT_MODIFIED
+			&& ! pending.headers.contains_key (header::CONTENT_ENCODING);
+
+		let state =
+			match (self.head_only, self.encoding) {
+
+			(true, encoding) =>
+				WriterState::Head (
+					pending,
+					encoding.filter (|_| compressible)),
+
+			(false, Some (encoding)) if compressible =>
+				WriterState::Buffering (
+					pending,
+					encoding,
+					Vec::new ()),
+
+			(false, _) =>
+				WriterState::Plain (
+					pending.start (
+						self.bytes_written.clone (),
+					) ?),
+
+		};
+
+		Ok (ServerResponseWriter {
+			state,
+			minimum_size: self.minimum_size,
+			length: 0,
+			bytes_written: self.bytes_written.clone (),
+		})
+
+	}
+
+}
+
+impl PendingResponse {
+
+	/// Hands the status and headers to the connection, returning a writer
+	/// for the body.
+	fn start (
+		self,
+		bytes_written: Rc <Cell <u64>>,
+	) -> Result <BodyWriter, IoError> {
+
+		let (chunk_sender, chunk_receiver) =
+			mpsc::channel (
+				BODY_CHANNEL_SIZE);
+
+		let mut hyper_response =
+			HyperResponse::new (
+				ServerBody {
+					receiver: chunk_receiver,
+					ended: false,
+				});
+
+		* hyper_response.status_mut () =
+			self.status;
+
+		* hyper_response.headers_mut () =
+			self.headers;
+
+		self.sender.send (
+			hyper_response,
+		).map_err (|_|
+			IoError::new (
+				IoErrorKind::BrokenPipe,
+				"Connection closed")
+		) ?;
+
+		Ok (BodyWriter {
+			sender: chunk_sender,
+			buffer: Vec::with_capacity (BODY_CHUNK_SIZE),
+			bytes_written,
+		})
+
+	}
+
+}
+
+pub struct ServerResponseWriter {
+	state: WriterState,
+	minimum_size: u64,
+	length: u64,
+	bytes_written: Rc <Cell <u64>>,
+}
+
+enum WriterState {
+	Head (PendingResponse, Option <ResponseEncoding>),
+	Buffering (PendingResponse, ResponseEncoding, Vec <u8>),
+	Plain (BodyWriter),
+	Gzip (GzEncoder <BodyWriter>),
+	Deflate (ZlibEncoder <BodyWriter>),
+	Finished,
+}
+
+/// Passes the body to the connection in chunks, and counts the bytes
+/// actually sent, after any compression.
+struct BodyWriter {
+	sender: mpsc::Sender <Option <Bytes>>,
+	buffer: Vec <u8>,
+	bytes_written: Rc <Cell <u64>>,
+}
+
+impl ServerResponseWriter {
+
+	/// Completes the response. A writer which is dropped instead, such as
+	/// when a handler fails part way, aborts the response.
+	pub fn end (
+		mut self,
+	) -> Result <(), IoError> {
+
+		self.finish ()
+
+	}
+
+	fn compress_start (
+		& mut self,
+	) -> Result <(), IoError> {
+
+		let (mut pending, encoding, body) =
+			match mem::replace (& mut self.state, WriterState::Finished) {
+
+			WriterState::Buffering (pending, encoding, body) =>
+				(pending, encoding, body),
+
+			state => {
+				self.state = state;
+				return Ok (());
+			},
+
+		};
+
+		pending.headers.remove (
+			header::CONTENT_LENGTH);
+
+		pending.headers.insert (
+			header::CONTENT_ENCODING,
+			HyperHeaderValue::from_static (
+				encoding.header_value ()));
+
+		let writer =
+			pending.start (
+				self.bytes_written.clone (),
+			) ?;
+
+		match encoding {
+
+			ResponseEncoding::Gzip => {
+
+				let mut encoder =
+					GzEncoder::new (
+						writer,
+						Compression::default ());
+
+				encoder.write_all (
+					& body) ?;
+
+				self.state =
+					WriterState::Gzip (encoder);
+
+			},
+
+			ResponseEncoding::Deflate => {
+
+				let mut encoder =
+					ZlibEncoder::new (
+						writer,
+						Compression::default ());
+
+				encoder.write_all (
+					& body) ?;
+
+				self.state =
+					WriterState::Deflate (encoder);
+
+			},
+
+		}
+
+		Ok (())
+
+	}
+
+	fn finish (
+		& mut self,
+	) -> Result <(), IoError> {
+
+		match mem::replace (& mut self.state, WriterState::Finished) {
+
+			WriterState::Head (mut pending, encoding) => {
+
+				// a compressed body is streamed, so its length isn't known
+
+				match encoding {
+
+					Some (encoding) if self.length >= self.minimum_size => {
+
+						pending.headers.remove (
+							header::CONTENT_LENGTH);
+
+						pending.headers.insert (
+							header::CONTENT_ENCODING,
+							HyperHeaderValue::from_static (
+								encoding.header_value ()));
+
+					},
+
+					_ => {
+
+						pending.headers.insert (
+							header::CONTENT_LENGTH,
+							HyperHeaderValue::from (self.length));
+
+					},
+
+				}
+
+				pending.start (
+					self.bytes_written.clone (),
+				) ?.end () ?;
+
+			},
+
+			WriterState::Buffering (mut pending, _, body) => {
+
+				pending.headers.insert (
+					header::CONTENT_LENGTH,
+					HyperHeaderValue::from (body.len () as u64));
+
+				let mut writer =
+					pending.start (
+						self.bytes_written.clone (),
+					) ?;
+
+				writer.write_all (
+					& body) ?;
+
+				writer.end () ?;
+
+			},
+
+			WriterState::Plain (writer) =>
+				writer.end () ?,
+
+			WriterState::Gzip (encoder) =>
+				encoder.finish () ?.end () ?,
+
+			WriterState::Deflate (encoder) =>
+				encoder.finish () ?.end () ?,
+
+			WriterState::Finished =>
+				(),
+
+		}
+
+		Ok (())
+
+	}
+
+}
+
+impl Write for ServerResponseWriter {
+
+	fn write (
+		& mut self,
+		buffer: & [u8],
+	) -> Result <usize, IoError> {
+
+		let written =
+			match self.state {
+
+			WriterState::Head (_, _) =>
+				buffer.len (),
+
+			WriterState::Buffering (_, _, ref mut body) => {
+
+				body.extend_from_slice (
+					buffer);
+
+				buffer.len ()
+
+			},
+
+			WriterState::Plain (ref mut writer) =>
+				writer.write (buffer) ?,
+
+			WriterState::Gzip (ref mut encoder) =>
+				encoder.write (buffer) ?,
+
+			WriterState::Deflate (ref mut encoder) =>
+				encoder.write (buffer) ?,
+
+			WriterState::Finished =>
+				return Err (
+					IoError::other (
+						"Response already finished")),
+
+		};
+
+		self.length += written as u64;
+
+		let buffer_full =
+			match self.state {
+				WriterState::Buffering (_, _, ref body) =>
+					body.len () as u64 >= self.minimum_size,
+				_ => false,
+			};
+
+		if buffer_full {
+			self.compress_start () ?;
+		}
+
+		Ok (written)
+
+	}
+
+	fn flush (
+		& mut self,
+	) -> Result <(), IoError> {
+
+		match self.state {
+			WriterState::Plain (ref mut writer) => writer.flush (),
+			WriterState::Gzip (ref mut encoder) => encoder.flush (),
+			WriterState::Deflate (ref mut encoder) => encoder.flush (),
+			_ => Ok (()),
+		}
+
+	}
+
+}
+
+impl BodyWriter {
+
+	/// Sends what remains of the body, and marks its end. A writer which is
+	/// dropped without this aborts the body instead.
+	fn end (
+		mut self,
+	) -> Result <(), IoError> {
+
+		self.flush () ?;
+
+		self.sender.blocking_send (
+			None,
+		).map_err (|_|
+			IoError::new (
+				IoErrorKind::BrokenPipe,
+				"Connection closed")
+		)
+
+	}
+
+}
+
+impl Write for BodyWriter {
+
+	fn write (
+		& mut self,
+		buffer: & [u8],
+	) -> Result <usize, IoError> {
+
+		self.buffer.extend_from_slice (
+			buffer);
+
+		if self.buffer.len () >= BODY_CHUNK_SIZE {
+			self.flush () ?;
+		}
+
+		Ok (buffer.len ())
+
+	}
+
+	fn flush (
+		& mut self,
+	) -> Result <(), IoError> {
+
+		if self.buffer.is_empty () {
+			return Ok (());
+		}
+
+		let chunk =
+			mem::replace (
+				& mut self.buffer,
+				Vec::with_capacity (BODY_CHUNK_SIZE));
+
+		let chunk_size =
+			chunk.len () as u64;
+
+		// this runs on the blocking pool, so it may wait for the connection
+
+		self.sender.blocking_send (
+			Some (Bytes::from (chunk)),
+		).map_err (|_|
+			IoError::new (
+				IoErrorKind::BrokenPipe,
+				"Connection closed")
+		) ?;
+
+		self.bytes_written.set (
+			self.bytes_written.get () + chunk_size);
+
+		Ok (())
+
+	}
+
+}
+
+/// The body of a response, as it is received from the handler. If the
+/// handler fails before ending it, the body fails too, so that the connection
+/// is aborted rather than the client taking what it got as complete.
+pub struct ServerBody {
+	receiver: mpsc::Receiver <Option <Bytes>>,
+	ended: bool,
+}
+
+impl ServerBody {
+
+	pub fn empty (
+	) -> ServerBody {
+
+		let (_, receiver) =
+			mpsc::channel (1);
+
+		ServerBody {
+			receiver,
+			ended: true,
+		}
+
+	}
+
+}
+
+impl HyperBody for ServerBody {
+
+	type Data = Bytes;
+	type Error = IoError;
+
+	fn poll_frame (
+		mut self: Pin <& mut Self>,
+		context: & mut Context,
+	) -> Poll <Option <Result <HyperFrame <Bytes>, IoError>>> {
+
+		if self.ended {
+			return Poll::Ready (None);
+		}
+
+		match self.receiver.poll_recv (context) {
+
+			Poll::Ready (Some (Some (chunk))) =>
+				Poll::Ready (Some (Ok (HyperFrame::data (chunk)))),
+
+			Poll::Ready (Some (None)) => {
+				self.ended = true;
+				Poll::Ready (None)
+			},
+
+			Poll::Ready (None) =>
+				Poll::Ready (Some (Err (
+					IoError::other (
+						"Response body not completed")))),
+
+			Poll::Pending =>
+				Poll::Pending,
+
+		}
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::thread;
+
+	use http_body_util::BodyExt;
+
+	use tokio::runtime::Builder as RuntimeBuilder;
+
+	use super::*;
+
+	fn body_collect (
+		ended: bool,
+	) -> Result <Bytes, IoError> {
+
+		let (sender, receiver) =
+			oneshot::channel ();
+
+		let writer_thread =
+			thread::spawn (move || {
+
+				let mut response =
+					ServerResponse::new (
+						sender,
+						false,
+						None,
+						0);
+
+				let mut writer =
+					response.start ().unwrap ();
+
+				writer.write_all (
+					b"partial").unwrap ();
+
+				if ended {
+					writer.end ().unwrap ();
+				}
+
+			});
+
+		let runtime =
+			RuntimeBuilder::new_current_thread ().build ().unwrap ();
+
+		let result =
+			runtime.block_on (async {
+				receiver.await.unwrap ().into_body ().collect ().await
+			}).map (|collected|
+				collected.to_bytes ()
+			);
+
+		writer_thread.join ().unwrap ();
+
+		result
+
+	}
+
+	#[ test ]
+	fn body_ends_when_the_writer_is_ended () {
+
+		assert_eq! (
+			body_collect (true).unwrap (),
+			Bytes::from_static (b"partial"));
+
+	}
+
+	#[ test ]
+	fn body_fails_when_the_writer_is_dropped () {
+
+		assert! (
+			body_collect (false).is_err ());
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
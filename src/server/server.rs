@@ -1,70 +1,607 @@
-use std::error::Error;
+use std::fs;
+use std::net::TcpListener as StdTcpListener;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
-use hyper::server::Listening as HyperListening;
-use hyper::server::Server as HyperServer;
+use hyper::Request as HyperRequest;
+use hyper::body::Incoming as HyperIncoming;
+use hyper::server::conn::http1 as hyper_http1;
+use hyper::service::service_fn;
 
-use server::*;
-use settings::*;
-use upstream::*;
+use hyper_util::rt::TokioIo;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::net::TcpListener;
+use tokio::runtime::Handle as RuntimeHandle;
+use tokio::sync::Semaphore;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio::time;
+
+use crate::auth::*;
+use crate::inventory::*;
+use crate::server::*;
+use crate::settings::*;
+use crate::ssl::*;
+use crate::unix_socket::*;
+use crate::upstream::*;
 
 pub struct Server {
-	hyper_listening: HyperListening,
-	state: Arc <Mutex <ServerState>>,
+	settings: Arc <Settings>,
+	authenticator: Arc <Authenticator>,
+	access_log: Arc <AccessLog>,
+	openssl_server: Option <OpensslServer>,
+	shutdown_sender: watch::Sender <bool>,
+	connections_receiver: Mutex <Option <mpsc::Receiver <()>>>,
+}
+
+/// The listeners, and the certificate they use, which are set up before
+/// privileges are dropped. Nothing is accepted until the server is started.
+pub struct ServerListeners {
+	tcp_listener: Option <TcpListener>,
+	openssl_server: Option <OpensslServer>,
+	unix_socket_listener: Option <UnixSocketListener>,
 }
 
+/// Held by each accept loop and connection. When all of them have gone, the
+/// server has finished shutting down.
+type ConnectionGuard =
+	mpsc::Sender <()>;
+
 impl Server {
 
+	/// Loads the server certificate and binds the listeners, which is done
+	/// first so that it can be done with privileges which are then dropped.
+	pub fn bind (
+		settings: & Settings,
+		runtime: & RuntimeHandle,
+	) -> Result <ServerListeners, String> {
+
+		// listeners must be registered with the runtime as they are created
+
+		let _runtime_guard =
+			runtime.enter ();
+
+		let (tcp_listener, openssl_server) =
+			match (
+				settings.server.listen_address.as_ref (),
+				settings.server.listen_port,
+			) {
+
+			(Some (listen_address), Some (listen_port)) => {
+
+				let openssl_server =
+					match settings.server.tls {
+
+					Some (ref tls_settings) =>
+						Some (
+							OpensslServer::new (
+								Arc::new (tls_settings.clone ()),
+							) ?
+						),
+
+					None =>
+						None,
+
+				};
+
+				let tcp_listener =
+					Self::bind_tcp (
+						(listen_address.as_str (), listen_port),
+					) ?;
+
+				println! (
+					"Listening on {}://{}:{}",
+					if openssl_server.is_some () { "https" } else { "http" },
+					listen_address,
+					listen_port);
+
+				(Some (tcp_listener), openssl_server)
+
+			},
+
+			(None, None) =>
+				(None, None),
+
+			_ =>
+				return Err (
+					"Must specify both listen address and port, or neither"
+						.to_string ()),
+
+		};
+
+		let unix_socket_listener =
+			match settings.server.unix_socket {
+
+			Some (ref unix_socket_settings) => {
+
+				let unix_socket_listener =
+					UnixSocketListener::bind (
+						unix_socket_settings,
+					) ?;
+
+				println! (
+					"Listening on unix socket {}",
+					unix_socket_settings.path.to_string_lossy ());
+
+				Some (unix_socket_listener)
+
+			},
+
+			None =>
+				None,
+
+		};
+
+		if tcp_listener.is_none () && unix_socket_listener.is_none () {
+
+			return Err (
+				"Must specify a listen address and port, a unix socket, or both"
+					.to_string ());
+
+		}
+
+		Ok (ServerListeners {
+			tcp_listener,
+			openssl_server,
+			unix_socket_listener,
+		})
+
+	}
+
+	/// Starts accepting connections on the listeners.
 	pub fn start (
 		settings: Arc <Settings>,
+		listeners: ServerListeners,
 		upstream: Arc <Upstream>,
+		inventory: Arc <SharedInventory>,
+		runtime: & RuntimeHandle,
 	) -> Result <Server, String> {
 
+		let authenticator =
+			Arc::new (
+				Authenticator::new (
+					settings.clone (),
+				) ?
+			);
+
 		let state =
-			Arc::new (Mutex::new (
+			Arc::new (
 				ServerState {
-					counter: 0,
+					metrics: ServerMetrics::new (),
+					rate_limiter: RateLimiter::new (
+						& settings.server.rate_limits),
+					request_slots: Arc::new (
+						Semaphore::new (
+							settings.server.max_concurrent_requests)),
+					start_time: Instant::now (),
 				}
-			));
+			);
+
+		let access_log =
+			Arc::new (
+				AccessLog::new (
+					& settings.server.access_log,
+				) ?
+			);
 
 		let handler =
 			ServerHandler {
 				settings: settings.clone (),
-				state: state.clone (),
-				upstream: upstream,
+				state,
+				upstream,
+				inventory,
+				authenticator: authenticator.clone (),
+				access_log: access_log.clone (),
 			};
 
-		let hyper_server =
-			HyperServer::http (
-				(
-					settings.server.listen_address.as_str (),
-					settings.server.listen_port,
-				),
+		let ServerListeners {
+			tcp_listener,
+			openssl_server,
+			unix_socket_listener,
+		} = listeners;
+
+		let (shutdown_sender, shutdown_receiver) =
+			watch::channel (false);
+
+		let (connection_guard, connections_receiver) =
+			mpsc::channel (1);
+
+		if let Some (tcp_listener) = tcp_listener {
+
+			runtime.spawn (
+				Self::accept_tcp (
+					tcp_listener,
+					openssl_server.clone (),
+					handler.clone (),
+					runtime.clone (),
+					shutdown_receiver.clone (),
+					connection_guard.clone ()));
+
+		}
+
+		if let Some (unix_socket_listener) = unix_socket_listener {
+
+			runtime.spawn (
+				Self::accept_unix (
+					unix_socket_listener,
+					handler,
+					runtime.clone (),
+					shutdown_receiver,
+					connection_guard));
+
+		}
+
+		Ok (Server {
+			settings,
+			authenticator,
+			access_log,
+			openssl_server,
+			shutdown_sender,
+			connections_receiver: Mutex::new (Some (connections_receiver)),
+		})
+
+	}
+
+	fn bind_tcp (
+		listen_address: (& str, u16),
+	) -> Result <TcpListener, String> {
+
+		let std_tcp_listener =
+			StdTcpListener::bind (
+				listen_address,
 			).map_err (|error|
 				format! (
 					"Error creating server: {}",
-					error.description ())
+					error)
 			) ?;
 
-		let hyper_listening =
-			hyper_server.handle (
-				handler,
-			).map_err (|error|
-				format! (
-					"Error starting server: {}",
-					error.description ())
-			) ?;
+		std_tcp_listener.set_nonblocking (
+			true,
+		).map_err (|error|
+			format! (
+				"Error creating server: {}",
+				error)
+		) ?;
+
+		TcpListener::from_std (
+			std_tcp_listener,
+		).map_err (|error|
+			format! (
+				"Error starting server: {}",
+				error)
+		)
+
+	}
+
+	async fn accept_tcp (
+		tcp_listener: TcpListener,
+		openssl_server: Option <OpensslServer>,
+		handler: ServerHandler,
+		runtime: RuntimeHandle,
+		mut shutdown_receiver: watch::Receiver <bool>,
+		connection_guard: ConnectionGuard,
+	) {
+
+		// returning drops the listener, so that no more connections are made
+
+		loop {
+
+			let accept_result =
+				tokio::select! {
+
+				accept_result = tcp_listener.accept () =>
+					accept_result,
+
+				_ = shutdown_receiver.changed () =>
+					return,
+
+			};
+
+			let (stream, remote_address) =
+				match accept_result {
+
+				Ok (accepted) =>
+					accepted,
+
+				Err (error) => {
+
+					println! (
+						"Error accepting connection: {}",
+						error);
+
+					// this is usually running out of file descriptors, so
+					// give some time for connections to close
+
+					time::sleep (
+						Duration::from_millis (100),
+					).await;
+
+					continue;
+
+				},
+
+			};
+
+			let openssl_server = openssl_server.clone ();
+			let handler = handler.clone ();
+			let runtime = runtime.clone ();
+			let shutdown_receiver = shutdown_receiver.clone ();
+			let connection_guard = connection_guard.clone ();
+
+			tokio::spawn (async move {
+
+				let openssl_server =
+					match openssl_server {
+
+					Some (openssl_server) =>
+						openssl_server,
+
+					None => {
+
+						Self::serve (
+							stream,
+							ServerConnection {
+								client_address: ClientAddress::Remote (
+									remote_address.ip ()),
+								peer_certificate_names: Vec::new (),
+								peer_credentials: None,
+							},
+							handler,
+							runtime,
+							shutdown_receiver,
+							connection_guard,
+						).await;
+
+						return;
+
+					},
+
+				};
+
+				// a failed handshake is the client's problem, and there is no
+				// way to report it over http, so the connection is just closed
+
+				let ssl_stream =
+					match openssl_server.accept (stream).await {
+						Ok (ssl_stream) => ssl_stream,
+						Err (_) => return,
+					};
+
+				let peer_certificate_names =
+					peer_certificate_names (
+						ssl_stream.ssl ());
+
+				Self::serve (
+					ssl_stream,
+					ServerConnection {
+						client_address: ClientAddress::Remote (
+							remote_address.ip ()),
+						peer_certificate_names,
+						peer_credentials: None,
+					},
+					handler,
+					runtime,
+					shutdown_receiver,
+					connection_guard,
+				).await;
+
+			});
+
+		}
+
+	}
+
+	async fn accept_unix (
+		unix_socket_listener: UnixSocketListener,
+		handler: ServerHandler,
+		runtime: RuntimeHandle,
+		mut shutdown_receiver: watch::Receiver <bool>,
+		connection_guard: ConnectionGuard,
+	) {
+
+		loop {
+
+			let accept_result =
+				tokio::select! {
+
+				accept_result = unix_socket_listener.accept () =>
+					accept_result,
+
+				_ = shutdown_receiver.changed () =>
+					return,
+
+			};
+
+			let (stream, peer_credentials) =
+				match accept_result {
+
+				Ok (accepted) =>
+					accepted,
+
+				Err (error) => {
+
+					println! (
+						"Error accepting connection: {}",
+						error);
+
+					time::sleep (
+						Duration::from_millis (100),
+					).await;
+
+					continue;
+
+				},
+
+			};
+
+			tokio::spawn (
+				Self::serve (
+					stream,
+					ServerConnection {
+						client_address: ClientAddress::Local (
+							peer_credentials.uid),
+						peer_certificate_names: Vec::new (),
+						peer_credentials: Some (peer_credentials),
+					},
+					handler.clone (),
+					runtime.clone (),
+					shutdown_receiver.clone (),
+					connection_guard.clone ()));
+
+		}
+
+	}
+
+	/// Serves requests on a connection until it is closed or the server shuts
+	/// down. Connection errors are the client's, so they are ignored.
+	async fn serve <Stream> (
+		stream: Stream,
+		connection: ServerConnection,
+		handler: ServerHandler,
+		runtime: RuntimeHandle,
+		mut shutdown_receiver: watch::Receiver <bool>,
+		_connection_guard: ConnectionGuard,
+	) where Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+
+		let service =
+			service_fn (move |hyper_request: HyperRequest <HyperIncoming>|
+				handler.clone ().handle (
+					ServerRequest::new (
+						hyper_request,
+						& connection,
+						runtime.clone ()))
+			);
+
+		let hyper_connection =
+			hyper_http1::Builder::new (
+			).serve_connection (
+				TokioIo::new (stream),
+				service);
+
+		tokio::pin! (hyper_connection);
+
+		tokio::select! {
+
+		result = hyper_connection.as_mut () => {
+			result.unwrap_or (());
+			return;
+		},
+
+		_ = shutdown_receiver.changed () =>
+			(),
+
+		}
+
+		hyper_connection.as_mut ().graceful_shutdown ();
+
+		hyper_connection.await.unwrap_or (());
+
+	}
+
+	/// Stops accepting connections and waits for requests in progress to
+	/// finish, up to the shutdown timeout.
+	pub async fn shutdown (
+		& self,
+	) {
+
+		self.shutdown_sender.send (
+			true,
+		).unwrap_or (());
+
+		if let Some (ref unix_socket_settings) =
+			self.settings.server.unix_socket {
+
+			fs::remove_file (
+				& unix_socket_settings.path,
+			).unwrap_or_else (|error|
+				println! (
+					"Error removing unix socket {}: {}",
+					unix_socket_settings.path.to_string_lossy (),
+					error)
+			);
+
+		}
+
+		let connections_receiver =
+			self.connections_receiver.lock ().unwrap ().take ();
+
+		if let Some (mut connections_receiver) = connections_receiver {
+
+			// nothing is ever sent, so this returns once every guard is gone
+
+			let drained =
+				time::timeout (
+					Duration::from_secs (
+						self.settings.server.shutdown_timeout),
+					connections_receiver.recv (),
+				).await.is_ok ();
+
+			if ! drained {
+
+				println! (
+					"Shutdown timeout expired, abandoning requests in progress");
+
+			}
+
+		}
+
+	}
+
+	pub fn reload_tokens (
+		& self,
+	) -> Result <(), String> {
+
+		self.authenticator.reload () ?;
 
 		println! (
-			"Listening on {}:{}",
-			settings.server.listen_address,
-			settings.server.listen_port);
+			"Reloaded API tokens");
 
-		Ok (Server {
-			hyper_listening: hyper_listening,
-			state: state,
-		})
+		Ok (())
+
+	}
+
+	pub fn reopen_access_log (
+		& self,
+	) -> Result <(), String> {
+
+		self.access_log.reopen ()
+
+	}
+
+	pub fn reload_tls (
+		& self,
+	) -> Result <(), String> {
+
+		if let Some (ref openssl_server) =
+			self.openssl_server {
+
+			openssl_server.reload () ?;
+
+			println! (
+				"Reloaded TLS certificate");
+
+		}
+
+		Ok (())
+
+	}
+
+	pub fn reload_tokens_if_changed (
+		& self,
+	) -> Result <(), String> {
+
+		if self.authenticator.reload_if_changed () ? {
+
+			println! (
+				"Token file changed, reloaded API tokens");
+
+		}
+
+		Ok (())
 
 	}
 
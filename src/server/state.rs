@@ -1,5 +1,47 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tokio::sync::Semaphore;
+
+use crate::server::*;
+
+/// State shared by every request. Each part does its own locking, split into
+/// shards by key, so that concurrent requests rarely wait for each other.
 pub struct ServerState {
-	pub counter: u64,
+	pub metrics: ServerMetrics,
+	pub rate_limiter: RateLimiter,
+	pub request_slots: Arc <Semaphore>,
+	pub start_time: Instant,
+}
+
+const STATE_SHARD_COUNT: usize = 16;
+
+pub fn state_shards_new <Shard, NewShard: Fn () -> Shard> (
+	new_shard: NewShard,
+) -> Vec <Mutex <Shard>> {
+
+	(0 .. STATE_SHARD_COUNT).map (|_|
+		Mutex::new (new_shard ())
+	).collect ()
+
+}
+
+pub fn state_shard_index <Key: Hash> (
+	key: & Key,
+) -> usize {
+
+	let mut hasher =
+		DefaultHasher::new ();
+
+	key.hash (
+		& mut hasher);
+
+	(hasher.finish () % STATE_SHARD_COUNT as u64) as usize
+
 }
 
 // ex: noet ts=4 filetype=rust
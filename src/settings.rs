@@ -1,10 +1,8 @@
-use std::error::Error;
+use std::env;
 use std::fs::File;
 use std::path::Path;
 use std::path::PathBuf;
 
-use serde_yaml;
-
 #[ derive (Serialize, Deserialize) ]
 pub struct Settings {
 
@@ -19,6 +17,8 @@ pub struct Settings {
 
 }
 
+/// The daemon switches to the user and group, if given, once listeners are
+/// bound. The group defaults to the user's primary group.
 #[ derive (Serialize, Deserialize) ]
 pub struct GeneralSettings {
 
@@ -28,6 +28,15 @@ pub struct GeneralSettings {
 	#[ serde (rename = "project-data") ]
 	pub project_data: PathBuf,
 
+	#[ serde (rename = "log-file") ]
+	pub log_file: Option <PathBuf>,
+
+	#[ serde (rename = "user") ]
+	pub user: Option <String>,
+
+	#[ serde (rename = "group") ]
+	pub group: Option <String>,
+
 }
 
 #[ derive (Serialize, Deserialize) ]
@@ -51,19 +60,204 @@ pub struct UpstreamSettings {
 	#[ serde (rename = "key-prefix") ]
 	pub key_prefix: String,
 
+	#[ serde (rename = "watch-error-threshold", default = "watch_error_threshold_default") ]
+	pub watch_error_threshold: u64,
+
 }
 
 #[ derive (Serialize, Deserialize) ]
 pub struct ServerSettings {
 
 	#[ serde (rename = "listen-address") ]
-	pub listen_address: String,
+	pub listen_address: Option <String>,
 
 	#[ serde (rename = "listen-port") ]
-	pub listen_port: u16,
+	pub listen_port: Option <u16>,
+
+	#[ serde (rename = "unix-socket") ]
+	pub unix_socket: Option <UnixSocketSettings>,
 
 	#[ serde (rename = "authorization-token") ]
-	pub authorization_token: String,
+	pub authorization_token: Option <String>,
+
+	#[ serde (rename = "tokens", default = "empty_vec_default") ]
+	pub tokens: Vec <TokenSettings>,
+
+	#[ serde (rename = "token-file") ]
+	pub token_file: Option <PathBuf>,
+
+	#[ serde (rename = "tls") ]
+	pub tls: Option <ServerTlsSettings>,
+
+	#[ serde (rename = "access-log", default = "access_log_default") ]
+	pub access_log: AccessLogSettings,
+
+	#[ serde (rename = "compression", default = "compression_default") ]
+	pub compression: CompressionSettings,
+
+	#[ serde (rename = "rate-limits", default = "rate_limits_default") ]
+	pub rate_limits: RateLimitsSettings,
+
+	#[ serde (rename = "shutdown-timeout", default = "shutdown_timeout_default") ]
+	pub shutdown_timeout: u64,
+
+	/// Each request in progress holds a thread, so this also bounds the
+	/// threads used. Further requests are refused until one finishes.
+	#[ serde (rename = "max-concurrent-requests", default = "max_concurrent_requests_default") ]
+	pub max_concurrent_requests: usize,
+
+}
+
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct AccessLogSettings {
+
+	#[ serde (rename = "path") ]
+	pub path: Option <PathBuf>,
+
+	#[ serde (rename = "format", default = "access_log_format_default") ]
+	pub format: String,
+
+}
+
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct CompressionSettings {
+
+	#[ serde (rename = "enabled", default = "compression_enabled_default") ]
+	pub enabled: bool,
+
+	#[ serde (rename = "minimum-size", default = "compression_minimum_size_default") ]
+	pub minimum_size: u64,
+
+}
+
+/// Limits apply separately to each token, or client certificate, and to each
+/// client address, or user id for unix socket clients. Either may be left
+/// out.
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct RateLimitsSettings {
+
+	#[ serde (rename = "per-token") ]
+	pub per_token: Option <RateLimitSettings>,
+
+	#[ serde (rename = "per-address") ]
+	pub per_address: Option <RateLimitSettings>,
+
+}
+
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct RateLimitSettings {
+
+	#[ serde (rename = "rate") ]
+	pub rate: f64,
+
+	#[ serde (rename = "burst") ]
+	pub burst: u64,
+
+	#[ serde (rename = "max-concurrent") ]
+	pub max_concurrent: Option <u64>,
+
+}
+
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct ServerTlsSettings {
+
+	#[ serde (rename = "certificate") ]
+	pub certificate: PathBuf,
+
+	#[ serde (rename = "private-key") ]
+	pub private_key: PathBuf,
+
+	#[ serde (rename = "ca-certificate") ]
+	pub ca_certificate: Option <PathBuf>,
+
+	#[ serde (rename = "client-ca-certificate") ]
+	pub client_ca_certificate: Option <PathBuf>,
+
+	#[ serde (rename = "client-identities", default = "empty_vec_default") ]
+	pub client_identities: Vec <ClientIdentitySettings>,
+
+}
+
+/// The mode is given in octal, as for chmod, and defaults to 600. The owner
+/// and group are names, and default to those of the daemon.
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct UnixSocketSettings {
+
+	#[ serde (rename = "path") ]
+	pub path: PathBuf,
+
+	#[ serde (rename = "mode") ]
+	pub mode: Option <String>,
+
+	#[ serde (rename = "owner") ]
+	pub owner: Option <String>,
+
+	#[ serde (rename = "group") ]
+	pub group: Option <String>,
+
+	#[ serde (rename = "peer-identities", default = "empty_vec_default") ]
+	pub peer_identities: Vec <PeerIdentitySettings>,
+
+}
+
+/// Matches local clients by the credentials of the process which connected
+/// to the unix socket. A uid or gid which is left out matches any.
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct PeerIdentitySettings {
+
+	#[ serde (rename = "name") ]
+	pub name: String,
+
+	#[ serde (rename = "uid") ]
+	pub uid: Option <u32>,
+
+	#[ serde (rename = "gid") ]
+	pub gid: Option <u32>,
+
+	#[ serde (rename = "permissions") ]
+	pub permissions: PermissionSettings,
+
+}
+
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct TokenSettings {
+
+	#[ serde (rename = "name") ]
+	pub name: String,
+
+	#[ serde (rename = "token") ]
+	pub token: Option <String>,
+
+	#[ serde (rename = "hash", alias = "token-hash") ]
+	pub hash: Option <String>,
+
+	#[ serde (rename = "permissions") ]
+	pub permissions: PermissionSettings,
+
+}
+
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct ClientIdentitySettings {
+
+	#[ serde (rename = "subject") ]
+	pub subject: String,
+
+	#[ serde (rename = "permissions") ]
+	pub permissions: PermissionSettings,
+
+}
+
+#[ derive (Clone, Serialize, Deserialize) ]
+pub struct PermissionSettings {
+
+	#[ serde (rename = "scopes") ]
+	pub scopes: Vec <String>,
+
+	#[ serde (rename = "namespaces") ]
+	pub namespaces: Option <Vec <String>>,
+
+	#[ serde (rename = "classes") ]
+	pub classes: Option <Vec <String>>,
 
 }
 
@@ -92,20 +286,206 @@ impl Settings {
 				format! (
 					"Error opening {}: {}",
 					settings_path.to_string_lossy (),
-					error.description ())
+					error)
+			) ?;
+
+		let mut settings: Settings =
+			serde_yaml::from_reader (
+				settings_file,
+			).map_err (|error|
+				format! (
+					"Error reading {}: {}",
+					settings_path.to_string_lossy (),
+					error)
+			) ?;
+
+		let current_dir =
+			env::current_dir (
+			).map_err (|error|
+				format! (
+					"Error getting current directory: {}",
+					error)
 			) ?;
 
-		serde_yaml::from_reader (
-			settings_file,
+		settings.paths_resolve (
+			& current_dir);
+
+		settings.validate (
 		).map_err (|error|
 			format! (
-				"Error reading {}: {}",
+				"Error in {}: {}",
 				settings_path.to_string_lossy (),
-				error.description ())
-		)
+				error)
+		) ?;
+
+		Ok (settings)
 
 	}
 
+	/// Checks for values which serde accepts but which can't be used.
+	fn validate (
+		& self,
+	) -> Result <(), String> {
+
+		if self.server.max_concurrent_requests == 0 {
+
+			return Err (
+				"Invalid max-concurrent-requests: must be at least 1".to_string ());
+
+		}
+
+		for (name, rate_limit_settings) in [
+			("per-token", & self.server.rate_limits.per_token),
+			("per-address", & self.server.rate_limits.per_address),
+		] {
+
+			if let Some (rate_limit_settings) = rate_limit_settings {
+
+				if rate_limit_settings.rate.is_nan ()
+					|| rate_limit_settings.rate <= 0.0 {
+
+					return Err (
+						format! (
+							"Invalid rate-limits {} rate: must be above 0",
+							name));
+
+				}
+
+				if rate_limit_settings.burst == 0 {
+
+					return Err (
+						format! (
+							"Invalid rate-limits {} burst: must be at least 1",
+							name));
+
+				}
+
+			}
+
+		}
+
+		// client certificates are only requested when there is a ca to
+		// verify them against
+
+		if let Some (ref tls_settings) = self.server.tls {
+
+			if ! tls_settings.client_identities.is_empty ()
+				&& tls_settings.client_ca_certificate.is_none () {
+
+				return Err (
+					"Client identities require a client-ca-certificate".to_string ());
+
+			}
+
+		}
+
+		Ok (())
+
+	}
+
+	/// Makes every path absolute, relative to the directory we were started
+	/// in, since the daemon changes to the root directory when it detaches.
+	fn paths_resolve (
+		& mut self,
+		base: & Path,
+	) {
+
+		let general =
+			& mut self.general;
+
+		path_resolve (base, & mut general.pid_file);
+		path_resolve (base, & mut general.project_data);
+		path_option_resolve (base, & mut general.log_file);
+
+		let upstream =
+			& mut self.upstream;
+
+		path_resolve (base, & mut upstream.ca_certificate);
+		path_resolve (base, & mut upstream.certificate);
+		path_resolve (base, & mut upstream.private_key);
+
+		let server =
+			& mut self.server;
+
+		path_option_resolve (base, & mut server.token_file);
+		path_option_resolve (base, & mut server.access_log.path);
+
+		if let Some (ref mut unix_socket) = server.unix_socket {
+			path_resolve (base, & mut unix_socket.path);
+		}
+
+		if let Some (ref mut tls) = server.tls {
+			path_resolve (base, & mut tls.certificate);
+			path_resolve (base, & mut tls.private_key);
+			path_option_resolve (base, & mut tls.ca_certificate);
+			path_option_resolve (base, & mut tls.client_ca_certificate);
+		}
+
+	}
+
+}
+
+fn path_resolve (
+	base: & Path,
+	path: & mut PathBuf,
+) {
+
+	if path.is_relative () {
+
+		* path =
+			base.join (
+				path.as_path ());
+
+	}
+
+}
+
+fn path_option_resolve (
+	base: & Path,
+	path: & mut Option <PathBuf>,
+) {
+
+	if let Some (ref mut path) = * path {
+
+		path_resolve (
+			base,
+			path);
+
+	}
+
+}
+
+fn empty_vec_default <Type> () -> Vec <Type> { Vec::new () }
+
+fn watch_error_threshold_default () -> u64 { 3 }
+
+fn access_log_default () -> AccessLogSettings {
+	AccessLogSettings {
+		path: None,
+		format: access_log_format_default (),
+	}
 }
 
+fn access_log_format_default () -> String { "combined".to_string () }
+
+fn compression_default () -> CompressionSettings {
+	CompressionSettings {
+		enabled: compression_enabled_default (),
+		minimum_size: compression_minimum_size_default (),
+	}
+}
+
+fn compression_enabled_default () -> bool { true }
+fn compression_minimum_size_default () -> u64 { 1024 }
+
+fn rate_limits_default () -> RateLimitsSettings {
+	RateLimitsSettings {
+		per_token: None,
+		per_address: None,
+	}
+}
+
+fn shutdown_timeout_default () -> u64 { 30 }
+fn max_concurrent_requests_default () -> usize { 64 }
+
 // ex: noet ts=4 filetype=rust
@@ -0,0 +1,111 @@
+use std::io::Error as IoError;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new (false);
+static SHUTDOWN_RECEIVED: AtomicBool = AtomicBool::new (false);
+
+/// Records signals received while starting up, before the runtime is
+/// watching for them.
+pub fn signals_install (
+) -> Result <(), String> {
+
+	signal_install (
+		libc::SIGHUP,
+		"SIGHUP",
+		sighup_handler,
+	) ?;
+
+	signal_install (
+		libc::SIGTERM,
+		"SIGTERM",
+		shutdown_handler,
+	) ?;
+
+	signal_install (
+		libc::SIGINT,
+		"SIGINT",
+		shutdown_handler,
+	) ?;
+
+	Ok (())
+
+}
+
+fn signal_install (
+	signal: libc::c_int,
+	signal_name: & str,
+	handler: extern "C" fn (libc::c_int),
+) -> Result <(), String> {
+
+	let mut action: libc::sigaction =
+		unsafe { mem::zeroed () };
+
+	action.sa_sigaction = handler as libc::sighandler_t;
+	action.sa_flags = libc::SA_RESTART;
+
+	let signal_result =
+		unsafe {
+			libc::sigemptyset (& mut action.sa_mask);
+			libc::sigaction (
+				signal,
+				& action,
+				ptr::null_mut ())
+		};
+
+	if signal_result != 0 {
+
+		return Err (
+			format! (
+				"Error installing {} handler: {}",
+				signal_name,
+				IoError::last_os_error ()));
+
+	}
+
+	Ok (())
+
+}
+
+/// Returns true if SIGHUP has been received since the last call.
+pub fn sighup_take (
+) -> bool {
+
+	SIGHUP_RECEIVED.swap (
+		false,
+		Ordering::SeqCst)
+
+}
+
+/// Returns true once SIGTERM or SIGINT has been received.
+pub fn shutdown_requested (
+) -> bool {
+
+	SHUTDOWN_RECEIVED.load (
+		Ordering::SeqCst)
+
+}
+
+extern "C" fn sighup_handler (
+	_signal: libc::c_int,
+) {
+
+	SIGHUP_RECEIVED.store (
+		true,
+		Ordering::SeqCst);
+
+}
+
+extern "C" fn shutdown_handler (
+	_signal: libc::c_int,
+) {
+
+	SHUTDOWN_RECEIVED.store (
+		true,
+		Ordering::SeqCst);
+
+}
+
+// ex: noet ts=4 filetype=rust
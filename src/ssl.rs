@@ -1,155 +1,338 @@
-use std::io::Error as IoError;
-use std::io::Read;
-use std::io::Write;
-use std::net::SocketAddr;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::time::Duration;
+use std::sync::RwLock;
+
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::Ssl;
+use openssl::ssl::SslContext;
+use openssl::ssl::SslContextBuilder;
+use openssl::ssl::SslMethod;
+use openssl::ssl::SslOptions;
+use openssl::ssl::SslRef;
+use openssl::ssl::SslVerifyMode;
+use openssl::x509::X509;
+use openssl::x509::X509Name;
+use openssl::x509::store::X509StoreBuilder;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+
+use tokio_openssl::SslStream;
+
+use crate::settings::*;
+
+/// Returns the common name followed by any DNS subject alternative names of
+/// the verified peer certificate, if one was presented.
+pub fn peer_certificate_names (
+	ssl: & SslRef,
+) -> Vec <String> {
+
+	let peer_certificate =
+		match ssl.peer_certificate () {
+			Some (peer_certificate) => peer_certificate,
+			None => return Vec::new (),
+		};
+
+	let mut names: Vec <String> =
+		peer_certificate.subject_name ().entries_by_nid (
+			Nid::COMMONNAME,
+		).filter_map (|entry|
+			entry.data ().to_string ().ok ()
+		).collect ();
+
+	if let Some (subject_alt_names) =
+		peer_certificate.subject_alt_names () {
+
+		names.extend (
+			subject_alt_names.iter ().filter_map (|general_name|
+				general_name.dnsname ().map (|name|
+					name.to_owned ())));
 
-use hyper::error::Error as HyperError;
-use hyper::net::HttpStream as HyperHttpStream;
-use hyper::net::NetworkStream as HyperNetworkStream;
-use hyper::net::SslClient as HyperSslClient;
+	}
 
-use openssl::ssl::SslStream;
-use openssl::ssl::SslConnector;
+	names
 
-#[ derive (Clone) ]
-pub struct OpensslStream {
-	ssl_stream: Arc <Mutex <SslStream <HyperHttpStream>>>,
 }
 
-impl Read for OpensslStream {
-
-	fn read (
-		& mut self,
-		buffer: & mut [u8],
-	) -> Result <usize, IoError> {
+#[ derive (Clone) ]
+pub struct OpensslServer {
+	tls_settings: Arc <ServerTlsSettings>,
+	ssl_context: Arc <RwLock <SslContext>>,
+}
 
-		let mut ssl_stream =
-			self.ssl_stream.lock ().unwrap ();
+impl OpensslServer {
 
-		ssl_stream.read (
-			buffer,
-		)
+	pub fn new (
+		tls_settings: Arc <ServerTlsSettings>,
+	) -> Result <OpensslServer, String> {
+
+		let ssl_context =
+			Self::ssl_context_create (
+				& tls_settings,
+			) ?;
+
+		Ok (OpensslServer {
+			tls_settings,
+			ssl_context: Arc::new (RwLock::new (
+				ssl_context,
+			)),
+		})
 
 	}
 
-}
+	pub fn reload (
+		& self,
+	) -> Result <(), String> {
 
-impl Write for OpensslStream {
+		let ssl_context =
+			Self::ssl_context_create (
+				& self.tls_settings,
+			) ?;
 
-	fn write (
-		& mut self,
-		buffer: & [u8],
-	) -> Result <usize, IoError> {
+		* self.ssl_context.write ().unwrap () =
+			ssl_context;
 
-		let mut ssl_stream =
-			self.ssl_stream.lock ().unwrap ();
-
-		ssl_stream.write (
-			buffer,
-		)
+		Ok (())
 
 	}
 
-	fn flush (
-		& mut self,
-	) -> Result <(), IoError> {
-
-		let mut ssl_stream =
-			self.ssl_stream.lock ().unwrap ();
+	fn ssl_context_create (
+		tls_settings: & ServerTlsSettings,
+	) -> Result <SslContext, String> {
 
-		ssl_stream.flush ()
+		let mut ssl_context_builder =
+			SslContextBuilder::new (
+				SslMethod::tls (),
+			).map_err (|error|
+				format! (
+					"Error initialising openssl: {}",
+					error)
+			) ?;
 
-	}
+		ssl_context_builder.set_options (
+			SslOptions::NO_SSLV2 | SslOptions::NO_SSLV3);
 
-}
+		// the server's chain is only sent to clients, and is never trusted
+		// to verify them
 
-impl HyperNetworkStream for OpensslStream {
+		if let Some (ref ca_certificate) =
+			tls_settings.ca_certificate {
 
-	fn peer_addr (
-		& mut self,
-	) -> Result <SocketAddr, IoError> {
+			for certificate in Self::certificates_load (
+				ca_certificate,
+			) ? {
 
-		let mut ssl_stream =
-			self.ssl_stream.lock ().unwrap ();
+				ssl_context_builder.add_extra_chain_cert (
+					certificate,
+				).map_err (|error|
+					format! (
+						"Error loading ca certificate {}: {}",
+						ca_certificate.to_string_lossy (),
+						error)
+				) ?;
 
-		ssl_stream.get_mut ().peer_addr ()
+			}
 
-	}
+		}
 
-	fn set_read_timeout (
-		& self,
-		value: Option <Duration>,
-	) -> Result <(), IoError> {
+		// client certificates are requested but not required, so that
+		// clients can still fall back to a bearer token
 
-		let ssl_stream =
-			self.ssl_stream.lock ().unwrap ();
+		if let Some (ref client_ca_certificate) =
+			tls_settings.client_ca_certificate {
 
-		ssl_stream.get_ref ().set_read_timeout (
-			value,
-		)
+			let client_ca_error = |error: & dyn Error|
+				format! (
+					"Error loading client ca certificate {}: {}",
+					client_ca_certificate.to_string_lossy (),
+					error);
 
-	}
+			let mut client_store_builder =
+				X509StoreBuilder::new (
+				).map_err (|error|
+					client_ca_error (& error)
+				) ?;
 
-	fn set_write_timeout (
-		& self,
-		value: Option <Duration>,
-	) -> Result <(), IoError> {
+			for certificate in Self::certificates_load (
+				client_ca_certificate,
+			) ? {
 
-		let ssl_stream =
-			self.ssl_stream.lock ().unwrap ();
+				client_store_builder.add_cert (
+					certificate,
+				).map_err (|error|
+					client_ca_error (& error)
+				) ?;
 
-		ssl_stream.get_ref ().set_write_timeout (
-			value,
-		)
+			}
 
-	}
+			ssl_context_builder.set_verify_cert_store (
+				client_store_builder.build (),
+			).map_err (|error|
+				client_ca_error (& error)
+			) ?;
 
-}
+			ssl_context_builder.set_client_ca_list (
+				X509Name::load_client_ca_file (
+					client_ca_certificate,
+				).map_err (|error|
+					client_ca_error (& error)
+				) ?);
 
-pub struct OpensslClient {
-	ssl_connector: SslConnector,
-}
+			ssl_context_builder.set_verify (
+				SslVerifyMode::PEER);
 
-impl OpensslClient {
+		}
 
-	pub fn new (
-		ssl_connector: SslConnector,
-	) -> OpensslClient {
+		// the files are read here, rather than by openssl, so that an error
+		// opening them, such as after dropping privileges, is reported as such
+
+		let certificate_error = |error: & dyn Error|
+			format! (
+				"Error loading certificate {}: {}",
+				tls_settings.certificate.to_string_lossy (),
+				error);
+
+		let mut certificates =
+			Self::certificates_load (
+				& tls_settings.certificate,
+			) ?.into_iter ();
+
+		let certificate =
+			certificates.next ().ok_or_else (||
+				format! (
+					"No certificate found in {}",
+					tls_settings.certificate.to_string_lossy ())
+			) ?;
+
+		ssl_context_builder.set_certificate (
+			& certificate,
+		).map_err (|error|
+			certificate_error (& error)
+		) ?;
+
+		for certificate in certificates {
+
+			ssl_context_builder.add_extra_chain_cert (
+				certificate,
+			).map_err (|error|
+				certificate_error (& error)
+			) ?;
 
-		OpensslClient {
-			ssl_connector: ssl_connector,
 		}
 
-	}
+		let private_key =
+			fs::read (
+				& tls_settings.private_key,
+			).map_err (|error|
+				format! (
+					"Error reading {}: {}",
+					tls_settings.private_key.to_string_lossy (),
+					error)
+			).and_then (|pem|
+				PKey::private_key_from_pem (
+					& pem,
+				).map_err (|error|
+					format! (
+						"Error loading private key {}: {}",
+						tls_settings.private_key.to_string_lossy (),
+						error)
+				)
+			) ?;
+
+		ssl_context_builder.set_private_key (
+			& private_key,
+		).map_err (|error|
+			format! (
+				"Error loading private key {}: {}",
+				tls_settings.private_key.to_string_lossy (),
+				error)
+		) ?;
+
+		ssl_context_builder.check_private_key (
+		).map_err (|error|
+			format! (
+				"Private key {} does not match certificate {}: {}",
+				tls_settings.private_key.to_string_lossy (),
+				tls_settings.certificate.to_string_lossy (),
+				error)
+		) ?;
+
+		Ok (ssl_context_builder.build ())
 
-}
+	}
 
-impl HyperSslClient for OpensslClient {
+	fn certificates_load (
+		path: & Path,
+	) -> Result <Vec <X509>, String> {
+
+		fs::read (
+			path,
+		).map_err (|error|
+			format! (
+				"Error reading {}: {}",
+				path.to_string_lossy (),
+				error)
+		).and_then (|pem|
+			X509::stack_from_pem (
+				& pem,
+			).map_err (|error|
+				format! (
+					"Error loading certificates from {}: {}",
+					path.to_string_lossy (),
+					error)
+			)
+		)
 
-	type Stream = OpensslStream;
+	}
 
-	fn wrap_client (
+	/// Performs the server side of the handshake on an accepted connection.
+	pub async fn accept <Stream> (
 		& self,
-		stream: HyperHttpStream,
-		host: & str,
-	) -> Result <OpensslStream, HyperError> {
-
-		let ssl_stream =
-			Arc::new (Mutex::new (
-				self.ssl_connector.connect (
-					host,
-					stream,
-				).map_err (|error|
-					HyperError::Ssl (Box::new (error))
-				) ?
-			));
+		stream: Stream,
+	) -> Result <SslStream <Stream>, String>
+	where Stream: AsyncRead + AsyncWrite + Unpin {
 
-		Ok (OpensslStream {
-			ssl_stream: ssl_stream,
-		})
+		// hold the lock only long enough to create the session, so that a
+		// reload never waits for a slow handshake
+
+		let ssl = {
+
+			let ssl_context =
+				self.ssl_context.read ().unwrap ();
+
+			Ssl::new (
+				& ssl_context,
+			).map_err (|error|
+				format! (
+					"Error creating TLS session: {}",
+					error)
+			) ?
+
+		};
+
+		let mut ssl_stream =
+			SslStream::new (
+				ssl,
+				stream,
+			).map_err (|error|
+				format! (
+					"Error creating TLS session: {}",
+					error)
+			) ?;
+
+		Pin::new (
+			& mut ssl_stream,
+		).accept ().await.map_err (|error|
+			format! (
+				"TLS handshake failed: {}",
+				error)
+		) ?;
+
+		Ok (ssl_stream)
 
 	}
 
@@ -0,0 +1,114 @@
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::SocketAddr as UnixSocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// Sends state notifications to systemd, as sd_notify does, for services
+/// with Type=notify.
+pub struct SystemdNotifier {
+	socket: UnixDatagram,
+	address: UnixSocketAddr,
+	watchdog_interval: Option <Duration>,
+}
+
+impl SystemdNotifier {
+
+	property_accessors! {
+		copy watchdog_interval: Option <Duration>;
+	}
+
+	/// Returns a notifier if NOTIFY_SOCKET is set. Watchdog pings are sent at
+	/// half the interval in WATCHDOG_USEC.
+	pub fn from_environment (
+	) -> Result <Option <SystemdNotifier>, String> {
+
+		let socket_name =
+			match env::var ("NOTIFY_SOCKET") {
+				Ok (ref socket_name) if ! socket_name.is_empty () =>
+					socket_name.clone (),
+				_ =>
+					return Ok (None),
+			};
+
+		let address =
+			if socket_name.starts_with ('@') {
+				UnixSocketAddr::from_abstract_name (
+					& socket_name.as_bytes () [1 .. ])
+			} else {
+				UnixSocketAddr::from_pathname (
+					& socket_name)
+			}.map_err (|error|
+				format! (
+					"Invalid NOTIFY_SOCKET {}: {}",
+					socket_name,
+					error)
+			) ?;
+
+		let socket =
+			UnixDatagram::unbound (
+			).map_err (|error|
+				format! (
+					"Error creating notify socket: {}",
+					error)
+			) ?;
+
+		let watchdog_interval =
+			env::var (
+				"WATCHDOG_USEC",
+			).ok ().and_then (|watchdog_usec|
+				watchdog_usec.parse::<u64> ().ok ()
+			).filter (|& watchdog_usec|
+				watchdog_usec > 0
+			).map (|watchdog_usec|
+				Duration::from_micros (
+					watchdog_usec / 2)
+			);
+
+		Ok (Some (SystemdNotifier {
+			socket,
+			address,
+			watchdog_interval,
+		}))
+
+	}
+
+	/// Sends one or more newline-separated assignments, such as "READY=1".
+	pub fn notify (
+		& self,
+		state: & str,
+	) -> Result <(), String> {
+
+		self.socket.send_to_addr (
+			state.as_bytes (),
+			& self.address,
+		).map_err (|error|
+			format! (
+				"Error notifying systemd: {}",
+				error)
+		) ?;
+
+		Ok (())
+
+	}
+
+	/// Sends a notification, logging rather than returning any error, since
+	/// there is nothing else to be done about it.
+	pub fn notify_or_log (
+		& self,
+		state: & str,
+	) {
+
+		self.notify (
+			state,
+		).unwrap_or_else (|error|
+			println! (
+				"{}",
+				error)
+		);
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,292 @@
+use std::fs;
+use std::io::Error as IoError;
+use std::io::ErrorKind as IoErrorKind;
+use std::mem;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+
+use crate::privileges::*;
+use crate::settings::*;
+
+/// The credentials of the process which connected to a unix socket, as
+/// reported by the kernel when the connection was accepted.
+#[ derive (Clone, Copy, Debug) ]
+pub struct PeerCredentials {
+	pub uid: libc::uid_t,
+	pub gid: libc::gid_t,
+}
+
+pub struct UnixSocketListener {
+	listener: UnixListener,
+}
+
+impl UnixSocketListener {
+
+	/// Binds the socket, replacing any stale one, with the configured mode and
+	/// ownership. A socket which still accepts connections is left alone.
+	pub fn bind (
+		unix_socket_settings: & UnixSocketSettings,
+	) -> Result <UnixSocketListener, String> {
+
+		let path =
+			& unix_socket_settings.path;
+
+		if let Ok (metadata) = fs::symlink_metadata (path) {
+
+			if ! metadata.file_type ().is_socket () {
+
+				return Err (
+					format! (
+						"Refusing to replace {}, which is not a socket",
+						path.to_string_lossy ()));
+
+			}
+
+			// without a PID file, as in the foreground, this is the only way
+			// to tell that another instance is still using it
+
+			match StdUnixStream::connect (path) {
+
+				Ok (_) =>
+					return Err (
+						format! (
+							"Unix socket {} is in use by another process",
+							path.to_string_lossy ())),
+
+				Err (ref error) if error.kind () == IoErrorKind::ConnectionRefused =>
+					(),
+
+				Err (error) =>
+					return Err (
+						format! (
+							"Error checking existing socket {}: {}",
+							path.to_string_lossy (),
+							error)),
+
+			}
+
+			fs::remove_file (
+				path,
+			).map_err (|error|
+				format! (
+					"Error removing stale socket {}: {}",
+					path.to_string_lossy (),
+					error)
+			) ?;
+
+		}
+
+		// the socket is created accessible only to us, and then opened up to
+		// the configured mode; the umask is process wide, but nothing else
+		// creates files while the listeners are bound
+
+		let previous_umask =
+			unsafe { libc::umask (0o177) };
+
+		let bind_result =
+			StdUnixListener::bind (
+				path);
+
+		unsafe { libc::umask (previous_umask) };
+
+		let std_listener =
+			bind_result.map_err (|error|
+				format! (
+					"Error binding unix socket {}: {}",
+					path.to_string_lossy (),
+					error)
+			) ?;
+
+		if let Some (ref mode) = unix_socket_settings.mode {
+
+			let mode =
+				u32::from_str_radix (
+					mode,
+					8,
+				).map_err (|_|
+					format! (
+						"Invalid unix socket mode: {}",
+						mode)
+				) ?;
+
+			fs::set_permissions (
+				path,
+				fs::Permissions::from_mode (mode),
+			).map_err (|error|
+				format! (
+					"Error setting mode of {}: {}",
+					path.to_string_lossy (),
+					error)
+			) ?;
+
+		}
+
+		if unix_socket_settings.owner.is_some ()
+			|| unix_socket_settings.group.is_some () {
+
+			unix_socket_chown (
+				path,
+				unix_socket_settings.owner.as_deref (),
+				unix_socket_settings.group.as_deref (),
+			) ?;
+
+		}
+
+		let listener =
+			std_listener.set_nonblocking (
+				true,
+			).and_then (|()|
+				UnixListener::from_std (
+					std_listener)
+			).map_err (|error|
+				format! (
+					"Error listening on unix socket {}: {}",
+					path.to_string_lossy (),
+					error)
+			) ?;
+
+		Ok (UnixSocketListener {
+			listener,
+		})
+
+	}
+
+	/// Accepts a connection, with the credentials of the connecting process,
+	/// which are required to identify it.
+	pub async fn accept (
+		& self,
+	) -> Result <(UnixStream, PeerCredentials), IoError> {
+
+		let (stream, _) =
+			self.listener.accept ().await ?;
+
+		let peer_credentials =
+			peer_credentials_get (
+				& stream,
+			) ?;
+
+		Ok ((stream, peer_credentials))
+
+	}
+
+}
+
+fn peer_credentials_get (
+	stream: & UnixStream,
+) -> Result <PeerCredentials, IoError> {
+
+	let mut ucred: libc::ucred =
+		unsafe { mem::zeroed () };
+
+	let mut ucred_size =
+		mem::size_of::<libc::ucred> () as libc::socklen_t;
+
+	let result = unsafe {
+		libc::getsockopt (
+			stream.as_raw_fd (),
+			libc::SOL_SOCKET,
+			libc::SO_PEERCRED,
+			& mut ucred as * mut libc::ucred as * mut libc::c_void,
+			& mut ucred_size)
+	};
+
+	if result != 0 {
+		return Err (IoError::last_os_error ());
+	}
+
+	Ok (PeerCredentials {
+		uid: ucred.uid,
+		gid: ucred.gid,
+	})
+
+}
+
+fn unix_socket_chown (
+	path: & Path,
+	owner: Option <& str>,
+	group: Option <& str>,
+) -> Result <(), String> {
+
+	// an id of -1 leaves it unchanged
+
+	let uid =
+		match owner {
+			Some (owner) => user_id_for_name (owner) ?,
+			None => libc::uid_t::MAX,
+		};
+
+	let gid =
+		match group {
+			Some (group) => group_id_for_name (group) ?,
+			None => libc::gid_t::MAX,
+		};
+
+	path_chown (
+		path,
+		uid,
+		gid)
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::process;
+
+	use tokio::runtime::Builder as RuntimeBuilder;
+
+	use super::*;
+
+	#[ test ]
+	fn live_sockets_are_kept_and_stale_ones_replaced () {
+
+		let runtime =
+			RuntimeBuilder::new_current_thread ().enable_io ().build ().unwrap ();
+
+		let _runtime_guard =
+			runtime.enter ();
+
+		let path =
+			env::temp_dir ().join (
+				format! (
+					"gridlinker-unix-socket-test-{}",
+					process::id ()));
+
+		let unix_socket_settings =
+			UnixSocketSettings {
+				path: path.clone (),
+				mode: None,
+				owner: None,
+				group: None,
+				peer_identities: Vec::new (),
+			};
+
+		let listener =
+			UnixSocketListener::bind (& unix_socket_settings).unwrap ();
+
+		assert_eq! (
+			fs::metadata (& path).unwrap ().permissions ().mode () & 0o777,
+			0o600);
+
+		assert! (
+			UnixSocketListener::bind (& unix_socket_settings).is_err ());
+
+		drop (listener);
+
+		UnixSocketListener::bind (& unix_socket_settings).unwrap ();
+
+		fs::remove_file (& path).unwrap ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
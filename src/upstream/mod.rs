@@ -0,0 +1,10 @@
+#[ allow (clippy::module_inception) ]
+mod upstream;
+mod upstream_client;
+mod upstream_data;
+
+pub use self::upstream::*;
+pub use self::upstream_client::*;
+pub use self::upstream_data::*;
+
+// ex: noet ts=4 filetype=rust
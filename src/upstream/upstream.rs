@@ -0,0 +1,590 @@
+use std::cmp;
+use std::future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use arc_swap::ArcSwap;
+
+use tokio::runtime::Handle as RuntimeHandle;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::etcd::*;
+use crate::settings::*;
+use crate::systemd::*;
+use crate::upstream::*;
+
+pub struct Upstream {
+	state: Arc <Mutex <State>>,
+	snapshot: Arc <ArcSwap <UpstreamSnapshot>>,
+	watch_task: JoinHandle <()>,
+	resync_sender: mpsc::Sender <ResyncReply>,
+}
+
+/// A request for the watch task to reload everything, which is answered
+/// with the etcd index of the new snapshot.
+type ResyncReply =
+	oneshot::Sender <Result <u64, String>>;
+
+enum WatchEvent {
+	Change (Result <(), String>),
+	Resync (ResyncReply),
+	Idle,
+}
+
+/// The state of the watch, for monitoring. The data itself is published
+/// separately, in snapshots, so that readers never wait on this.
+struct State {
+	member: String,
+	watch_alive: bool,
+	last_update: Instant,
+	watch_errors: u64,
+	watch_reconnects: u64,
+	watch_consecutive_errors: u64,
+	watch_last_error: Option <String>,
+}
+
+/// An immutable copy of the cached data, with the etcd index it reflects.
+/// Readers keep the one they loaded for a consistent view.
+pub struct UpstreamSnapshot {
+	data: UpstreamData,
+	etcd_index: u64,
+}
+
+impl UpstreamSnapshot {
+
+	property_accessors! {
+		ref data: & UpstreamData;
+		copy etcd_index: u64;
+	}
+
+}
+
+/// A point-in-time summary of the upstream connection, for monitoring.
+pub struct UpstreamStatus {
+	pub member: String,
+	pub etcd_index: u64,
+	pub cache_size: usize,
+	pub watch_alive: bool,
+	pub last_update: Instant,
+	pub watch_errors: u64,
+	pub watch_reconnects: u64,
+	pub watch_consecutive_errors: u64,
+	pub watch_last_error: Option <String>,
+}
+
+impl Upstream {
+
+	/// Loads the initial data, blocking until it is complete, and then starts
+	/// watching for changes on the runtime.
+	pub fn start (
+		settings: Arc <Settings>,
+		mut upstream_client: UpstreamClient,
+		runtime: & RuntimeHandle,
+		notifier: Option <Arc <SystemdNotifier>>,
+	) -> Result <Upstream, String> {
+
+		let (resource_data, etcd_index) =
+			runtime.block_on (
+				Self::resource_data_load_initial (
+					settings.clone (),
+					& mut upstream_client),
+			) ?;
+
+		let snapshot =
+			Arc::new (
+				ArcSwap::from_pointee (
+					UpstreamSnapshot {
+						data: resource_data,
+						etcd_index,
+					}));
+
+		let state =
+			Arc::new (Mutex::new (
+				State {
+					member: format! (
+						"{}:{}",
+						settings.upstream.server_names [0],
+						settings.upstream.server_port),
+					watch_alive: true,
+					last_update: Instant::now (),
+					watch_errors: 0,
+					watch_reconnects: 0,
+					watch_consecutive_errors: 0,
+					watch_last_error: None,
+				}
+			));
+
+		let (resync_sender, resync_receiver) =
+			mpsc::channel (1);
+
+		let watch_task =
+			runtime.spawn (
+				Self::watch_task (
+					settings,
+					state.clone (),
+					snapshot.clone (),
+					upstream_client,
+					resync_receiver,
+					notifier));
+
+		Ok (Upstream {
+			state,
+			snapshot,
+			watch_task,
+			resync_sender,
+		})
+
+	}
+
+	async fn resource_data_load_initial (
+		settings: Arc <Settings>,
+		upstream_client: & mut UpstreamClient,
+	) -> Result <(UpstreamData, u64), String> {
+
+		println! (
+			"Load initial data ...");
+
+		let (etcd_index, response_data) =
+			upstream_client.get (
+				& format! (
+					"/v2/keys{}/resource?recursive=true",
+					settings.upstream.key_prefix),
+			).await ?;
+
+		let mut resource_data =
+			UpstreamData::new ();
+
+		Self::store_node_recursive (
+			settings.clone (),
+			& mut resource_data,
+			& response_data.node);
+
+		println! (
+			"Got {} nodes, etcd index is {}",
+			resource_data.len (),
+			etcd_index);
+
+		Ok (
+			(
+				resource_data,
+				etcd_index,
+			)
+		)
+
+	}
+
+	/// Stores a node and everything below it. Directories are stored as well
+	/// as values, so that the hierarchy, including empty directories, can be
+	/// listed.
+	fn store_node_recursive (
+		settings: Arc <Settings>,
+		data: & mut UpstreamData,
+		node: & EtcdNode,
+	) {
+
+		let key =
+			& node.key [settings.upstream.key_prefix.len () .. ];
+
+		data.insert (
+			NodeData::new (
+				key.to_owned (),
+				node.value.clone ().unwrap_or_default (),
+				node.dir,
+				node.created_index,
+				node.modified_index));
+
+		for child_node in node.nodes.iter () {
+
+			Self::store_node_recursive (
+				settings.clone (),
+				data,
+				child_node,
+			);
+
+		}
+
+	}
+
+	/// Applies a change received from the watch. Deletions and expiries
+	/// arrive as a node with no value, which must be removed rather than
+	/// stored.
+	fn apply_response (
+		settings: Arc <Settings>,
+		data: & mut UpstreamData,
+		response: & EtcdResponse,
+	) {
+
+		match response.action.as_str () {
+
+			"delete" | "expire" | "compareAndDelete" =>
+				data.remove_recursive (
+					& response.node.key [
+						settings.upstream.key_prefix.len () .. ]),
+
+			_ =>
+				Self::store_node_recursive (
+					settings,
+					data,
+					& response.node),
+
+		}
+
+	}
+
+	async fn watch_task (
+		settings: Arc <Settings>,
+		state: Arc <Mutex <State>>,
+		snapshot: Arc <ArcSwap <UpstreamSnapshot>>,
+		mut upstream_client: UpstreamClient,
+		mut resync_receiver: mpsc::Receiver <ResyncReply>,
+		notifier: Option <Arc <SystemdNotifier>>,
+	) {
+
+		let _watch_guard =
+			WatchGuard {
+				state: state.clone (),
+			};
+
+		// pings are only sent once the loop has come round, so that a task
+		// stuck part way through is noticed; a watch which sees no changes for
+		// a whole interval is abandoned and started again, to come round
+
+		let watchdog_interval =
+			notifier.as_ref ().and_then (|notifier|
+				notifier.watchdog_interval ());
+
+		loop {
+
+			// a resync abandons any watch in progress, which is then resumed
+			// from the new snapshot
+
+			let watch_event =
+				tokio::select! {
+
+				result = Self::watch_once (
+					settings.clone (),
+					state.clone (),
+					snapshot.clone (),
+					& mut upstream_client,
+				) =>
+					WatchEvent::Change (result),
+
+				Some (resync_reply) = resync_receiver.recv () =>
+					WatchEvent::Resync (resync_reply),
+
+				() = Self::watchdog_sleep (watchdog_interval) =>
+					WatchEvent::Idle,
+
+				};
+
+			match watch_event {
+
+				WatchEvent::Resync (resync_reply) => {
+
+					let result =
+						Self::resync_once (
+							settings.clone (),
+							state.clone (),
+							snapshot.clone (),
+							& mut upstream_client,
+						).await;
+
+					match result {
+
+						Ok (etcd_index) =>
+							Self::notify (
+								& notifier,
+								& format! (
+									"WATCHDOG=1\nSTATUS=Resynced, etcd index is {}",
+									etcd_index)),
+
+						Err (ref error) =>
+							Self::notify (
+								& notifier,
+								& format! (
+									"WATCHDOG=1\nSTATUS=Error resyncing upstream: {}",
+									error)),
+
+					}
+
+					resync_reply.send (
+						result,
+					).unwrap_or (());
+
+				},
+
+				WatchEvent::Change (Ok (())) => {
+
+					state.lock ().unwrap ().watch_consecutive_errors = 0;
+
+					Self::notify (
+						& notifier,
+						& format! (
+							"WATCHDOG=1\nSTATUS=Watching, etcd index is {}",
+							snapshot.load ().etcd_index));
+
+				},
+
+				WatchEvent::Idle =>
+					Self::notify (
+						& notifier,
+						"WATCHDOG=1"),
+
+				WatchEvent::Change (Err (error)) => {
+
+					println! (
+						"Error watching upstream: {}",
+						error);
+
+					Self::notify (
+						& notifier,
+						& format! (
+							"WATCHDOG=1\nSTATUS=Error watching upstream: {}",
+							error));
+
+					let consecutive_errors = {
+
+						let mut state =
+							state.lock ().unwrap ();
+
+						state.watch_errors += 1;
+						state.watch_consecutive_errors += 1;
+						state.watch_last_error = Some (error);
+
+						state.watch_consecutive_errors
+
+					};
+
+					// back off exponentially, up to half a minute, but not
+					// for so long that the watchdog expires
+
+					let backoff =
+						Duration::from_secs (
+							1 << cmp::min (consecutive_errors - 1, 5));
+
+					time::sleep (
+						watchdog_interval.map_or (backoff, |watchdog_interval|
+							cmp::min (backoff, watchdog_interval)),
+					).await;
+
+					state.lock ().unwrap ().watch_reconnects += 1;
+
+				},
+
+			}
+
+		}
+
+	}
+
+	/// Waits for the watchdog interval, or forever if there is no watchdog.
+	async fn watchdog_sleep (
+		watchdog_interval: Option <Duration>,
+	) {
+
+		match watchdog_interval {
+			Some (watchdog_interval) => time::sleep (watchdog_interval).await,
+			None => future::pending::<()> ().await,
+		}
+
+	}
+
+	async fn watch_once (
+		settings: Arc <Settings>,
+		state: Arc <Mutex <State>>,
+		snapshot: Arc <ArcSwap <UpstreamSnapshot>>,
+		upstream_client: & mut UpstreamClient,
+	) -> Result <(), String> {
+
+		// this task is the only writer, so the snapshot can't change between
+		// reading it here and replacing it below
+
+		let previous_snapshot =
+			snapshot.load_full ();
+
+		let (etcd_index, response_data) =
+			upstream_client.get (
+				& format! (
+					"/v2/keys{}/resource\
+					?wait=true\
+					&waitIndex={}\
+					&recursive=true",
+					settings.upstream.key_prefix,
+					previous_snapshot.etcd_index + 1),
+			).await ?;
+
+		// the persistent maps make this clone cheap, and readers holding the
+		// previous snapshot are unaffected by the changes
+
+		let mut data =
+			previous_snapshot.data.clone ();
+
+		Self::apply_response (
+			settings.clone (),
+			& mut data,
+			& response_data);
+
+		println! (
+			"Update: Got {} nodes, etcd index is {}",
+			data.len (),
+			etcd_index);
+
+		snapshot.store (
+			Arc::new (
+				UpstreamSnapshot {
+					data,
+					etcd_index,
+				}));
+
+		state.lock ().unwrap ().last_update =
+			Instant::now ();
+
+		Ok (())
+
+	}
+
+	fn notify (
+		notifier: & Option <Arc <SystemdNotifier>>,
+		state: & str,
+	) {
+
+		if let Some (ref notifier) = * notifier {
+
+			notifier.notify_or_log (
+				state);
+
+		}
+
+	}
+
+	/// Replaces the cached data with a full load from etcd, in case it has
+	/// drifted or the watch has fallen too far behind to catch up.
+	async fn resync_once (
+		settings: Arc <Settings>,
+		state: Arc <Mutex <State>>,
+		snapshot: Arc <ArcSwap <UpstreamSnapshot>>,
+		upstream_client: & mut UpstreamClient,
+	) -> Result <u64, String> {
+
+		println! (
+			"Resync requested");
+
+		let (data, etcd_index) =
+			Self::resource_data_load_initial (
+				settings,
+				upstream_client,
+			).await ?;
+
+		snapshot.store (
+			Arc::new (
+				UpstreamSnapshot {
+					data,
+					etcd_index,
+				}));
+
+		let mut state =
+			state.lock ().unwrap ();
+
+		state.last_update = Instant::now ();
+		state.watch_consecutive_errors = 0;
+
+		Ok (etcd_index)
+
+	}
+
+	/// Asks the watch task to reload everything from etcd, and waits for it
+	/// to finish. This must not be called from within the runtime.
+	pub fn resync (
+		& self,
+	) -> Result <u64, String> {
+
+		let (reply_sender, reply_receiver) =
+			oneshot::channel ();
+
+		self.resync_sender.blocking_send (
+			reply_sender,
+		).map_err (|_|
+			"Upstream watch is not running".to_string ()
+		) ?;
+
+		reply_receiver.blocking_recv (
+		).map_err (|_|
+			"Upstream watch stopped during resync".to_string ()
+		) ?
+
+	}
+
+	/// Stops the watch. It is only ever cancelled while waiting, so the
+	/// snapshot is left as it was after the last complete update.
+	pub fn stop (
+		& self,
+	) {
+
+		self.watch_task.abort ();
+
+	}
+
+	/// Returns the current snapshot of the cached data. This never blocks,
+	/// and the snapshot stays consistent however long it is held.
+	pub fn snapshot (
+		& self,
+	) -> Arc <UpstreamSnapshot> {
+
+		self.snapshot.load_full ()
+
+	}
+
+	pub fn status (
+		& self,
+	) -> UpstreamStatus {
+
+		let snapshot =
+			self.snapshot.load_full ();
+
+		let state =
+			self.state.lock ().unwrap ();
+
+		UpstreamStatus {
+			member: state.member.clone (),
+			etcd_index: snapshot.etcd_index,
+			cache_size: snapshot.data.len (),
+			watch_alive: state.watch_alive,
+			last_update: state.last_update,
+			watch_errors: state.watch_errors,
+			watch_reconnects: state.watch_reconnects,
+			watch_consecutive_errors: state.watch_consecutive_errors,
+			watch_last_error: state.watch_last_error.clone (),
+		}
+
+	}
+
+}
+
+/// Marks the watch as no longer running when the task ends, including when
+/// it panics.
+struct WatchGuard {
+	state: Arc <Mutex <State>>,
+}
+
+impl Drop for WatchGuard {
+
+	fn drop (
+		& mut self,
+	) {
+
+		let mut state =
+			self.state.lock ().unwrap_or_else (|error|
+				error.into_inner ());
+
+		state.watch_alive = false;
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
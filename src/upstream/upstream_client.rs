@@ -0,0 +1,270 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use http_body_util::BodyExt;
+use http_body_util::Empty;
+
+use hyper::Request as HyperRequest;
+use hyper::Response as HyperResponse;
+use hyper::body::Incoming as HyperIncoming;
+use hyper::client::conn::http1 as hyper_http1;
+use hyper::header;
+
+use hyper_util::rt::TokioIo;
+
+use openssl::ssl::SslConnector;
+use openssl::ssl::SslFiletype;
+use openssl::ssl::SslMethod;
+
+use tokio::net::TcpStream;
+
+use tokio_openssl::SslStream;
+
+use crate::etcd::*;
+use crate::settings::*;
+
+type EtcdSender =
+	hyper_http1::SendRequest <Empty <Bytes>>;
+
+/// Makes requests to etcd, over a connection which is kept open between them.
+pub struct UpstreamClient {
+	settings: Arc <Settings>,
+	ssl_connector: SslConnector,
+	sender: Option <EtcdSender>,
+}
+
+impl UpstreamClient {
+
+	pub fn new (
+		settings: Arc <Settings>,
+	) -> Result <UpstreamClient, String> {
+
+		let mut ssl_connector_builder =
+			SslConnector::builder (
+				SslMethod::tls (),
+			).map_err (|error|
+				format! (
+					"Error initialising openssl: {}",
+					error)
+			) ?;
+
+		ssl_connector_builder.set_ca_file (
+			& settings.upstream.ca_certificate,
+		).map_err (|error|
+			format! (
+				"Error loading ca certificate {}: {}",
+				settings.upstream.ca_certificate.to_string_lossy (),
+				error)
+		) ?;
+
+		ssl_connector_builder.set_certificate_file (
+			& settings.upstream.certificate,
+			SslFiletype::PEM,
+		).map_err (|error|
+			format! (
+				"Error loading certificate {}: {}",
+				settings.upstream.certificate.to_string_lossy (),
+				error)
+		) ?;
+
+		ssl_connector_builder.set_private_key_file (
+			& settings.upstream.private_key,
+			SslFiletype::PEM,
+		).map_err (|error|
+			format! (
+				"Error loading private key {}: {}",
+				settings.upstream.private_key.to_string_lossy (),
+				error)
+		) ?;
+
+		Ok (UpstreamClient {
+			settings,
+			ssl_connector: ssl_connector_builder.build (),
+			sender: None,
+		})
+
+	}
+
+	/// Fetches a path from etcd, returning the etcd index from the
+	/// response headers along with the decoded response.
+	pub async fn get (
+		& mut self,
+		path: & str,
+	) -> Result <(u64, EtcdResponse), String> {
+
+		let server_url =
+			format! (
+				"https://{}:{}{}",
+				self.settings.upstream.server_names [0],
+				self.settings.upstream.server_port,
+				path);
+
+		let mut sender =
+			match self.sender.take () {
+				Some (sender) if ! sender.is_closed () => sender,
+				_ => self.connect (& server_url).await ?,
+			};
+
+		sender.ready ().await.map_err (|error|
+			format! (
+				"Error connecting to {}: {}",
+				server_url,
+				error)
+		) ?;
+
+		let hyper_request =
+			HyperRequest::get (
+				path,
+			).header (
+				header::HOST,
+				format! (
+					"{}:{}",
+					self.settings.upstream.server_names [0],
+					self.settings.upstream.server_port),
+			).body (
+				Empty::new (),
+			).map_err (|error|
+				format! (
+					"Error requesting {}: {}",
+					server_url,
+					error)
+			) ?;
+
+		let response =
+			sender.send_request (
+				hyper_request,
+			).await.map_err (|error|
+				format! (
+					"Error requesting {}: {}",
+					server_url,
+					error)
+			) ?;
+
+		let etcd_index =
+			Self::etcd_index_from_response (
+				& server_url,
+				& response,
+			) ?;
+
+		let response_bytes =
+			response.into_body ().collect ().await.map_err (|error|
+				format! (
+					"Error reading from {}: {}",
+					server_url,
+					error)
+			) ?.to_bytes ();
+
+		let response_data: EtcdResponse =
+			serde_json::from_slice (
+				& response_bytes,
+			).map_err (|error|
+				format! (
+					"Error decoding response from {}: {}",
+					server_url,
+					error)
+			) ?;
+
+		// only a connection which completed the request is reused
+
+		self.sender =
+			Some (sender);
+
+		Ok ((etcd_index, response_data))
+
+	}
+
+	async fn connect (
+		& self,
+		server_url: & str,
+	) -> Result <EtcdSender, String> {
+
+		let server_name =
+			& self.settings.upstream.server_names [0];
+
+		let tcp_stream =
+			TcpStream::connect (
+				(server_name.as_str (), self.settings.upstream.server_port),
+			).await.map_err (|error|
+				format! (
+					"Error connecting to {}: {}",
+					server_url,
+					error)
+			) ?;
+
+		let ssl =
+			self.ssl_connector.configure (
+			).and_then (|ssl_config|
+				ssl_config.into_ssl (
+					server_name)
+			).map_err (|error|
+				format! (
+					"Error connecting to {}: {}",
+					server_url,
+					error)
+			) ?;
+
+		let mut ssl_stream =
+			SslStream::new (
+				ssl,
+				tcp_stream,
+			).map_err (|error|
+				format! (
+					"Error connecting to {}: {}",
+					server_url,
+					error)
+			) ?;
+
+		Pin::new (
+			& mut ssl_stream,
+		).connect ().await.map_err (|error|
+			format! (
+				"Error connecting to {}: {}",
+				server_url,
+				error)
+		) ?;
+
+		let (sender, connection) =
+			hyper_http1::handshake (
+				TokioIo::new (ssl_stream),
+			).await.map_err (|error|
+				format! (
+					"Error connecting to {}: {}",
+					server_url,
+					error)
+			) ?;
+
+		// the connection is driven separately, and ends when the sender is
+		// dropped; any error shows up in the next request
+
+		tokio::spawn (async move {
+			connection.await.unwrap_or (());
+		});
+
+		Ok (sender)
+
+	}
+
+	fn etcd_index_from_response (
+		server_url: & str,
+		response: & HyperResponse <HyperIncoming>,
+	) -> Result <u64, String> {
+
+		response.headers ().get (
+			"X-Etcd-Index",
+		).and_then (|value|
+			value.to_str ().ok ()
+		).and_then (|value|
+			value.parse ().ok ()
+		).ok_or_else (||
+			format! (
+				"Missing or invalid X-Etcd-Index from {}",
+				server_url)
+		)
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
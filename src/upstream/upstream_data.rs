@@ -0,0 +1,711 @@
+use std::iter;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use im::OrdMap;
+use im::OrdSet;
+
+use serde_json::Value as JsonValue;
+
+/// The cached upstream keys, in key order, with resources also indexed by
+/// name, class and namespace. The namespace index only holds namespaces given
+/// in a resource's own identity, not those taken from its class. The maps are
+/// persistent, so cloning for a new snapshot is cheap.
+#[ derive (Clone) ]
+pub struct UpstreamData {
+	nodes: OrdMap <String, Arc <NodeData>>,
+	resources: OrdMap <String, UpstreamResource>,
+	resources_by_class: OrdMap <String, OrdSet <String>>,
+	resources_by_namespace: OrdMap <String, OrdSet <String>>,
+}
+
+#[ derive (Clone) ]
+struct UpstreamResource {
+	node: Arc <NodeData>,
+	class: Option <String>,
+	namespace: Option <String>,
+}
+
+impl UpstreamData {
+
+	pub fn new (
+	) -> UpstreamData {
+
+		UpstreamData {
+			nodes: OrdMap::new (),
+			resources: OrdMap::new (),
+			resources_by_class: OrdMap::new (),
+			resources_by_namespace: OrdMap::new (),
+		}
+
+	}
+
+	pub fn len (
+		& self,
+	) -> usize {
+
+		self.nodes.len ()
+
+	}
+
+	pub fn get (
+		& self,
+		key: & str,
+	) -> Option <& Arc <NodeData>> {
+
+		self.nodes.get (
+			key)
+
+	}
+
+	/// Every node below the given key, in key order, but not the key itself.
+	pub fn descendants <'a> (
+		& 'a self,
+		key: & str,
+	) -> impl Iterator <Item = & 'a Arc <NodeData>> {
+
+		let prefix =
+			format! ("{}/", key);
+
+		// the keys below the prefix are contiguous, so the first which
+		// doesn't match is the end
+
+		self.nodes.range (
+			prefix.clone () ..,
+		).take_while (move |(key, _)|
+			key.starts_with (& prefix)
+		).map (|(_, node)|
+			node
+		)
+
+	}
+
+	/// The nodes immediately below the given key, in key order. Deeper nodes
+	/// are skipped over rather than visited.
+	pub fn children <'a> (
+		& 'a self,
+		key: & str,
+	) -> impl Iterator <Item = & 'a Arc <NodeData>> {
+
+		let prefix =
+			format! ("{}/", key);
+
+		let mut range =
+			self.nodes.range (
+				prefix.clone () ..);
+
+		let mut skip_key =
+			String::new ();
+
+		iter::from_fn (move || {
+
+			loop {
+
+				let (key, node) =
+					range.next () ?;
+
+				if ! key.starts_with (& prefix) {
+					return None;
+				}
+
+				let position =
+					match key [prefix.len () .. ].find ('/') {
+						Some (position) => position,
+						None => return Some (node),
+					};
+
+				// this is below a child, which may not have a node of its
+				// own; "0" follows "/", so seeking to the child's key with "0"
+				// appended skips everything else below it
+
+				skip_key.clear ();
+				skip_key.push_str (& key [ .. prefix.len () + position]);
+				skip_key.push ('0');
+
+				range =
+					self.nodes.range::<_, str> ((
+						Bound::Included (skip_key.as_str ()),
+						Bound::Unbounded,
+					));
+
+			}
+
+		})
+
+	}
+
+	/// The data node for the named resource.
+	pub fn resource (
+		& self,
+		resource_name: & str,
+	) -> Option <& Arc <NodeData>> {
+
+		self.resources.get (
+			resource_name,
+		).map (|resource|
+			& resource.node
+		)
+
+	}
+
+	/// Every resource, in name order.
+	pub fn resources (
+		& self,
+	) -> impl Iterator <Item = (& str, & Arc <NodeData>)> {
+
+		self.resources_with_prefix (
+			"")
+
+	}
+
+	/// Every resource whose name starts with the given prefix, in name order.
+	pub fn resources_with_prefix <'a> (
+		& 'a self,
+		name_prefix: & str,
+	) -> impl Iterator <Item = (& 'a str, & 'a Arc <NodeData>)> {
+
+		let prefix =
+			name_prefix.to_owned ();
+
+		self.resources.range (
+			prefix.clone () ..,
+		).take_while (move |(resource_name, _)|
+			resource_name.starts_with (& prefix)
+		).map (|(resource_name, resource)|
+			(resource_name.as_str (), & resource.node)
+		)
+
+	}
+
+	pub fn resources_for_class (
+		& self,
+		class_name: & str,
+	) -> Vec <(& str, & Arc <NodeData>)> {
+
+		self.resources_for_names (
+			self.resources_by_class.get (
+				class_name))
+
+	}
+
+	/// Resources which name the namespace explicitly in their identity. See
+	/// the note on the namespace index above.
+	pub fn resources_for_namespace (
+		& self,
+		namespace_name: & str,
+	) -> Vec <(& str, & Arc <NodeData>)> {
+
+		self.resources_for_names (
+			self.resources_by_namespace.get (
+				namespace_name))
+
+	}
+
+	fn resources_for_names <'a> (
+		& 'a self,
+		resource_names: Option <& 'a OrdSet <String>>,
+	) -> Vec <(& 'a str, & 'a Arc <NodeData>)> {
+
+		resource_names.into_iter ().flat_map (|resource_names|
+			resource_names.iter ()
+		).filter_map (|resource_name|
+			self.resources.get (
+				resource_name,
+			).map (|resource|
+				(resource_name.as_str (), & resource.node)
+			)
+		).collect ()
+
+	}
+
+	/// Stores a node, replacing any existing node with the same key. Missing
+	/// parent directories are created, since a watch event only carries the
+	/// node which changed.
+	pub fn insert (
+		& mut self,
+		node: NodeData,
+	) {
+
+		let node =
+			Arc::new (node);
+
+		self.parents_create (
+			& node);
+
+		if let Some (resource_name) =
+			resource_name_for_data_key (node.key ()) {
+
+			self.index_remove (
+				resource_name);
+
+			if ! node.dir () {
+
+				self.index_add (
+					resource_name,
+					& node);
+
+			}
+
+		}
+
+		self.nodes.insert (
+			node.key ().to_owned (),
+			node);
+
+	}
+
+	fn parents_create (
+		& mut self,
+		node: & NodeData,
+	) {
+
+		let mut parent_key =
+			node.key ();
+
+		// if a parent exists then so do its own parents, so stop there
+
+		while let Some (position) = parent_key.rfind ('/') {
+
+			parent_key =
+				& parent_key [ .. position];
+
+			if parent_key.is_empty ()
+				|| self.nodes.contains_key (parent_key) {
+
+				break;
+
+			}
+
+			self.nodes.insert (
+				parent_key.to_owned (),
+				Arc::new (NodeData::new (
+					parent_key.to_owned (),
+					String::new (),
+					true,
+					node.modified_index (),
+					node.modified_index ())));
+
+		}
+
+	}
+
+	/// Removes a node and everything below it.
+	pub fn remove_recursive (
+		& mut self,
+		key: & str,
+	) {
+
+		let mut removed_keys: Vec <String> =
+			self.descendants (
+				key,
+			).map (|node|
+				node.key ().to_owned ()
+			).collect ();
+
+		removed_keys.push (
+			key.to_owned ());
+
+		for removed_key in removed_keys {
+
+			if let Some (resource_name) =
+				resource_name_for_data_key (& removed_key) {
+
+				self.index_remove (
+					resource_name);
+
+			}
+
+			self.nodes.remove (
+				& removed_key);
+
+		}
+
+	}
+
+	fn index_add (
+		& mut self,
+		resource_name: & str,
+		node: & Arc <NodeData>,
+	) {
+
+		let value: Option <JsonValue> =
+			serde_json::from_str (
+				node.value (),
+			).ok ();
+
+		let identity_string = |name: & str| -> Option <String> {
+			value.as_ref ().and_then (|value|
+				value.pointer (
+					& format! ("/identity/{}", name),
+				)
+			).and_then (|value|
+				value.as_str ()
+			).map (|value|
+				value.to_owned ()
+			)
+		};
+
+		let class =
+			identity_string ("class");
+
+		let namespace =
+			identity_string ("namespace");
+
+		if let Some (ref class) = class {
+
+			index_set_add (
+				& mut self.resources_by_class,
+				class,
+				resource_name);
+
+		}
+
+		if let Some (ref namespace) = namespace {
+
+			index_set_add (
+				& mut self.resources_by_namespace,
+				namespace,
+				resource_name);
+
+		}
+
+		self.resources.insert (
+			resource_name.to_owned (),
+			UpstreamResource {
+				node: node.clone (),
+				class,
+				namespace,
+			});
+
+	}
+
+	fn index_remove (
+		& mut self,
+		resource_name: & str,
+	) {
+
+		let resource =
+			match self.resources.remove (resource_name) {
+				Some (resource) => resource,
+				None => return,
+			};
+
+		if let Some (ref class) = resource.class {
+
+			index_set_remove (
+				& mut self.resources_by_class,
+				class,
+				resource_name);
+
+		}
+
+		if let Some (ref namespace) = resource.namespace {
+
+			index_set_remove (
+				& mut self.resources_by_namespace,
+				namespace,
+				resource_name);
+
+		}
+
+	}
+
+}
+
+fn index_set_add (
+	index: & mut OrdMap <String, OrdSet <String>>,
+	index_key: & str,
+	resource_name: & str,
+) {
+
+	let mut resource_names =
+		index.get (
+			index_key,
+		).cloned (
+		).unwrap_or_default ();
+
+	resource_names.insert (
+		resource_name.to_owned ());
+
+	index.insert (
+		index_key.to_owned (),
+		resource_names);
+
+}
+
+fn index_set_remove (
+	index: & mut OrdMap <String, OrdSet <String>>,
+	index_key: & str,
+	resource_name: & str,
+) {
+
+	let mut resource_names =
+		match index.get (index_key) {
+			Some (resource_names) => resource_names.clone (),
+			None => return,
+		};
+
+	resource_names.remove (
+		resource_name);
+
+	if resource_names.is_empty () {
+
+		index.remove (
+			index_key);
+
+	} else {
+
+		index.insert (
+			index_key.to_owned (),
+			resource_names);
+
+	}
+
+}
+
+/// Returns the name of the resource whose data is held in a key, which takes
+/// the form "/resource/<name>/data".
+fn resource_name_for_data_key (
+	key: & str,
+) -> Option <& str> {
+
+	if key.len () <= "/resource/".len () + "/data".len ()
+		|| ! key.starts_with ("/resource/")
+		|| ! key.ends_with ("/data") {
+
+		return None;
+
+	}
+
+	Some (
+		& key [
+			"/resource/".len ()
+			..
+			key.len () - "/data".len ()
+		])
+
+}
+
+pub struct NodeData {
+	key: String,
+	value: String,
+	dir: bool,
+	created_index: u64,
+	modified_index: u64,
+}
+
+impl NodeData {
+
+	pub fn new (
+		key: String,
+		value: String,
+		dir: bool,
+		created_index: u64,
+		modified_index: u64,
+	) -> NodeData {
+
+		NodeData {
+			key,
+			value,
+			dir,
+			created_index,
+			modified_index,
+		}
+
+	}
+
+	property_accessors! {
+		ref key: & str;
+		ref value: & str;
+		copy dir: bool;
+		copy created_index: u64;
+		copy modified_index: u64;
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn value_node (
+		key: & str,
+		value: & str,
+	) -> NodeData {
+
+		NodeData::new (
+			key.to_owned (),
+			value.to_owned (),
+			false,
+			1,
+			1)
+
+	}
+
+	fn dir_node (
+		key: & str,
+	) -> NodeData {
+
+		NodeData::new (
+			key.to_owned (),
+			String::new (),
+			true,
+			1,
+			1)
+
+	}
+
+	fn resource_node (
+		name: & str,
+		class: & str,
+		namespace: & str,
+	) -> NodeData {
+
+		value_node (
+			& format! ("/resource/{}/data", name),
+			& format! (
+				"{{\"identity\": {{\"class\": \"{}\", \"namespace\": \"{}\"}}}}",
+				class,
+				namespace))
+
+	}
+
+	fn child_keys (
+		data: & UpstreamData,
+		key: & str,
+	) -> Vec <String> {
+
+		data.children (
+			key,
+		).map (|node|
+			node.key ().to_owned ()
+		).collect ()
+
+	}
+
+	fn resource_names (
+		resources: Vec <(& str, & Arc <NodeData>)>,
+	) -> Vec <String> {
+
+		resources.into_iter ().map (|(resource_name, _)|
+			resource_name.to_owned ()
+		).collect ()
+
+	}
+
+	#[ test ]
+	fn insert_creates_missing_parents () {
+
+		let mut data =
+			UpstreamData::new ();
+
+		data.insert (dir_node ("/resource"));
+		data.insert (dir_node ("/resource/a"));
+		data.insert (resource_node ("a", "web", "live"));
+
+		// as from a watch event, without its directory
+
+		data.insert (resource_node ("b", "web", "live"));
+
+		assert! (data.get ("/resource/b").unwrap ().dir ());
+		assert! (data.resource ("b").is_some ());
+
+		assert_eq! (
+			child_keys (& data, "/resource"),
+			vec! ["/resource/a", "/resource/b"]);
+
+		// an existing directory is left alone
+
+		data.insert (value_node ("/resource/a/other", "x"));
+
+		assert_eq! (data.len (), 6);
+
+	}
+
+	#[ test ]
+	fn children_skips_nested_nodes () {
+
+		let mut data =
+			UpstreamData::new ();
+
+		data.insert (dir_node ("/a"));
+		data.insert (value_node ("/a/b", "1"));
+		data.insert (value_node ("/a/c/d/e", "2"));
+		data.insert (value_node ("/a/c/f", "3"));
+		data.insert (value_node ("/a/c0", "4"));
+		data.insert (value_node ("/ab", "5"));
+
+		assert_eq! (
+			child_keys (& data, "/a"),
+			vec! ["/a/b", "/a/c", "/a/c0"]);
+
+		assert_eq! (
+			child_keys (& data, "/a/c"),
+			vec! ["/a/c/d", "/a/c/f"]);
+
+		assert_eq! (
+			child_keys (& data, ""),
+			vec! ["/a", "/ab"]);
+
+	}
+
+	#[ test ]
+	fn indexes_follow_inserts_and_removals () {
+
+		let mut data =
+			UpstreamData::new ();
+
+		data.insert (resource_node ("a", "web", "live"));
+		data.insert (resource_node ("b", "web", "test"));
+
+		assert_eq! (
+			resource_names (data.resources_for_class ("web")),
+			vec! ["a", "b"]);
+
+		assert_eq! (
+			resource_names (data.resources_for_namespace ("live")),
+			vec! ["a"]);
+
+		// changing the class and namespace moves the resource between
+		// index entries
+
+		data.insert (resource_node ("a", "db", "test"));
+
+		assert_eq! (
+			resource_names (data.resources_for_class ("web")),
+			vec! ["b"]);
+
+		assert_eq! (
+			resource_names (data.resources_for_class ("db")),
+			vec! ["a"]);
+
+		assert_eq! (
+			resource_names (data.resources_for_namespace ("test")),
+			vec! ["a", "b"]);
+
+		assert! (data.resources_for_namespace ("live").is_empty ());
+
+		// removing the resource's directory removes it from every index
+
+		data.remove_recursive ("/resource/b");
+
+		assert! (data.resource ("b").is_none ());
+		assert! (data.get ("/resource/b/data").is_none ());
+		assert! (data.resources_for_class ("web").is_empty ());
+
+		assert_eq! (
+			resource_names (data.resources_for_namespace ("test")),
+			vec! ["a"]);
+
+		assert_eq! (
+			data.resources ().map (|(resource_name, _)|
+				resource_name
+			).collect::<Vec <_>> (),
+			vec! ["a"]);
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust